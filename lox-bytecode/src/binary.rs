@@ -0,0 +1,571 @@
+//! The binary `.loxc` module format.
+//!
+//! A file starts with a header: the magic bytes `LOXC`, the format version as a
+//! little-endian `u16`, the length of the payload and a CRC-32 of the payload, both
+//! as little-endian `u32`. The payload holds the constant pool, the names of the
+//! globals, then every chunk with its instruction stream and spans, and finally the
+//! line tables.
+//!
+//! All other integers are stored as unsigned LEB128, numbers as the little-endian
+//! bits of their `f64` and strings as a length followed by UTF-8 bytes.
+
+use crate::bytecode::*;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+pub const MAGIC: &[u8; 4] = b"LOXC";
+
+/// Bump this whenever the encoding or the instruction set changes.
+pub const FORMAT_VERSION: u16 = 4;
+
+const HEADER_LEN: usize = 14;
+
+#[derive(Debug)]
+pub enum ReadError {
+    Io(io::Error),
+    InvalidMagic,
+    UnsupportedVersion(u16),
+    ChecksumMismatch,
+    UnexpectedEnd,
+    InvalidTag(&'static str, u8),
+    InvalidString,
+    InvalidInteger,
+    DuplicateGlobal(String),
+    TrailingData,
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReadError::Io(error) => write!(f, "{}", error),
+            ReadError::InvalidMagic => write!(f, "not a compiled lox module"),
+            ReadError::UnsupportedVersion(version) => write!(
+                f,
+                "module has format version {} but version {} is required, recompile it",
+                version, FORMAT_VERSION
+            ),
+            ReadError::ChecksumMismatch => write!(f, "checksum mismatch, the module is corrupt"),
+            ReadError::UnexpectedEnd => write!(f, "unexpected end of module"),
+            ReadError::InvalidTag(kind, tag) => write!(f, "invalid {} tag {}", kind, tag),
+            ReadError::InvalidString => write!(f, "invalid UTF-8 in string"),
+            ReadError::InvalidInteger => write!(f, "integer out of range"),
+            ReadError::DuplicateGlobal(name) => write!(f, "global '{}' is defined twice", name),
+            ReadError::TrailingData => write!(f, "unexpected data after module"),
+        }
+    }
+}
+
+impl std::error::Error for ReadError {}
+
+impl From<io::Error> for ReadError {
+    fn from(error: io::Error) -> Self {
+        ReadError::Io(error)
+    }
+}
+
+impl Module {
+    /// Write the module in the binary `.loxc` format.
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let mut payload = Encoder::default();
+        payload.module(self);
+        let payload = payload.bytes;
+
+        writer.write_all(MAGIC)?;
+        writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+        writer.write_all(&crc32(&payload).to_le_bytes())?;
+        writer.write_all(&payload)
+    }
+
+    /// Read a module in the binary `.loxc` format, as written by `write_to`.
+    pub fn read_from<R: Read>(mut reader: R) -> Result<Module, ReadError> {
+        let mut data = vec![];
+        reader.read_to_end(&mut data)?;
+
+        if data.len() < MAGIC.len() || &data[..MAGIC.len()] != MAGIC {
+            return Err(ReadError::InvalidMagic);
+        }
+        if data.len() < HEADER_LEN {
+            return Err(ReadError::UnexpectedEnd);
+        }
+
+        let version = u16::from_le_bytes([data[4], data[5]]);
+        if version != FORMAT_VERSION {
+            return Err(ReadError::UnsupportedVersion(version));
+        }
+
+        let length = u32::from_le_bytes([data[6], data[7], data[8], data[9]]) as usize;
+        let checksum = u32::from_le_bytes([data[10], data[11], data[12], data[13]]);
+        let payload = &data[HEADER_LEN..];
+        if payload.len() < length {
+            return Err(ReadError::UnexpectedEnd);
+        }
+        if payload.len() > length {
+            return Err(ReadError::TrailingData);
+        }
+        if crc32(payload) != checksum {
+            return Err(ReadError::ChecksumMismatch);
+        }
+
+        let mut decoder = Decoder { bytes: payload };
+        let module = decoder.module()?;
+        if !decoder.bytes.is_empty() {
+            return Err(ReadError::TrailingData);
+        }
+        Ok(module)
+    }
+}
+
+#[derive(Default)]
+struct Encoder {
+    bytes: Vec<u8>,
+}
+
+impl Encoder {
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn usize(&mut self, value: usize) {
+        let mut value = value as u64;
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                self.u8(byte);
+                return;
+            }
+            self.u8(byte | 0x80);
+        }
+    }
+
+    fn f64(&mut self, value: f64) {
+        self.bytes.extend_from_slice(&value.to_bits().to_le_bytes());
+    }
+
+    fn string(&mut self, value: &str) {
+        self.usize(value.len());
+        self.bytes.extend_from_slice(value.as_bytes());
+    }
+
+    fn module(&mut self, module: &Module) {
+        self.usize(module.constants().len());
+        for constant in module.constants() {
+            self.constant(constant);
+        }
+
+        self.usize(module.globals().len());
+        for global in module.globals() {
+            self.string(global);
+        }
+
+        self.usize(module.chunks().len());
+        for chunk in module.chunks() {
+            self.chunk(chunk);
+        }
+
+        self.usize(module.line_tables().len());
+        for table in module.line_tables() {
+            self.usize(table.first_chunk);
+            self.usize(table.first_line);
+            self.usize(table.starts.len());
+            for start in &table.starts {
+                self.usize(*start as usize);
+            }
+        }
+    }
+
+    fn constant(&mut self, constant: &Constant) {
+        match constant {
+            Constant::Number(number) => {
+                self.u8(0);
+                self.f64(*number);
+            }
+            Constant::String(string) => {
+                self.u8(1);
+                self.string(string);
+            }
+            Constant::Closure(closure) => {
+                self.u8(2);
+                self.string(&closure.function.name);
+                self.usize(closure.function.chunk_index);
+                self.usize(closure.function.arity);
+                self.usize(closure.upvalues.len());
+                for upvalue in &closure.upvalues {
+                    match upvalue {
+                        Upvalue::Local(index) => {
+                            self.u8(0);
+                            self.usize(*index);
+                        }
+                        Upvalue::Upvalue(index) => {
+                            self.u8(1);
+                            self.usize(*index);
+                        }
+                    }
+                }
+            }
+            Constant::Class(class) => {
+                self.u8(3);
+                self.string(&class.name);
+            }
+        }
+    }
+
+    fn chunk(&mut self, chunk: &Chunk) {
+        self.usize(chunk.instructions().len());
+        for instruction in chunk.instructions() {
+            self.instruction(*instruction);
+        }
+
+        // Spans are mostly increasing and short, so store the distance to the previous one.
+        let mut previous = 0u32;
+        for span in chunk.spans() {
+            let delta = span.start.wrapping_sub(previous) as i32;
+            self.usize(((delta << 1) ^ (delta >> 31)) as u32 as usize);
+            self.usize(span.end.saturating_sub(span.start) as usize);
+            previous = span.start;
+        }
+    }
+
+    fn instruction(&mut self, instruction: Instruction) {
+        use Instruction::*;
+        let (opcode, operand) = match instruction {
+            Constant(index) => (0, Some(index)),
+            True => (1, None),
+            False => (2, None),
+            Nil => (3, None),
+            Negate => (4, None),
+            Add => (5, None),
+            Subtract => (6, None),
+            Multiply => (7, None),
+            Divide => (8, None),
+            Not => (9, None),
+            Equal => (10, None),
+            Greater => (11, None),
+            Less => (12, None),
+            Pop => (13, None),
+            Return => (14, None),
+            Print => (15, None),
+            DefineGlobal(index) => (16, Some(index)),
+            GetGlobal(index) => (17, Some(index)),
+            SetGlobal(index) => (18, Some(index)),
+            GetLocal(index) => (19, Some(index)),
+            SetLocal(index) => (20, Some(index)),
+            GetUpvalue(index) => (21, Some(index)),
+            SetUpvalue(index) => (22, Some(index)),
+            SetProperty(index) => (23, Some(index)),
+            GetProperty(index) => (24, Some(index)),
+            GetSuper(index) => (25, Some(index)),
+            Jump(index) => (26, Some(index)),
+            JumpIfFalse(index) => (27, Some(index)),
+            Call(count) => (28, Some(count)),
+            CloseUpvalue => (29, None),
+            Class(index) => (30, Some(index)),
+            Closure(index) => (31, Some(index)),
+            Method(index) => (32, Some(index)),
+            Inherit => (33, None),
+            JumpIfTrue(index) => (34, Some(index)),
+        };
+        self.u8(opcode);
+        if let Some(operand) = operand {
+            self.usize(operand);
+        }
+    }
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], ReadError> {
+        if self.bytes.len() < count {
+            return Err(ReadError::UnexpectedEnd);
+        }
+        let (taken, rest) = self.bytes.split_at(count);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, ReadError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn usize(&mut self) -> Result<usize, ReadError> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value as usize);
+            }
+        }
+        Err(ReadError::InvalidInteger)
+    }
+
+    fn u32(&mut self) -> Result<u32, ReadError> {
+        Ok(self.usize()? as u32)
+    }
+
+    fn f64(&mut self) -> Result<f64, ReadError> {
+        let mut bits = [0; 8];
+        bits.copy_from_slice(self.take(8)?);
+        Ok(f64::from_bits(u64::from_le_bytes(bits)))
+    }
+
+    fn string(&mut self) -> Result<String, ReadError> {
+        let length = self.usize()?;
+        let bytes = self.take(length)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| ReadError::InvalidString)
+    }
+
+    fn module(&mut self) -> Result<Module, ReadError> {
+        let mut module = Module::new();
+
+        for _ in 0..self.usize()? {
+            let constant = self.constant()?;
+            module.push_constant(constant);
+        }
+
+        for index in 0..self.usize()? {
+            let name = self.string()?;
+            if module.add_global(&name) != index {
+                return Err(ReadError::DuplicateGlobal(name));
+            }
+        }
+
+        for _ in 0..self.usize()? {
+            let index = module.add_chunk();
+            self.chunk(module.chunk_mut(index))?;
+        }
+
+        for _ in 0..self.usize()? {
+            let first_chunk = self.usize()?;
+            let first_line = self.usize()?;
+            let mut starts = vec![];
+            for _ in 0..self.usize()? {
+                starts.push(self.u32()?);
+            }
+            module.add_line_table(LineTable {
+                first_chunk,
+                first_line,
+                starts,
+            });
+        }
+
+        Ok(module)
+    }
+
+    fn constant(&mut self) -> Result<Constant, ReadError> {
+        match self.u8()? {
+            0 => Ok(Constant::Number(self.f64()?)),
+            1 => Ok(Constant::String(self.string()?)),
+            2 => {
+                let function = Function {
+                    name: self.string()?,
+                    chunk_index: self.usize()?,
+                    arity: self.usize()?,
+                };
+                let mut upvalues = vec![];
+                for _ in 0..self.usize()? {
+                    let upvalue = match self.u8()? {
+                        0 => Upvalue::Local(self.usize()?),
+                        1 => Upvalue::Upvalue(self.usize()?),
+                        tag => return Err(ReadError::InvalidTag("upvalue", tag)),
+                    };
+                    upvalues.push(upvalue);
+                }
+                Ok(Constant::Closure(Closure { function, upvalues }))
+            }
+            3 => Ok(Constant::Class(Class {
+                name: self.string()?,
+            })),
+            tag => Err(ReadError::InvalidTag("constant", tag)),
+        }
+    }
+
+    fn chunk(&mut self, chunk: &mut Chunk) -> Result<(), ReadError> {
+        let count = self.usize()?;
+        let mut instructions = vec![];
+        for _ in 0..count {
+            instructions.push(self.instruction()?);
+        }
+
+        let mut previous = 0u32;
+        for instruction in instructions {
+            let zigzag = self.u32()?;
+            let delta = ((zigzag >> 1) as i32) ^ -((zigzag & 1) as i32);
+            let start = previous.wrapping_add(delta as u32);
+            let end = start.saturating_add(self.u32()?);
+            chunk.add_instruction(instruction, Span { start, end });
+            previous = start;
+        }
+
+        Ok(())
+    }
+
+    fn instruction(&mut self) -> Result<Instruction, ReadError> {
+        use Instruction::*;
+        let instruction = match self.u8()? {
+            0 => Constant(self.usize()?),
+            1 => True,
+            2 => False,
+            3 => Nil,
+            4 => Negate,
+            5 => Add,
+            6 => Subtract,
+            7 => Multiply,
+            8 => Divide,
+            9 => Not,
+            10 => Equal,
+            11 => Greater,
+            12 => Less,
+            13 => Pop,
+            14 => Return,
+            15 => Print,
+            16 => DefineGlobal(self.usize()?),
+            17 => GetGlobal(self.usize()?),
+            18 => SetGlobal(self.usize()?),
+            19 => GetLocal(self.usize()?),
+            20 => SetLocal(self.usize()?),
+            21 => GetUpvalue(self.usize()?),
+            22 => SetUpvalue(self.usize()?),
+            23 => SetProperty(self.usize()?),
+            24 => GetProperty(self.usize()?),
+            25 => GetSuper(self.usize()?),
+            26 => Jump(self.usize()?),
+            27 => JumpIfFalse(self.usize()?),
+            28 => Call(self.usize()?),
+            29 => CloseUpvalue,
+            30 => Class(self.usize()?),
+            31 => Closure(self.usize()?),
+            32 => Method(self.usize()?),
+            33 => Inherit,
+            34 => JumpIfTrue(self.usize()?),
+            opcode => return Err(ReadError::InvalidTag("opcode", opcode)),
+        };
+        Ok(instruction)
+    }
+}
+
+// CRC-32 as used by zlib and PNG.
+fn crc32(bytes: &[u8]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0u32; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = i as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 1 == 1 {
+                    (crc >> 1) ^ 0xedb8_8320
+                } else {
+                    crc >> 1
+                };
+                bit += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    };
+
+    !bytes.iter().fold(!0u32, |crc, byte| {
+        TABLE[((crc ^ u32::from(*byte)) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn module() -> Module {
+        let mut module = Module::new();
+        let top = module.add_chunk();
+        let function = module.add_chunk();
+        let a = module.add_constant(Constant::Number(-0.5));
+        let b = module.add_constant(Constant::String("héllo".into()));
+        let c = module.add_constant(Constant::Class(Class { name: "A".into() }));
+        let d = module.add_constant(Constant::Closure(Closure {
+            function: Function {
+                name: "f".into(),
+                chunk_index: function,
+                arity: 2,
+            },
+            upvalues: vec![Upvalue::Local(1), Upvalue::Upvalue(300)],
+        }));
+        module.add_global("x");
+        let g = module.add_global("f");
+
+        let span = |start, end| Span { start, end };
+        let chunk = module.chunk_mut(top);
+        chunk.add_instruction(Instruction::Constant(a), span(10, 14));
+        chunk.add_instruction(Instruction::Constant(b), span(3, 5));
+        chunk.add_instruction(Instruction::Class(c), span(1000, 1001));
+        chunk.add_instruction(Instruction::Closure(d), span(0, 0));
+        chunk.add_instruction(Instruction::DefineGlobal(g), span(0, 0));
+        chunk.add_instruction(Instruction::JumpIfFalse(70000), span(20, 21));
+        chunk.add_instruction(Instruction::Return, span(21, 22));
+        let chunk = module.chunk_mut(function);
+        chunk.add_instruction(Instruction::GetUpvalue(1), span(5, 6));
+        chunk.add_instruction(Instruction::Return, span(6, 7));
+        module.set_lines(vec![0, 12, 400]);
+        module.add_line_table(LineTable {
+            first_chunk: function,
+            first_line: 3,
+            starts: vec![0, 5],
+        });
+        module
+    }
+
+    fn write(module: &Module) -> Vec<u8> {
+        let mut data = vec![];
+        module.write_to(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let module = module();
+        let data = write(&module);
+        assert_eq!(&data[..4], MAGIC);
+        assert_eq!(Module::read_from(&data[..]).unwrap(), module);
+    }
+
+    #[test]
+    fn test_rejects_other_versions() {
+        let mut data = write(&module());
+        data[4..6].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            Module::read_from(&data[..]),
+            Err(ReadError::UnsupportedVersion(version)) if version == FORMAT_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn test_rejects_corrupt_data() {
+        let data = write(&module());
+
+        let mut corrupt = data.clone();
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 1;
+        assert!(matches!(
+            Module::read_from(&corrupt[..]),
+            Err(ReadError::ChecksumMismatch)
+        ));
+
+        assert!(matches!(
+            Module::read_from(&data[..data.len() - 1]),
+            Err(ReadError::UnexpectedEnd)
+        ));
+        assert!(matches!(
+            Module::read_from(&b"{\"chunks\": []}"[..]),
+            Err(ReadError::InvalidMagic)
+        ));
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+}
@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 pub type InstructionIndex = usize;
 pub type ConstantIndex = usize;
@@ -6,6 +7,7 @@ pub type StackIndex = usize;
 pub type ChunkIndex = usize;
 pub type ArgumentCount = usize;
 pub type UpvalueIndex = usize;
+pub type GlobalIndex = usize;
 
 #[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
 pub enum Instruction {
@@ -30,52 +32,113 @@ pub enum Instruction {
     Return,
     Print,
 
-    DefineGlobal(ConstantIndex),
-    GetGlobal(ConstantIndex),
-    SetGlobal(ConstantIndex),
+    DefineGlobal(GlobalIndex),
+    GetGlobal(GlobalIndex),
+    SetGlobal(GlobalIndex),
     GetLocal(StackIndex),
     SetLocal(StackIndex),
     GetUpvalue(StackIndex),
     SetUpvalue(StackIndex),
     SetProperty(ConstantIndex),
     GetProperty(ConstantIndex),
+    GetSuper(ConstantIndex),
 
     Jump(InstructionIndex),
     JumpIfFalse(InstructionIndex),
+    JumpIfTrue(InstructionIndex),
     Call(ArgumentCount),
     CloseUpvalue,
 
     Class(ConstantIndex),
     Closure(ConstantIndex),
+    Method(ConstantIndex),
+    Inherit,
     // etc
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+impl Instruction {
+    // Offset the constant this instruction refers to, used when appending modules.
+    fn with_constant_offset(self, offset: usize) -> Instruction {
+        use Instruction::*;
+        match self {
+            Constant(index) => Constant(index + offset),
+            SetProperty(index) => SetProperty(index + offset),
+            GetProperty(index) => GetProperty(index + offset),
+            GetSuper(index) => GetSuper(index + offset),
+            Class(index) => Class(index + offset),
+            Closure(index) => Closure(index + offset),
+            Method(index) => Method(index + offset),
+            instruction => instruction,
+        }
+    }
+
+    // Map the global this instruction refers to through `globals`, used when appending modules.
+    fn with_globals(self, globals: &[GlobalIndex]) -> Instruction {
+        use Instruction::*;
+        match self {
+            DefineGlobal(index) => DefineGlobal(globals[index]),
+            GetGlobal(index) => GetGlobal(globals[index]),
+            SetGlobal(index) => SetGlobal(globals[index]),
+            instruction => instruction,
+        }
+    }
+}
+
+/// Byte offsets into the source an instruction was compiled from.
+#[derive(Debug, PartialEq, Copy, Clone, Default, Serialize, Deserialize)]
+pub struct Span {
+    pub start: u32,
+    pub end: u32,
+}
+
+/// Where the lines of the source a range of chunks was compiled from start.
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
+pub struct LineTable {
+    /// The first chunk of the range, it runs up to the first chunk of the next table.
+    pub first_chunk: ChunkIndex,
+    /// The number of lines before `starts`, for code that is part of a larger source.
+    pub first_line: usize,
+    /// The byte offset at which each line starts.
+    pub starts: Vec<u32>,
+}
+
+impl LineTable {
+    fn line(&self, pos: u32) -> usize {
+        let line = match self.starts.binary_search(&pos) {
+            Ok(index) => index + 1,
+            Err(0) => return 0,
+            Err(index) => index,
+        };
+        self.first_line + line
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct Class {
     pub name: String,
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct Closure {
     pub function: Function,
     pub upvalues: Vec<Upvalue>,
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub enum Upvalue {
     Local(StackIndex),
     Upvalue(UpvalueIndex),
 }
 
 //TODO Merge this into Closure, we'll wait until methods are implemented though
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct Function {
     pub name: String,
     pub chunk_index: ChunkIndex,
     pub arity: usize,
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub enum Constant {
     Number(f64),
     String(String),
@@ -102,15 +165,57 @@ impl From<Function> for Constant {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct Chunk {
     instructions: Vec<Instruction>,
+    spans: Vec<Span>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Module {
     chunks: Vec<Chunk>,
     constants: Vec<Constant>,
+    // Sorted by first chunk, modules that are appended keep their own table.
+    lines: Vec<LineTable>,
+    // The name of every global, by the slot the instructions refer to it with.
+    globals: Vec<String>,
+    #[serde(skip)]
+    global_indices: HashMap<String, GlobalIndex>,
+    // Where each number and string first appears in `constants`.
+    #[serde(skip)]
+    constant_indices: HashMap<ConstantKey, ConstantIndex>,
+}
+
+// Numbers are compared bitwise, so NaN is found again and -0.0 is kept apart from 0.0.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Number(u64),
+    String(String),
+}
+
+impl ConstantKey {
+    fn new(constant: &Constant) -> Option<ConstantKey> {
+        match constant {
+            Constant::Number(number) => Some(ConstantKey::Number(number.to_bits())),
+            Constant::String(string) => Some(ConstantKey::String(string.clone())),
+            Constant::Closure(_) | Constant::Class(_) => None,
+        }
+    }
+}
+
+impl PartialEq for Module {
+    fn eq(&self, other: &Self) -> bool {
+        self.chunks == other.chunks
+            && self.constants == other.constants
+            && self.lines == other.lines
+            && self.globals == other.globals
+    }
+}
+
+impl Default for Module {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Module {
@@ -118,9 +223,14 @@ impl Module {
         Module {
             chunks: vec![],
             constants: vec![],
+            lines: vec![],
+            globals: vec![],
+            global_indices: HashMap::new(),
+            constant_indices: HashMap::new(),
         }
     }
 
+    #[inline]
     pub fn chunk(&self, index: ChunkIndex) -> &Chunk {
         &self.chunks[index]
     }
@@ -133,15 +243,30 @@ impl Module {
         self.chunks.len() - 1
     }
 
+    /// Add a constant to the pool, numbers and strings that are already in it are reused.
     pub fn add_constant(&mut self, constant: Constant) -> ConstantIndex {
+        match ConstantKey::new(&constant).and_then(|key| self.constant_indices.get(&key)) {
+            Some(&index) => index,
+            None => self.push_constant(constant),
+        }
+    }
+
+    /// Add a constant at the end of the pool even if it is already in it, for modules whose
+    /// instructions already refer to constants by index.
+    pub fn push_constant(&mut self, constant: Constant) -> ConstantIndex {
+        let index = self.constants.len();
+        if let Some(key) = ConstantKey::new(&constant) {
+            self.constant_indices.entry(key).or_insert(index);
+        }
         self.constants.push(constant);
-        self.constants.len() - 1
+        index
     }
 
     pub fn constants(&self) -> &[Constant] {
         &self.constants
     }
 
+    #[inline]
     pub fn constant(&self, index: ConstantIndex) -> &Constant {
         &self.constants[index]
     }
@@ -149,17 +274,136 @@ impl Module {
     pub fn chunks(&self) -> &[Chunk] {
         &self.chunks
     }
+
+    /// The slot of the global called `name`, adding it if this is the first time it is used.
+    pub fn add_global(&mut self, name: &str) -> GlobalIndex {
+        if let Some(&index) = self.global_indices.get(name) {
+            return index;
+        }
+        self.globals.push(name.to_string());
+        self.global_indices
+            .insert(name.to_string(), self.globals.len() - 1);
+        self.globals.len() - 1
+    }
+
+    /// The slot of the global called `name`, if it is used.
+    pub fn global_index(&self, name: &str) -> Option<GlobalIndex> {
+        self.global_indices.get(name).copied()
+    }
+
+    /// The names of the globals, by slot.
+    pub fn globals(&self) -> &[String] {
+        &self.globals
+    }
+
+    /// Set the byte offsets at which each source line starts, used to map spans to lines.
+    pub fn set_lines(&mut self, lines: Vec<u32>) {
+        self.lines = vec![LineTable {
+            first_chunk: 0,
+            first_line: 0,
+            starts: lines,
+        }];
+    }
+
+    /// Add a line table for the chunks from `table.first_chunk` on.
+    pub fn add_line_table(&mut self, table: LineTable) {
+        self.lines.push(table);
+    }
+
+    /// Append the chunks and constants of `other` to this module, fixing up the indices
+    /// they refer to. Globals with the same name share a slot.
+    /// Returns the index `other`'s top-level chunk ends up at.
+    pub fn append(&mut self, other: Module) -> ChunkIndex {
+        let chunk_offset = self.chunks.len();
+        let constant_offset = self.constants.len();
+        let globals: Vec<GlobalIndex> = other
+            .globals
+            .iter()
+            .map(|name| self.add_global(name))
+            .collect();
+
+        for mut constant in other.constants {
+            if let Constant::Closure(ref mut closure) = constant {
+                closure.function.chunk_index += chunk_offset;
+            }
+            self.push_constant(constant);
+        }
+
+        for mut chunk in other.chunks {
+            for instruction in chunk.instructions.iter_mut() {
+                *instruction = instruction
+                    .with_constant_offset(constant_offset)
+                    .with_globals(&globals);
+            }
+            self.chunks.push(chunk);
+        }
+
+        // Spans of `other` point into its own source, so its chunks don't share our lines.
+        if other.lines.is_empty() {
+            self.lines.push(LineTable {
+                first_chunk: chunk_offset,
+                ..LineTable::default()
+            });
+        }
+        for mut table in other.lines {
+            table.first_chunk += chunk_offset;
+            self.lines.push(table);
+        }
+
+        chunk_offset
+    }
+
+    /// Move all spans `offset` bytes and all lines `lines` lines further into the source.
+    /// Used for code that is part of a larger source, like a session in the repl.
+    pub fn shift_spans(&mut self, offset: u32, lines: usize) {
+        for chunk in self.chunks.iter_mut() {
+            for span in chunk.spans.iter_mut() {
+                span.start += offset;
+                span.end += offset;
+            }
+        }
+        for table in self.lines.iter_mut() {
+            table.first_line += lines;
+            for start in table.starts.iter_mut() {
+                *start += offset;
+            }
+        }
+    }
+
+    pub fn line_tables(&self) -> &[LineTable] {
+        &self.lines
+    }
+
+    /// The 1-based source line containing `pos` in chunk `index`, or 0 if there is no
+    /// line information.
+    pub fn line(&self, index: ChunkIndex, pos: u32) -> usize {
+        let tables = self
+            .lines
+            .partition_point(|table| table.first_chunk <= index);
+        match tables.checked_sub(1) {
+            Some(table) => self.lines[table].line(pos),
+            None => 0,
+        }
+    }
+}
+
+impl Default for Chunk {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Chunk {
     pub fn new() -> Chunk {
         Chunk {
             instructions: vec![],
+            spans: vec![],
         }
     }
 
-    pub fn add_instruction(&mut self, instruction: Instruction) -> InstructionIndex {
+    pub fn add_instruction(&mut self, instruction: Instruction, span: Span) -> InstructionIndex {
         self.instructions.push(instruction);
+        self.spans.push(span);
         self.instructions.len() - 1
     }
 
@@ -175,12 +419,89 @@ impl Chunk {
     pub fn patch_instruction_to(&mut self, index: InstructionIndex, to: InstructionIndex) {
         match self.instructions[index] {
             Instruction::JumpIfFalse(ref mut placeholder) => *placeholder = to,
+            Instruction::JumpIfTrue(ref mut placeholder) => *placeholder = to,
             Instruction::Jump(ref mut placeholder) => *placeholder = to,
             _ => (), // Nothing to patch
         };
     }
 
+    #[inline]
     pub fn instructions(&self) -> &[Instruction] {
         &self.instructions
     }
+
+    pub fn span(&self, index: InstructionIndex) -> Span {
+        self.spans[index]
+    }
+
+    pub fn spans(&self) -> &[Span] {
+        &self.spans
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_constant_deduplicates() {
+        let mut module = Module::new();
+        assert_eq!(module.add_constant("a".into()), 0);
+        assert_eq!(module.add_constant(1.0.into()), 1);
+        assert_eq!(module.add_constant("a".into()), 0);
+        assert_eq!(module.add_constant(1.0.into()), 1);
+        assert_eq!(module.constants().len(), 2);
+    }
+
+    #[test]
+    fn test_add_constant_compares_numbers_bitwise() {
+        let mut module = Module::new();
+        let zero = module.add_constant(0.0.into());
+        let negative_zero = module.add_constant((-0.0).into());
+        let nan = module.add_constant(f64::NAN.into());
+        assert_ne!(zero, negative_zero);
+        assert_eq!(module.add_constant((-0.0).into()), negative_zero);
+        assert_eq!(module.add_constant(f64::NAN.into()), nan);
+        assert_eq!(module.constants().len(), 3);
+    }
+
+    #[test]
+    fn test_push_constant_keeps_duplicates() {
+        let mut module = Module::new();
+        module.push_constant("a".into());
+        assert_eq!(module.push_constant("a".into()), 1);
+        assert_eq!(module.add_constant("a".into()), 0);
+    }
+
+    #[test]
+    fn test_append_keeps_line_tables() {
+        let mut module = Module::new();
+        module.add_chunk();
+        module.set_lines(vec![0, 10, 20]);
+        let mut other = Module::new();
+        other.add_chunk();
+        other.add_chunk();
+        other.set_lines(vec![0, 4]);
+        module.append(other);
+        let mut without_lines = Module::new();
+        without_lines.add_chunk();
+        module.append(without_lines);
+
+        assert_eq!(module.line(0, 15), 2);
+        assert_eq!(module.line(1, 2), 1);
+        assert_eq!(module.line(2, 15), 2);
+        assert_eq!(module.line(3, 15), 0);
+    }
+
+    #[test]
+    fn test_shift_spans_continues_lines() {
+        let mut module = Module::new();
+        module.add_chunk();
+        module.set_lines(vec![0, 4]);
+        module.shift_spans(30, 3);
+
+        assert_eq!(module.line(0, 30), 4);
+        assert_eq!(module.line(0, 36), 5);
+        assert_eq!(module.line(0, 10), 0);
+    }
 }
@@ -0,0 +1,264 @@
+//! Human readable listings of compiled modules.
+//!
+//! ```text
+//! == test (chunk 1, arity 0) ==
+//! 0000    7  Constant 5 ("My Test Function")
+//! 0001    |  Print
+//! 0002    |  Nil
+//! 0003    |  Return
+//! ```
+//!
+//! Each instruction is shown with its offset, the source line it was compiled from
+//! (`|` when it's the same as the previous instruction) and its operands. Constants are
+//! resolved, globals are shown with their names, jump targets are shown as labels and closures list the upvalues they capture.
+
+use crate::bytecode::*;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// Disassemble every chunk in `module`.
+pub fn disassemble(module: &Module) -> String {
+    let mut out = String::new();
+    for index in 0..module.chunks().len() {
+        if index > 0 {
+            out.push('\n');
+        }
+        out.push_str(&disassemble_chunk(module, index));
+    }
+    out
+}
+
+/// Disassemble a single chunk of `module`.
+pub fn disassemble_chunk(module: &Module, index: ChunkIndex) -> String {
+    let chunk = module.chunk(index);
+    let mut out = String::new();
+
+    match function(module, index) {
+        Some(function) => writeln!(
+            out,
+            "== {} (chunk {}, arity {}) ==",
+            function.name, index, function.arity
+        ),
+        None => writeln!(out, "== <script> (chunk {}) ==", index),
+    }
+    .unwrap();
+
+    let labels = labels(chunk);
+    let mut previous_line = None;
+    for (offset, &instruction) in chunk.instructions().iter().enumerate() {
+        if let Some(label) = labels.get(&offset) {
+            writeln!(out, "L{}:", label).unwrap();
+        }
+
+        let line = module.line(index, chunk.span(offset).start);
+        let shown_line = if previous_line == Some(line) {
+            "|".to_string()
+        } else {
+            line.to_string()
+        };
+        previous_line = Some(line);
+
+        let (name, operand) = decode(instruction);
+        write!(out, "{:04} {:>4}  {}", offset, shown_line, name).unwrap();
+        match instruction {
+            Instruction::Jump(to) | Instruction::JumpIfFalse(to) | Instruction::JumpIfTrue(to) => {
+                match labels.get(&to) {
+                    Some(label) => write!(out, " L{}", label),
+                    None => write!(out, " {}", to),
+                }
+            }
+            Instruction::DefineGlobal(global)
+            | Instruction::GetGlobal(global)
+            | Instruction::SetGlobal(global) => match module.globals().get(global) {
+                Some(name) => write!(out, " {} ({})", global, name),
+                None => write!(out, " {} (invalid)", global),
+            },
+            Instruction::Constant(constant)
+            | Instruction::SetProperty(constant)
+            | Instruction::GetProperty(constant)
+            | Instruction::GetSuper(constant)
+            | Instruction::Class(constant)
+            | Instruction::Closure(constant)
+            | Instruction::Method(constant) => {
+                write!(out, " {} ({})", constant, describe(module, constant))
+            }
+            _ => match operand {
+                Some(operand) => write!(out, " {}", operand),
+                None => Ok(()),
+            },
+        }
+        .unwrap();
+        out.push('\n');
+
+        if let Instruction::Closure(constant) = instruction {
+            if let Some(Constant::Closure(closure)) = module.constants().get(constant) {
+                for upvalue in &closure.upvalues {
+                    let (kind, index) = match *upvalue {
+                        Upvalue::Local(index) => ("local", index),
+                        Upvalue::Upvalue(index) => ("upvalue", index),
+                    };
+                    writeln!(out, "{:>11}    {} {}", "|", kind, index).unwrap();
+                }
+            }
+        }
+    }
+
+    out
+}
+
+// The function whose closure runs the chunk, the top-level chunks don't have one.
+fn function(module: &Module, index: ChunkIndex) -> Option<&Function> {
+    module
+        .constants()
+        .iter()
+        .find_map(|constant| match constant {
+            Constant::Closure(closure) if closure.function.chunk_index == index => {
+                Some(&closure.function)
+            }
+            _ => None,
+        })
+}
+
+// Number the jump targets of a chunk in the order they appear, targets outside the chunk
+// are left as offsets.
+fn labels(chunk: &Chunk) -> BTreeMap<InstructionIndex, usize> {
+    let length = chunk.instructions().len();
+    let mut labels: BTreeMap<InstructionIndex, usize> = chunk
+        .instructions()
+        .iter()
+        .filter_map(|instruction| match *instruction {
+            Instruction::Jump(to) | Instruction::JumpIfFalse(to) | Instruction::JumpIfTrue(to)
+                if to < length =>
+            {
+                Some((to, 0))
+            }
+            _ => None,
+        })
+        .collect();
+    for (number, label) in labels.values_mut().enumerate() {
+        *label = number;
+    }
+    labels
+}
+
+fn describe(module: &Module, index: ConstantIndex) -> String {
+    match module.constants().get(index) {
+        Some(Constant::Number(number)) => number.to_string(),
+        Some(Constant::String(string)) => format!("{:?}", string),
+        Some(Constant::Closure(closure)) => format!("<fn {}>", closure.function.name),
+        Some(Constant::Class(class)) => format!("<class {}>", class.name),
+        None => "invalid".to_string(),
+    }
+}
+
+fn decode(instruction: Instruction) -> (&'static str, Option<usize>) {
+    use Instruction::*;
+    match instruction {
+        Constant(index) => ("Constant", Some(index)),
+        True => ("True", None),
+        False => ("False", None),
+        Nil => ("Nil", None),
+        Negate => ("Negate", None),
+        Add => ("Add", None),
+        Subtract => ("Subtract", None),
+        Multiply => ("Multiply", None),
+        Divide => ("Divide", None),
+        Not => ("Not", None),
+        Equal => ("Equal", None),
+        Greater => ("Greater", None),
+        Less => ("Less", None),
+        Pop => ("Pop", None),
+        Return => ("Return", None),
+        Print => ("Print", None),
+        DefineGlobal(index) => ("DefineGlobal", Some(index)),
+        GetGlobal(index) => ("GetGlobal", Some(index)),
+        SetGlobal(index) => ("SetGlobal", Some(index)),
+        GetLocal(index) => ("GetLocal", Some(index)),
+        SetLocal(index) => ("SetLocal", Some(index)),
+        GetUpvalue(index) => ("GetUpvalue", Some(index)),
+        SetUpvalue(index) => ("SetUpvalue", Some(index)),
+        SetProperty(index) => ("SetProperty", Some(index)),
+        GetProperty(index) => ("GetProperty", Some(index)),
+        GetSuper(index) => ("GetSuper", Some(index)),
+        Jump(to) => ("Jump", Some(to)),
+        JumpIfFalse(to) => ("JumpIfFalse", Some(to)),
+        JumpIfTrue(to) => ("JumpIfTrue", Some(to)),
+        Call(arguments) => ("Call", Some(arguments)),
+        CloseUpvalue => ("CloseUpvalue", None),
+        Class(index) => ("Class", Some(index)),
+        Closure(index) => ("Closure", Some(index)),
+        Method(index) => ("Method", Some(index)),
+        Inherit => ("Inherit", None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(start: u32) -> Span {
+        Span {
+            start,
+            end: start + 1,
+        }
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let mut module = Module::new();
+        let top = module.add_chunk();
+        let function = module.add_chunk();
+        let hello = module.add_constant("hello".into());
+        let closure = module.add_constant(Constant::Closure(Closure {
+            function: Function {
+                name: "f".into(),
+                chunk_index: function,
+                arity: 1,
+            },
+            upvalues: vec![Upvalue::Local(1), Upvalue::Upvalue(0)],
+        }));
+
+        let chunk = module.chunk_mut(top);
+        chunk.add_instruction(Instruction::True, span(0));
+        chunk.add_instruction(Instruction::JumpIfFalse(4), span(0));
+        chunk.add_instruction(Instruction::Constant(hello), span(7));
+        chunk.add_instruction(Instruction::Print, span(7));
+        chunk.add_instruction(Instruction::Closure(closure), span(12));
+        chunk.add_instruction(Instruction::Return, span(12));
+        let chunk = module.chunk_mut(function);
+        chunk.add_instruction(Instruction::GetLocal(1), span(12));
+        chunk.add_instruction(Instruction::Return, span(12));
+        module.set_lines(vec![0, 6, 12]);
+
+        assert_eq!(
+            disassemble(&module),
+            "== <script> (chunk 0) ==\n\
+             0000    1  True\n\
+             0001    |  JumpIfFalse L0\n\
+             0002    2  Constant 0 (\"hello\")\n\
+             0003    |  Print\n\
+             L0:\n\
+             0004    3  Closure 1 (<fn f>)\n          \
+             |    local 1\n          \
+             |    upvalue 0\n\
+             0005    |  Return\n\
+             \n\
+             == f (chunk 1, arity 1) ==\n\
+             0000    3  GetLocal 1\n\
+             0001    |  Return\n"
+        );
+    }
+
+    #[test]
+    fn test_invalid_operands() {
+        let mut module = Module::new();
+        let chunk = module.add_chunk();
+        module
+            .chunk_mut(chunk)
+            .add_instruction(Instruction::GetGlobal(3), span(0));
+        module
+            .chunk_mut(chunk)
+            .add_instruction(Instruction::Jump(9), span(0));
+        assert!(disassemble(&module).ends_with("GetGlobal 3 (invalid)\n0001    |  Jump 9\n"));
+    }
+}
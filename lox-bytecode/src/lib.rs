@@ -1 +1,5 @@
-pub mod bytecode;
+pub mod binary;
+pub mod bytecode;
+pub mod disassembler;
+pub mod peephole;
+pub mod verifier;
@@ -0,0 +1,297 @@
+//! Small rewrites of the instructions in a chunk that make it shorter or faster to run.
+//!
+//! - Jumps to a jump are threaded to where that jump ends up.
+//! - A value that is pushed without side effects and immediately popped is removed.
+//! - `Not` followed by a conditional jump becomes the opposite conditional jump, when the
+//!   condition is popped on both paths.
+//!
+//! Removing instructions moves everything after them, jump targets are moved along.
+
+use crate::bytecode::*;
+use std::collections::HashSet;
+
+/// Optimize every chunk in `module`.
+pub fn optimize_module(module: &mut Module) {
+    for index in 0..module.chunks().len() {
+        optimize(module.chunk_mut(index));
+    }
+}
+
+/// Optimize a single chunk.
+pub fn optimize(chunk: &mut Chunk) {
+    loop {
+        let threaded = thread_jumps(chunk);
+        if !fuse(chunk) && !threaded {
+            break;
+        }
+    }
+}
+
+fn jump_target(instruction: Instruction) -> Option<InstructionIndex> {
+    match instruction {
+        Instruction::Jump(to) | Instruction::JumpIfFalse(to) | Instruction::JumpIfTrue(to) => {
+            Some(to)
+        }
+        _ => None,
+    }
+}
+
+fn with_jump_target(instruction: Instruction, to: InstructionIndex) -> Instruction {
+    match instruction {
+        Instruction::Jump(_) => Instruction::Jump(to),
+        Instruction::JumpIfFalse(_) => Instruction::JumpIfFalse(to),
+        Instruction::JumpIfTrue(_) => Instruction::JumpIfTrue(to),
+        instruction => instruction,
+    }
+}
+
+// Where a jump from `instruction` to `to` ends up, `None` if it doesn't get any further.
+// A conditional jump lands on another conditional jump with the same value on the stack,
+// so it's known which way that one goes.
+fn follow(
+    instructions: &[Instruction],
+    instruction: Instruction,
+    to: InstructionIndex,
+) -> Option<InstructionIndex> {
+    let next = match (instruction, *instructions.get(to)?) {
+        (_, Instruction::Jump(next)) => next,
+        (Instruction::JumpIfFalse(_), Instruction::JumpIfFalse(next))
+        | (Instruction::JumpIfTrue(_), Instruction::JumpIfTrue(next)) => next,
+        (Instruction::JumpIfFalse(_), Instruction::JumpIfTrue(_))
+        | (Instruction::JumpIfTrue(_), Instruction::JumpIfFalse(_)) => to + 1,
+        _ => return None,
+    };
+    Some(next).filter(|&next| next != to)
+}
+
+fn thread_jumps(chunk: &mut Chunk) -> bool {
+    let instructions = chunk.instructions().to_vec();
+    let mut changed = false;
+    for (index, &instruction) in instructions.iter().enumerate() {
+        if let Some(start) = jump_target(instruction) {
+            let mut to = start;
+            let mut seen = HashSet::new();
+            while let Some(next) = follow(&instructions, instruction, to) {
+                // Jumps that loop forever are left alone.
+                if !seen.insert(next) {
+                    to = start;
+                    break;
+                }
+                to = next;
+            }
+            if to != start {
+                chunk.patch_instruction_to(index, to);
+                changed = true;
+            }
+        }
+    }
+    changed
+}
+
+// Push a value without any side effects.
+fn is_pure_push(instruction: Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::Constant(_)
+            | Instruction::True
+            | Instruction::False
+            | Instruction::Nil
+            | Instruction::GetLocal(_)
+            | Instruction::GetUpvalue(_)
+    )
+}
+
+fn fuse(chunk: &mut Chunk) -> bool {
+    let instructions = chunk.instructions();
+    let targets: HashSet<InstructionIndex> = instructions
+        .iter()
+        .filter_map(|&i| jump_target(i))
+        .collect();
+    let is_pop = |index: InstructionIndex| instructions.get(index) == Some(&Instruction::Pop);
+
+    // What each instruction is replaced with, `None` for removed instructions.
+    let mut replacements: Vec<Option<Instruction>> =
+        instructions.iter().copied().map(Some).collect();
+    let mut changed = false;
+    let mut index = 0;
+    while index + 1 < instructions.len() {
+        // Jumps into the middle of a pattern need the instructions it removes.
+        if targets.contains(&(index + 1)) {
+            index += 1;
+            continue;
+        }
+
+        match (instructions[index], instructions[index + 1]) {
+            (push, Instruction::Pop) if is_pure_push(push) => {
+                replacements[index] = None;
+                replacements[index + 1] = None;
+            }
+            (Instruction::Not, Instruction::JumpIfFalse(to)) if is_pop(index + 2) && is_pop(to) => {
+                replacements[index] = Some(Instruction::JumpIfTrue(to));
+                replacements[index + 1] = None;
+            }
+            (Instruction::Not, Instruction::JumpIfTrue(to)) if is_pop(index + 2) && is_pop(to) => {
+                replacements[index] = Some(Instruction::JumpIfFalse(to));
+                replacements[index + 1] = None;
+            }
+            _ => {
+                index += 1;
+                continue;
+            }
+        }
+        changed = true;
+        index += 2;
+    }
+
+    if changed {
+        *chunk = compact(chunk, &replacements);
+    }
+    changed
+}
+
+// Build the chunk without the removed instructions, moving jump targets along.
+fn compact(chunk: &Chunk, replacements: &[Option<Instruction>]) -> Chunk {
+    // Jumps to a removed instruction go to the next one that is kept.
+    let mut new_indices = Vec::with_capacity(replacements.len() + 1);
+    let mut kept = 0;
+    for replacement in replacements {
+        new_indices.push(kept);
+        if replacement.is_some() {
+            kept += 1;
+        }
+    }
+    new_indices.push(kept);
+
+    let mut compacted = Chunk::new();
+    for (index, replacement) in replacements.iter().enumerate() {
+        if let Some(instruction) = *replacement {
+            let instruction = match jump_target(instruction) {
+                Some(to) => with_jump_target(instruction, new_indices[to.min(replacements.len())]),
+                None => instruction,
+            };
+            compacted.add_instruction(instruction, chunk.span(index));
+        }
+    }
+    compacted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::Instruction::*;
+
+    fn optimized(instructions: &[Instruction]) -> Vec<Instruction> {
+        let mut chunk = Chunk::new();
+        for &instruction in instructions {
+            chunk.add_instruction(instruction, Span::default());
+        }
+        optimize(&mut chunk);
+        chunk.instructions().to_vec()
+    }
+
+    #[test]
+    fn test_remove_pushed_and_popped() {
+        assert_eq!(
+            optimized(&[Constant(0), Pop, GetLocal(1), Pop, Nil, Return]),
+            vec![Nil, Return]
+        );
+        assert_eq!(
+            optimized(&[GetGlobal(0), Pop, Nil, Return]),
+            vec![GetGlobal(0), Pop, Nil, Return]
+        );
+    }
+
+    #[test]
+    fn test_jump_targets_move() {
+        assert_eq!(
+            optimized(&[
+                True,
+                JumpIfFalse(6),
+                Pop,
+                Nil,
+                Pop,
+                Jump(7),
+                Pop,
+                Nil,
+                Return
+            ]),
+            vec![True, JumpIfFalse(4), Pop, Jump(5), Pop, Nil, Return]
+        );
+    }
+
+    #[test]
+    fn test_jump_into_pattern() {
+        let instructions = [True, JumpIfFalse(4), Pop, Nil, Pop, Nil, Return];
+        assert_eq!(optimized(&instructions), instructions);
+    }
+
+    #[test]
+    fn test_thread_jumps() {
+        assert_eq!(
+            optimized(&[Jump(1), Jump(2), Nil, Return]),
+            vec![Jump(2), Jump(2), Nil, Return]
+        );
+        assert_eq!(
+            optimized(&[
+                GetLocal(0),
+                JumpIfFalse(3),
+                Pop,
+                JumpIfFalse(5),
+                Pop,
+                Return
+            ]),
+            vec![
+                GetLocal(0),
+                JumpIfFalse(5),
+                Pop,
+                JumpIfFalse(5),
+                Pop,
+                Return
+            ]
+        );
+        assert_eq!(
+            optimized(&[GetLocal(0), JumpIfTrue(3), Pop, JumpIfFalse(5), Pop, Return]),
+            vec![GetLocal(0), JumpIfTrue(4), Pop, JumpIfFalse(5), Pop, Return]
+        );
+        assert_eq!(optimized(&[Jump(1), Jump(0)]), vec![Jump(1), Jump(0)]);
+    }
+
+    #[test]
+    fn test_not_jump() {
+        assert_eq!(
+            optimized(&[
+                GetLocal(0),
+                Not,
+                JumpIfFalse(6),
+                Pop,
+                Nil,
+                Print,
+                Pop,
+                Nil,
+                Return
+            ]),
+            vec![
+                GetLocal(0),
+                JumpIfTrue(5),
+                Pop,
+                Nil,
+                Print,
+                Pop,
+                Nil,
+                Return
+            ]
+        );
+        // The result of `!a and b` is the negated value.
+        let instructions = [
+            GetLocal(0),
+            Not,
+            JumpIfFalse(5),
+            Pop,
+            GetLocal(0),
+            Print,
+            Nil,
+            Return,
+        ];
+        assert_eq!(optimized(&instructions), instructions);
+    }
+}
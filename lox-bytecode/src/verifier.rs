@@ -0,0 +1,510 @@
+//! Checks that a module is safe to run before handing it to the VM.
+//!
+//! Every operand must refer to an existing constant of the right kind, global, local,
+//! upvalue or instruction. The stack depth is tracked along every path through a chunk, it may
+//! never drop below the frame's own slots and must be the same whichever way an
+//! instruction is reached. Every chunk must end in `Return`.
+
+use crate::bytecode::*;
+use std::fmt;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum VerifyErrorKind {
+    MissingEntryChunk,
+    InvalidConstant(ConstantIndex),
+    UnexpectedConstant(ConstantIndex),
+    InvalidChunk(ChunkIndex),
+    SharedChunk(ChunkIndex),
+    InvalidJump(InstructionIndex),
+    InvalidGlobal(GlobalIndex),
+    InvalidLocal(StackIndex),
+    InvalidUpvalue(UpvalueIndex),
+    StackUnderflow,
+    InconsistentStackDepth(usize, usize),
+    MissingReturn,
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct VerifyError {
+    pub chunk: ChunkIndex,
+    pub instruction: InstructionIndex,
+    pub kind: VerifyErrorKind,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "chunk {}, instruction {}: ",
+            self.chunk, self.instruction
+        )?;
+        match self.kind {
+            VerifyErrorKind::MissingEntryChunk => write!(f, "module has no chunks"),
+            VerifyErrorKind::InvalidConstant(index) => {
+                write!(f, "constant {} does not exist", index)
+            }
+            VerifyErrorKind::UnexpectedConstant(index) => {
+                write!(f, "constant {} has the wrong type", index)
+            }
+            VerifyErrorKind::InvalidChunk(index) => write!(f, "chunk {} does not exist", index),
+            VerifyErrorKind::SharedChunk(index) => {
+                write!(f, "chunk {} is used by more than one function", index)
+            }
+            VerifyErrorKind::InvalidJump(index) => {
+                write!(f, "jump to instruction {} is out of range", index)
+            }
+            VerifyErrorKind::InvalidGlobal(index) => write!(f, "global {} does not exist", index),
+            VerifyErrorKind::InvalidLocal(index) => write!(f, "local {} does not exist", index),
+            VerifyErrorKind::InvalidUpvalue(index) => {
+                write!(f, "upvalue {} does not exist", index)
+            }
+            VerifyErrorKind::StackUnderflow => write!(f, "stack underflow"),
+            VerifyErrorKind::InconsistentStackDepth(expected, found) => write!(
+                f,
+                "stack depth is {} on one path and {} on another",
+                expected, found
+            ),
+            VerifyErrorKind::MissingReturn => write!(f, "chunk does not end in a return"),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+// The function a chunk is run as.
+#[derive(Copy, Clone)]
+struct Signature {
+    arity: usize,
+    upvalues: usize,
+}
+
+/// Verify that `module` can be run without the VM indexing out of bounds.
+pub fn verify(module: &Module) -> Result<(), VerifyError> {
+    verify_stack_sizes(module).map(|_| ())
+}
+
+/// Verify `module` like [`verify`], returning how many stack slots a frame of each chunk
+/// uses at most, counting the function and its arguments.
+pub fn verify_stack_sizes(module: &Module) -> Result<Vec<usize>, VerifyError> {
+    if module.chunks().is_empty() {
+        return Err(VerifyError {
+            chunk: 0,
+            instruction: 0,
+            kind: VerifyErrorKind::MissingEntryChunk,
+        });
+    }
+
+    let signatures = signatures(module)?;
+    module
+        .chunks()
+        .iter()
+        .enumerate()
+        .map(|(index, chunk)| {
+            let verifier = ChunkVerifier {
+                module,
+                chunk,
+                index,
+                signature: signatures[index],
+            };
+            verifier.verify()
+        })
+        .collect()
+}
+
+// Find the arity and upvalue count of every chunk, the entry chunk takes no arguments.
+fn signatures(module: &Module) -> Result<Vec<Signature>, VerifyError> {
+    let mut signatures: Vec<Option<Signature>> = vec![None; module.chunks().len()];
+    signatures[0] = Some(Signature {
+        arity: 0,
+        upvalues: 0,
+    });
+
+    for (chunk_index, chunk) in module.chunks().iter().enumerate() {
+        for (instruction, &operand) in chunk.instructions().iter().enumerate() {
+            let error = |kind| VerifyError {
+                chunk: chunk_index,
+                instruction,
+                kind,
+            };
+
+            if let Instruction::Closure(index) = operand {
+                if let Some(Constant::Closure(closure)) = module.constants().get(index) {
+                    let function = closure.function.chunk_index;
+                    match signatures.get(function) {
+                        None => return Err(error(VerifyErrorKind::InvalidChunk(function))),
+                        Some(Some(_)) => return Err(error(VerifyErrorKind::SharedChunk(function))),
+                        Some(None) => (),
+                    }
+                    signatures[function] = Some(Signature {
+                        arity: closure.function.arity,
+                        upvalues: closure.upvalues.len(),
+                    });
+                }
+            }
+        }
+    }
+
+    // Chunks no closure refers to are never run, check them as if they were an entry chunk.
+    Ok(signatures
+        .into_iter()
+        .map(|signature| {
+            signature.unwrap_or(Signature {
+                arity: 0,
+                upvalues: 0,
+            })
+        })
+        .collect())
+}
+
+struct ChunkVerifier<'a> {
+    module: &'a Module,
+    chunk: &'a Chunk,
+    index: ChunkIndex,
+    signature: Signature,
+}
+
+impl ChunkVerifier<'_> {
+    fn error(&self, instruction: InstructionIndex, kind: VerifyErrorKind) -> VerifyError {
+        VerifyError {
+            chunk: self.index,
+            instruction,
+            kind,
+        }
+    }
+
+    fn verify(&self) -> Result<usize, VerifyError> {
+        let instructions = self.chunk.instructions();
+        match instructions.last() {
+            Some(Instruction::Return) => (),
+            _ => {
+                return Err(self.error(instructions.len(), VerifyErrorKind::MissingReturn));
+            }
+        }
+
+        for (index, &instruction) in instructions.iter().enumerate() {
+            self.verify_operands(index, instruction)?;
+        }
+
+        self.verify_stack()
+    }
+
+    // Operands that don't depend on the state of the stack.
+    fn verify_operands(
+        &self,
+        index: InstructionIndex,
+        instruction: Instruction,
+    ) -> Result<(), VerifyError> {
+        match instruction {
+            Instruction::Constant(constant) => self.expect_constant(index, constant, |c| {
+                matches!(c, Constant::Number(_) | Constant::String(_))
+            }),
+            Instruction::SetProperty(constant)
+            | Instruction::GetProperty(constant)
+            | Instruction::GetSuper(constant)
+            | Instruction::Method(constant) => {
+                self.expect_constant(index, constant, |c| matches!(c, Constant::String(_)))
+            }
+            Instruction::Class(constant) => {
+                self.expect_constant(index, constant, |c| matches!(c, Constant::Class(_)))
+            }
+            Instruction::Closure(constant) => {
+                self.expect_constant(index, constant, |c| matches!(c, Constant::Closure(_)))
+            }
+            Instruction::DefineGlobal(global)
+            | Instruction::GetGlobal(global)
+            | Instruction::SetGlobal(global) => {
+                if global < self.module.globals().len() {
+                    Ok(())
+                } else {
+                    Err(self.error(index, VerifyErrorKind::InvalidGlobal(global)))
+                }
+            }
+            Instruction::GetUpvalue(upvalue) | Instruction::SetUpvalue(upvalue) => {
+                if upvalue < self.signature.upvalues {
+                    Ok(())
+                } else {
+                    Err(self.error(index, VerifyErrorKind::InvalidUpvalue(upvalue)))
+                }
+            }
+            Instruction::Jump(to) | Instruction::JumpIfFalse(to) | Instruction::JumpIfTrue(to) => {
+                if to < self.chunk.instructions().len() {
+                    Ok(())
+                } else {
+                    Err(self.error(index, VerifyErrorKind::InvalidJump(to)))
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn expect_constant<F>(
+        &self,
+        index: InstructionIndex,
+        constant: ConstantIndex,
+        is_expected: F,
+    ) -> Result<(), VerifyError>
+    where
+        F: Fn(&Constant) -> bool,
+    {
+        match self.module.constants().get(constant) {
+            None => Err(self.error(index, VerifyErrorKind::InvalidConstant(constant))),
+            Some(c) if !is_expected(c) => {
+                Err(self.error(index, VerifyErrorKind::UnexpectedConstant(constant)))
+            }
+            Some(_) => Ok(()),
+        }
+    }
+
+    // Follow every path through the chunk, tracking the depth of the frame's stack.
+    // Returns the largest depth on any path.
+    fn verify_stack(&self) -> Result<usize, VerifyError> {
+        let instructions = self.chunk.instructions();
+        let mut depths: Vec<Option<usize>> = vec![None; instructions.len()];
+        // The callee and its arguments.
+        let mut pending = vec![(0, self.signature.arity + 1)];
+        let mut max_depth = self.signature.arity + 1;
+
+        while let Some((index, depth)) = pending.pop() {
+            match depths[index] {
+                Some(known) if known == depth => continue,
+                Some(known) => {
+                    return Err(
+                        self.error(index, VerifyErrorKind::InconsistentStackDepth(known, depth))
+                    )
+                }
+                None => depths[index] = Some(depth),
+            }
+
+            let instruction = instructions[index];
+            let (pops, pushes) = self.stack_effect(index, instruction, depth)?;
+            if depth < pops + 1 {
+                // Slot 0 belongs to the frame and can't be popped.
+                return Err(self.error(index, VerifyErrorKind::StackUnderflow));
+            }
+            let depth = depth - pops + pushes;
+            max_depth = max_depth.max(depth);
+
+            match instruction {
+                Instruction::Return => (),
+                Instruction::Jump(to) => pending.push((to, depth)),
+                Instruction::JumpIfFalse(to) | Instruction::JumpIfTrue(to) => {
+                    pending.push((to, depth));
+                    pending.push((index + 1, depth));
+                }
+                // The last instruction is a `Return`, so this never runs off the end.
+                _ => pending.push((index + 1, depth)),
+            }
+        }
+
+        Ok(max_depth)
+    }
+
+    // How many values an instruction pops and pushes, checking operands that refer to the stack.
+    fn stack_effect(
+        &self,
+        index: InstructionIndex,
+        instruction: Instruction,
+        depth: usize,
+    ) -> Result<(usize, usize), VerifyError> {
+        let effect = match instruction {
+            Instruction::Constant(_)
+            | Instruction::True
+            | Instruction::False
+            | Instruction::Nil
+            | Instruction::GetGlobal(_)
+            | Instruction::GetUpvalue(_)
+            | Instruction::Class(_) => (0, 1),
+            Instruction::Negate | Instruction::Not | Instruction::GetProperty(_) => (1, 1),
+            Instruction::Add
+            | Instruction::Subtract
+            | Instruction::Multiply
+            | Instruction::Divide
+            | Instruction::Equal
+            | Instruction::Greater
+            | Instruction::Less => (2, 1),
+            Instruction::Pop
+            | Instruction::Print
+            | Instruction::DefineGlobal(_)
+            | Instruction::CloseUpvalue
+            | Instruction::Return => (1, 0),
+            Instruction::SetGlobal(_)
+            | Instruction::SetUpvalue(_)
+            | Instruction::JumpIfFalse(_)
+            | Instruction::JumpIfTrue(_) => (1, 1),
+            Instruction::GetLocal(local) | Instruction::SetLocal(local) => {
+                if local >= depth {
+                    return Err(self.error(index, VerifyErrorKind::InvalidLocal(local)));
+                }
+                match instruction {
+                    Instruction::GetLocal(_) => (0, 1),
+                    _ => (1, 1),
+                }
+            }
+            Instruction::SetProperty(_) | Instruction::GetSuper(_) => (2, 1),
+            Instruction::Jump(_) => (0, 0),
+            Instruction::Call(arguments) => (arguments + 1, 1),
+            Instruction::Method(_) | Instruction::Inherit => (2, 1),
+            Instruction::Closure(constant) => {
+                if let Constant::Closure(closure) = self.module.constant(constant) {
+                    for upvalue in &closure.upvalues {
+                        match *upvalue {
+                            Upvalue::Local(local) if local >= depth => {
+                                return Err(self.error(index, VerifyErrorKind::InvalidLocal(local)))
+                            }
+                            Upvalue::Upvalue(upvalue) if upvalue >= self.signature.upvalues => {
+                                return Err(
+                                    self.error(index, VerifyErrorKind::InvalidUpvalue(upvalue))
+                                )
+                            }
+                            _ => (),
+                        }
+                    }
+                }
+                (0, 1)
+            }
+        };
+        Ok(effect)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPAN: Span = Span { start: 0, end: 0 };
+
+    fn module(instructions: &[Instruction]) -> Module {
+        let mut module = Module::new();
+        module.add_constant(Constant::Number(1.0));
+        module.add_constant(Constant::String("a".into()));
+        let chunk = module.add_chunk();
+        for &instruction in instructions {
+            module.chunk_mut(chunk).add_instruction(instruction, SPAN);
+        }
+        module
+    }
+
+    fn error(instructions: &[Instruction]) -> (InstructionIndex, VerifyErrorKind) {
+        let error = verify(&module(instructions)).unwrap_err();
+        (error.instruction, error.kind)
+    }
+
+    #[test]
+    fn test_valid() {
+        use Instruction::*;
+        // var a = 1; if (a) print a; else print "a";
+        assert_eq!(
+            verify(&module(&[
+                Constant(0),
+                GetLocal(1),
+                JumpIfFalse(6),
+                Pop,
+                GetLocal(1),
+                Jump(8),
+                Pop,
+                Constant(1),
+                Print,
+                Pop,
+                Nil,
+                Return,
+            ])),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_function() {
+        let mut module = module(&[]);
+        let function = module.add_chunk();
+        let closure = module.add_constant(Constant::Closure(Closure {
+            function: Function {
+                name: "f".into(),
+                chunk_index: function,
+                arity: 2,
+            },
+            upvalues: vec![Upvalue::Local(0)],
+        }));
+        let top = module.chunk_mut(0);
+        top.add_instruction(Instruction::Closure(closure), SPAN);
+        top.add_instruction(Instruction::Return, SPAN);
+        let chunk = module.chunk_mut(function);
+        chunk.add_instruction(Instruction::GetLocal(2), SPAN);
+        chunk.add_instruction(Instruction::GetUpvalue(0), SPAN);
+        chunk.add_instruction(Instruction::Add, SPAN);
+        chunk.add_instruction(Instruction::Return, SPAN);
+        assert_eq!(verify(&module), Ok(()));
+        assert_eq!(verify_stack_sizes(&module), Ok(vec![2, 5]));
+
+        let chunk = module.chunk_mut(function);
+        *chunk = Chunk::new();
+        chunk.add_instruction(Instruction::GetUpvalue(1), SPAN);
+        chunk.add_instruction(Instruction::Return, SPAN);
+        assert_eq!(
+            verify(&module).unwrap_err(),
+            VerifyError {
+                chunk: function,
+                instruction: 0,
+                kind: VerifyErrorKind::InvalidUpvalue(1)
+            }
+        );
+    }
+
+    #[test]
+    fn test_operands() {
+        use Instruction::*;
+        assert_eq!(
+            error(&[Constant(2), Return]),
+            (0, VerifyErrorKind::InvalidConstant(2))
+        );
+        assert_eq!(
+            error(&[GetProperty(0), Return]),
+            (0, VerifyErrorKind::UnexpectedConstant(0))
+        );
+        assert_eq!(
+            error(&[GetGlobal(0), Return]),
+            (0, VerifyErrorKind::InvalidGlobal(0))
+        );
+        assert_eq!(
+            error(&[Closure(1), Return]),
+            (0, VerifyErrorKind::UnexpectedConstant(1))
+        );
+        assert_eq!(
+            error(&[Nil, Jump(3), Return]),
+            (1, VerifyErrorKind::InvalidJump(3))
+        );
+        assert_eq!(
+            error(&[Nil, GetLocal(2), Return]),
+            (1, VerifyErrorKind::InvalidLocal(2))
+        );
+    }
+
+    #[test]
+    fn test_stack() {
+        use Instruction::*;
+        assert_eq!(
+            error(&[Nil, Add, Return]),
+            (1, VerifyErrorKind::StackUnderflow)
+        );
+        // The callee in slot 0 can't be popped.
+        assert_eq!(
+            error(&[Pop, Nil, Return]),
+            (0, VerifyErrorKind::StackUnderflow)
+        );
+        assert_eq!(
+            error(&[True, JumpIfFalse(3), Nil, Nil, Return]),
+            (3, VerifyErrorKind::InconsistentStackDepth(3, 2))
+        );
+    }
+
+    #[test]
+    fn test_missing_return() {
+        assert_eq!(
+            error(&[Instruction::Nil]),
+            (1, VerifyErrorKind::MissingReturn)
+        );
+        assert_eq!(
+            verify(&Module::new()).unwrap_err().kind,
+            VerifyErrorKind::MissingEntryChunk
+        );
+    }
+}
@@ -1,6 +1,8 @@
 use super::locals::*;
 use super::CompilerError;
 use crate::bytecode::*;
+use lox_bytecode::peephole;
+use lox_syntax::position;
 
 #[derive(Copy, Clone)]
 pub enum ContextType {
@@ -20,6 +22,9 @@ struct CompilerContext {
 pub struct Compiler {
     module: Module,
     contexts: Vec<CompilerContext>,
+    // Whether each class that is being compiled has a superclass, the innermost one last.
+    classes: Vec<bool>,
+    span: Span,
 }
 
 impl CompilerContext {
@@ -104,9 +109,15 @@ impl Compiler {
         Compiler {
             module: Module::new(),
             contexts: vec![],
+            classes: vec![],
+            span: Span::default(),
         }
     }
 
+    pub fn optimize_chunks(&mut self) {
+        peephole::optimize_module(&mut self.module);
+    }
+
     pub fn into_module(self) -> Module {
         self.module
     }
@@ -115,6 +126,22 @@ impl Compiler {
         self.current_context().context_type
     }
 
+    /// Compile the body of a class with `f`, methods inside it can use `this` and `super`.
+    pub fn with_class<F>(&mut self, has_superclass: bool, f: F) -> Result<(), CompilerError>
+    where
+        F: FnOnce(&mut Self) -> Result<(), CompilerError>,
+    {
+        self.classes.push(has_superclass);
+        let result = f(self);
+        self.classes.pop();
+        result
+    }
+
+    /// Whether the innermost class has a superclass, `None` outside of a class.
+    pub fn has_superclass(&self) -> Option<bool> {
+        self.classes.last().copied()
+    }
+
     pub fn with_scope<F>(&mut self, f: F) -> Result<(), CompilerError>
     where
         F: FnOnce(&mut Self) -> Result<(), CompilerError>,
@@ -141,7 +168,10 @@ impl Compiler {
         self.begin_context(context_type);
 
         //TODO Move to begin_context
-        self.add_local(""); //TODO call local 'this' for method/initializer and maybe toplevel?
+        match context_type {
+            ContextType::Method | ContextType::Initializer => self.add_local("this"),
+            ContextType::Function | ContextType::TopLevel => self.add_local(""),
+        }
         self.mark_local_initialized();
 
         let result = f(self);
@@ -164,8 +194,17 @@ impl Compiler {
         })
     }
 
+    /// Set the source span attached to every instruction added from now on.
+    pub fn set_span(&mut self, span: position::Span) {
+        self.span = Span {
+            start: span.start.0,
+            end: span.end.0,
+        };
+    }
+
     pub fn add_instruction(&mut self, instruction: Instruction) -> InstructionIndex {
-        self.current_chunk_mut().add_instruction(instruction)
+        let span = self.span;
+        self.current_chunk_mut().add_instruction(instruction, span)
     }
 
     pub fn patch_instruction(&mut self, index: InstructionIndex) {
@@ -205,6 +244,10 @@ impl Compiler {
         self.module.add_constant(constant.into())
     }
 
+    pub fn add_global(&mut self, name: &str) -> GlobalIndex {
+        self.module.add_global(name)
+    }
+
     pub fn resolve_upvalue(&mut self, name: &str) -> Result<Option<StackIndex>, CompilerError> {
         for i in (0..(self.contexts.len() - 1)).rev() {
             // Skip the current context
@@ -87,7 +87,7 @@ impl Locals {
 
     pub fn insert(&mut self, identifier: &str) -> Option<&Local> {
         //TODO Maybe Result<&Local, ()> instead
-        if let Some(_) = self.get_at_depth(identifier, self.scope_depth) {
+        if self.get_at_depth(identifier, self.scope_depth).is_some() {
             None
         } else {
             self.stack.push(Local {
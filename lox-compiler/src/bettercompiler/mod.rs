@@ -6,21 +6,100 @@ mod statements;
 mod tests;
 
 use crate::bytecode::*;
+use crate::OptimizationLevel;
 use compiler::{Compiler, ContextType};
 use lox_syntax::ast::*;
-use lox_syntax::position::WithSpan;
+use lox_syntax::diagnostics::Diagnostic;
+use lox_syntax::position::{Span, WithSpan};
 use statements::compile_ast;
+use std::fmt;
 
 #[derive(Debug)]
 pub enum CompilerError {
     LocalAlreadyDefined,
     LocalNotInitialized,
+    ReturnFromInitializer,
+    ThisOutsideClass,
+    SuperOutsideClass,
+    ClassInheritsFromSelf,
+    SuperWithoutSuperclass,
 
     Multiple(Vec<CompilerError>),
     WithSpan(WithSpan<Box<CompilerError>>),
 }
 
-pub fn compile(ast: &Ast) -> Result<Module, CompilerError> {
+impl CompilerError {
+    fn with_span(self, span: Span) -> CompilerError {
+        CompilerError::WithSpan(WithSpan::new(Box::new(self), span))
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            CompilerError::LocalAlreadyDefined => "E101",
+            CompilerError::LocalNotInitialized => "E102",
+            CompilerError::ReturnFromInitializer => "E103",
+            CompilerError::ThisOutsideClass => "E104",
+            CompilerError::SuperOutsideClass => "E105",
+            CompilerError::ClassInheritsFromSelf => "E106",
+            CompilerError::SuperWithoutSuperclass => "E107",
+            CompilerError::Multiple(_) => "E100",
+            CompilerError::WithSpan(error) => error.value.code(),
+        }
+    }
+
+    /// All errors as diagnostics, `Multiple` is flattened.
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        match self {
+            CompilerError::Multiple(errors) => {
+                errors.iter().flat_map(|e| e.diagnostics()).collect()
+            }
+            CompilerError::WithSpan(error) => error
+                .value
+                .diagnostics()
+                .into_iter()
+                .map(|diagnostic| match diagnostic.span {
+                    Some(_) => diagnostic,
+                    None => diagnostic.with_span(error.span),
+                })
+                .collect(),
+            error => vec![Diagnostic::new(error.code(), error.to_string())],
+        }
+    }
+}
+
+impl fmt::Display for CompilerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompilerError::LocalAlreadyDefined => {
+                write!(f, "Already a variable with this name in this scope.")
+            }
+            CompilerError::LocalNotInitialized => {
+                write!(f, "Can't read local variable in its own initializer.")
+            }
+            CompilerError::ReturnFromInitializer => {
+                write!(f, "Can't return a value from an initializer.")
+            }
+            CompilerError::ThisOutsideClass => write!(f, "Can't use 'this' outside of a class."),
+            CompilerError::SuperOutsideClass => write!(f, "Can't use 'super' outside of a class."),
+            CompilerError::ClassInheritsFromSelf => write!(f, "A class can't inherit from itself."),
+            CompilerError::SuperWithoutSuperclass => {
+                write!(f, "Can't use 'super' in a class with no superclass.")
+            }
+            CompilerError::Multiple(errors) => {
+                for (i, error) in errors.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{}", error)?;
+                }
+                Ok(())
+            }
+            CompilerError::WithSpan(error) => write!(f, "{}", error.value),
+        }
+    }
+}
+
+pub fn compile(ast: &Ast, level: OptimizationLevel) -> Result<Module, CompilerError> {
     let mut compiler = Compiler::new();
 
     compiler.with_context(ContextType::TopLevel, |compiler| {
@@ -30,5 +109,9 @@ pub fn compile(ast: &Ast) -> Result<Module, CompilerError> {
         Ok(())
     })?;
 
+    if level == OptimizationLevel::Basic {
+        compiler.optimize_chunks();
+    }
+
     Ok(compiler.into_module())
 }
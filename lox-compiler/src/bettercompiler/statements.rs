@@ -3,9 +3,9 @@ use super::compiler::ContextType;
 use super::CompilerError;
 use crate::bytecode::*;
 use lox_syntax::ast::*;
-use lox_syntax::position::WithSpan;
+use lox_syntax::position::{Span, WithSpan};
 
-pub fn compile_ast(compiler: &mut Compiler, ast: &Ast) -> Result<(), CompilerError> {
+pub fn compile_ast(compiler: &mut Compiler, ast: &[Stmt]) -> Result<(), CompilerError> {
     let errors: Vec<_> = ast
         .iter()
         .map(|stmt| compile_stmt(compiler, stmt))
@@ -33,20 +33,24 @@ fn compile_stmt(compiler: &mut Compiler, stmt: &Stmt) -> Result<(), CompilerErro
         Stmt::Function(ref identifier, ref args, ref stmts) => {
             compile_function(compiler, identifier.as_ref(), args, stmts)
         }
-        Stmt::Return(ref expr) => compile_return(compiler, expr.as_ref()),
+        Stmt::Return(span, ref expr) => compile_return(compiler, *span, expr.as_ref()),
         Stmt::Class(ref identifier, ref extends, ref stmts) => {
             compile_class(compiler, identifier.as_ref(), extends.as_ref(), stmts)
         }
     }
 }
 
-fn declare_variable(compiler: &mut Compiler, identifier: &str) -> Result<(), CompilerError> {
+fn declare_variable<I: AsRef<str>>(
+    compiler: &mut Compiler,
+    identifier: WithSpan<I>,
+) -> Result<(), CompilerError> {
     if compiler.is_scoped() {
-        if compiler.has_local_in_current_scope(identifier) {
-            return Err(CompilerError::LocalAlreadyDefined); //TODO Don't return error, do `add_error` instead.
+        if compiler.has_local_in_current_scope(identifier.value.as_ref()) {
+            //TODO Don't return error, do `add_error` instead.
+            return Err(CompilerError::LocalAlreadyDefined.with_span(identifier.span));
         }
 
-        compiler.add_local(identifier);
+        compiler.add_local(identifier.value.as_ref());
     }
     Ok(())
 }
@@ -55,35 +59,98 @@ fn define_variable(compiler: &mut Compiler, identifier: &str) {
     if compiler.is_scoped() {
         compiler.mark_local_initialized();
     } else {
-        let constant = compiler.add_constant(identifier);
-        compiler.add_instruction(Instruction::DefineGlobal(constant));
+        let global = compiler.add_global(identifier);
+        compiler.add_instruction(Instruction::DefineGlobal(global));
     }
 }
 
 fn compile_class(
     compiler: &mut Compiler,
     identifier: WithSpan<&String>,
-    _extends: Option<&WithSpan<String>>,
-    _stmts: &[Stmt],
+    extends: Option<&WithSpan<String>>,
+    stmts: &[Stmt],
 ) -> Result<(), CompilerError> {
-    declare_variable(compiler, identifier.value)?;
+    compiler.set_span(identifier.span);
+    declare_variable(compiler, identifier.clone())?;
     let constant = compiler.add_constant(Constant::Class(Class {
         name: identifier.value.to_string(),
     }));
     compiler.add_instruction(Instruction::Class(constant));
     define_variable(compiler, identifier.value);
 
-    //TODO Extends
-    //TODO Methods
+    compiler.with_class(extends.is_some(), |compiler| {
+        if let Some(superclass) = extends {
+            if superclass.value == *identifier.value {
+                return Err(CompilerError::ClassInheritsFromSelf.with_span(superclass.span));
+            }
+
+            compiler.with_scope(|compiler| {
+                compile_variable(compiler, superclass.as_ref())?;
+                compiler.add_local("super");
+                compiler.mark_local_initialized();
+
+                compile_variable(compiler, identifier.clone())?;
+                compiler.set_span(superclass.span);
+                compiler.add_instruction(Instruction::Inherit);
+
+                compile_methods(compiler, identifier, stmts)
+            })
+        } else {
+            compile_methods(compiler, identifier, stmts)
+        }
+    })
+}
+
+fn compile_methods(
+    compiler: &mut Compiler,
+    identifier: WithSpan<&String>,
+    stmts: &[Stmt],
+) -> Result<(), CompilerError> {
+    if !stmts.is_empty() {
+        compile_variable(compiler, identifier)?;
+        for stmt in stmts {
+            if let Stmt::Function(ref identifier, ref args, ref block) = stmt {
+                compile_method(compiler, identifier.as_ref(), args, block)?;
+            }
+        }
+        compiler.add_instruction(Instruction::Pop);
+    }
+
+    Ok(())
+}
+
+fn compile_method(
+    compiler: &mut Compiler,
+    identifier: WithSpan<&String>,
+    args: &[WithSpan<Identifier>],
+    block: &[Stmt],
+) -> Result<(), CompilerError> {
+    compiler.set_span(identifier.span);
+    let context_type = if identifier.value == "init" {
+        ContextType::Initializer
+    } else {
+        ContextType::Method
+    };
+
+    compile_closure(compiler, identifier.value, args, block, context_type)?;
+
+    let constant = compiler.add_constant(identifier.value.as_str());
+    compiler.add_instruction(Instruction::Method(constant));
 
     Ok(())
 }
 
 fn compile_return<E: AsRef<Expr>>(
     compiler: &mut Compiler,
+    span: Span,
     expr: Option<E>,
 ) -> Result<(), CompilerError> {
-    if let Some(expr) = expr {
+    if let ContextType::Initializer = compiler.context_type() {
+        if expr.is_some() {
+            return Err(CompilerError::ReturnFromInitializer.with_span(span));
+        }
+        compiler.add_instruction(Instruction::GetLocal(0));
+    } else if let Some(expr) = expr {
         compile_expr(compiler, expr.as_ref())?;
     } else {
         compile_nil(compiler)?;
@@ -95,44 +162,60 @@ fn compile_return<E: AsRef<Expr>>(
 fn compile_function(
     compiler: &mut Compiler,
     identifier: WithSpan<&String>,
-    args: &Vec<WithSpan<Identifier>>,
-    block: &Vec<Stmt>,
+    args: &[WithSpan<Identifier>],
+    block: &[Stmt],
 ) -> Result<(), CompilerError> {
-    declare_variable(compiler, identifier.value)?;
+    compiler.set_span(identifier.span);
+    declare_variable(compiler, identifier.clone())?;
     if compiler.is_scoped() {
         compiler.mark_local_initialized();
     }
 
-    let (chunk_index, upvalues) =
-        compiler.with_scoped_context(ContextType::Function, |compiler| {
-            for arg in args {
-                declare_variable(compiler, &arg.value)?;
-                define_variable(compiler, &arg.value);
-            }
+    compile_closure(compiler, identifier.value, args, block, ContextType::Function)?;
+
+    compiler.set_span(identifier.span);
+    define_variable(compiler, identifier.value);
 
-            compile_block(compiler, block)?;
+    Ok(())
+}
 
-            compiler.add_instruction(Instruction::Nil);
-            compiler.add_instruction(Instruction::Return);
-            Ok(())
-        })?;
+fn compile_closure(
+    compiler: &mut Compiler,
+    name: &str,
+    args: &[WithSpan<Identifier>],
+    block: &[Stmt],
+    context_type: ContextType,
+) -> Result<(), CompilerError> {
+    let (chunk_index, upvalues) = compiler.with_scoped_context(context_type, |compiler| {
+        for arg in args {
+            declare_variable(compiler, arg.as_ref())?;
+            define_variable(compiler, &arg.value);
+        }
+
+        compile_block(compiler, block)?;
+
+        match compiler.context_type() {
+            ContextType::Initializer => compiler.add_instruction(Instruction::GetLocal(0)),
+            _ => compiler.add_instruction(Instruction::Nil),
+        };
+        compiler.add_instruction(Instruction::Return);
+        Ok(())
+    })?;
 
     let function = Function {
-        name: identifier.value.into(),
+        name: name.into(),
         chunk_index,
         arity: args.len(),
     };
 
     let closure = Closure {
         function,
-        upvalues: upvalues,
+        upvalues,
     };
 
     let constant = compiler.add_constant(Constant::Closure(closure));
     compiler.add_instruction(Instruction::Closure(constant));
 
-    define_variable(compiler, identifier.value);
-
     Ok(())
 }
 
@@ -165,15 +248,14 @@ fn compile_if<S: AsRef<Stmt>>(
     compiler.add_instruction(Instruction::Pop);
     compile_stmt(compiler, then_stmt)?;
 
+    // The condition is still on the stack when the jump is taken, even without an else branch.
+    let else_index = compiler.add_instruction(Instruction::Jump(0));
+    compiler.patch_instruction(then_index);
+    compiler.add_instruction(Instruction::Pop);
     if let Some(else_stmt) = else_stmt {
-        let else_index = compiler.add_instruction(Instruction::Jump(0));
-        compiler.patch_instruction(then_index);
-        compiler.add_instruction(Instruction::Pop);
         compile_stmt(compiler, else_stmt.as_ref())?;
-        compiler.patch_instruction(else_index);
-    } else {
-        compiler.patch_instruction(then_index);
     }
+    compiler.patch_instruction(else_index);
     Ok(())
 }
 
@@ -183,7 +265,7 @@ fn compile_expression_statement(compiler: &mut Compiler, expr: &Expr) -> Result<
     Ok(())
 }
 
-fn compile_block(compiler: &mut Compiler, ast: &Ast) -> Result<(), CompilerError> {
+fn compile_block(compiler: &mut Compiler, ast: &[Stmt]) -> Result<(), CompilerError> {
     compiler.with_scope(|compiler| compile_ast(compiler, ast))
 }
 
@@ -192,7 +274,8 @@ fn compile_var_declaration<T: AsRef<Expr>, I: AsRef<str>>(
     identifier: WithSpan<I>,
     expr: Option<T>,
 ) -> Result<(), CompilerError> {
-    declare_variable(compiler, identifier.value.as_ref())?;
+    compiler.set_span(identifier.span);
+    declare_variable(compiler, identifier.as_ref())?;
 
     //expr
     if let Some(expr) = expr {
@@ -201,6 +284,7 @@ fn compile_var_declaration<T: AsRef<Expr>, I: AsRef<str>>(
         compile_nil(compiler)?;
     }
 
+    compiler.set_span(identifier.span);
     define_variable(compiler, identifier.value.as_ref());
 
     Ok(())
@@ -235,8 +319,48 @@ fn compile_expr(compiler: &mut Compiler, expr: &Expr) -> Result<(), CompilerErro
             compiler_set(compiler, expr, identifier.as_ref(), value)
         }
         Expr::Get(ref expr, ref identifier) => compiler_get(compiler, expr, identifier.as_ref()),
-        ref expr => unimplemented!("{:?}", expr),
+        Expr::This(span) => compile_this(compiler, span),
+        Expr::Super(span, ref identifier) => compile_super(compiler, span, identifier.as_ref()),
+    }
+}
+
+fn compile_this(compiler: &mut Compiler, span: Span) -> Result<(), CompilerError> {
+    if !compile_hidden_local(compiler, "this")? {
+        return Err(CompilerError::ThisOutsideClass.with_span(span));
+    }
+    Ok(())
+}
+
+fn compile_super(
+    compiler: &mut Compiler,
+    span: Span,
+    identifier: WithSpan<&String>,
+) -> Result<(), CompilerError> {
+    // Only the innermost class counts, `super` must not find the superclass of an outer one.
+    match compiler.has_superclass() {
+        None => return Err(CompilerError::SuperOutsideClass.with_span(span)),
+        Some(false) => return Err(CompilerError::SuperWithoutSuperclass.with_span(span)),
+        Some(true) => (),
+    }
+    if !compile_hidden_local(compiler, "this")? || !compile_hidden_local(compiler, "super")? {
+        return Err(CompilerError::SuperOutsideClass.with_span(span));
+    }
+    let constant = compiler.add_constant(identifier.value.as_str());
+    compiler.set_span(identifier.span);
+    compiler.add_instruction(Instruction::GetSuper(constant));
+    Ok(())
+}
+
+// Get `this` or `super` from the enclosing method, returns false outside of a method that has one.
+fn compile_hidden_local(compiler: &mut Compiler, name: &str) -> Result<bool, CompilerError> {
+    if let Some(local) = compiler.resolve_local(name)? {
+        compiler.add_instruction(Instruction::GetLocal(local));
+    } else if let Some(upvalue) = compiler.resolve_upvalue(name)? {
+        compiler.add_instruction(Instruction::GetUpvalue(upvalue));
+    } else {
+        return Ok(false);
     }
+    Ok(true)
 }
 
 fn compiler_get(
@@ -246,6 +370,7 @@ fn compiler_get(
 ) -> Result<(), CompilerError> {
     compile_expr(compiler, expr)?;
     let constant = compiler.add_constant(identifier.value.as_str());
+    compiler.set_span(identifier.span);
     compiler.add_instruction(Instruction::GetProperty(constant));
     Ok(())
 }
@@ -259,6 +384,7 @@ fn compiler_set(
     compile_expr(compiler, expr)?;
     compile_expr(compiler, value)?;
     let constant = compiler.add_constant(identifier.value.as_str());
+    compiler.set_span(identifier.span);
     compiler.add_instruction(Instruction::SetProperty(constant));
     Ok(())
 }
@@ -269,6 +395,7 @@ fn compile_unary(
     expr: &Expr,
 ) -> Result<(), CompilerError> {
     compile_expr(compiler, expr)?;
+    compiler.set_span(operator.span);
     match operator.value {
         UnaryOperator::Minus => compiler.add_instruction(Instruction::Negate),
         UnaryOperator::Bang => compiler.add_instruction(Instruction::Not),
@@ -280,13 +407,14 @@ fn compile_unary(
 fn compile_call(
     compiler: &mut Compiler,
     identifier: &Expr,
-    args: &Vec<Expr>,
+    args: &WithSpan<Vec<Expr>>,
 ) -> Result<(), CompilerError> {
     compile_expr(compiler, identifier)?;
-    for arg in args {
+    for arg in &args.value {
         compile_expr(compiler, arg)?;
     }
-    compiler.add_instruction(Instruction::Call(args.len()));
+    compiler.set_span(args.span);
+    compiler.add_instruction(Instruction::Call(args.value.len()));
     Ok(())
 }
 
@@ -302,16 +430,13 @@ fn compile_logical(
     }
 }
 
-//TODO Implement this better, using one less jump, we can easily introduce a JumpIfTrue instruction.
 fn compile_logical_or(
     compiler: &mut Compiler,
     left: &Expr,
     right: &Expr,
 ) -> Result<(), CompilerError> {
     compile_expr(compiler, left)?;
-    let else_jump = compiler.add_instruction(Instruction::JumpIfFalse(0));
-    let end_jump = compiler.add_instruction(Instruction::Jump(0));
-    compiler.patch_instruction(else_jump);
+    let end_jump = compiler.add_instruction(Instruction::JumpIfTrue(0));
     compiler.add_instruction(Instruction::Pop);
     compile_expr(compiler, right)?;
     compiler.patch_instruction(end_jump);
@@ -347,16 +472,23 @@ fn compile_assign(
     expr: &Expr,
 ) -> Result<(), CompilerError> {
     compile_expr(compiler, expr)?;
-    if let Some(local) = compiler.resolve_local(identifier.value)? {
+    compiler.set_span(identifier.span);
+    if let Some(local) = compiler
+        .resolve_local(identifier.value)
+        .map_err(|error| error.with_span(identifier.span))?
+    {
         // Local
         compiler.add_instruction(Instruction::SetLocal(local));
-    } else if let Some(upvalue) = compiler.resolve_upvalue(identifier.value)? {
+    } else if let Some(upvalue) = compiler
+        .resolve_upvalue(identifier.value)
+        .map_err(|error| error.with_span(identifier.span))?
+    {
         // Upvalue
         compiler.add_instruction(Instruction::SetUpvalue(upvalue));
     } else {
         // Global
-        let constant = compiler.add_constant(identifier.value.as_str());
-        compiler.add_instruction(Instruction::SetGlobal(constant));
+        let global = compiler.add_global(identifier.value.as_str());
+        compiler.add_instruction(Instruction::SetGlobal(global));
     }
     Ok(())
 }
@@ -365,16 +497,23 @@ fn compile_variable(
     compiler: &mut Compiler,
     identifier: WithSpan<&String>,
 ) -> Result<(), CompilerError> {
-    if let Some(local) = compiler.resolve_local(identifier.value)? {
+    compiler.set_span(identifier.span);
+    if let Some(local) = compiler
+        .resolve_local(identifier.value)
+        .map_err(|error| error.with_span(identifier.span))?
+    {
         // Local
         compiler.add_instruction(Instruction::GetLocal(local));
-    } else if let Some(upvalue) = compiler.resolve_upvalue(identifier.value)? {
+    } else if let Some(upvalue) = compiler
+        .resolve_upvalue(identifier.value)
+        .map_err(|error| error.with_span(identifier.span))?
+    {
         // Upvalue
         compiler.add_instruction(Instruction::GetUpvalue(upvalue));
     } else {
         // Global
-        let constant = compiler.add_constant(identifier.value.as_str());
-        compiler.add_instruction(Instruction::GetGlobal(constant));
+        let global = compiler.add_global(identifier.value.as_str());
+        compiler.add_instruction(Instruction::GetGlobal(global));
     }
     Ok(())
 }
@@ -404,6 +543,7 @@ fn compile_binary(
 ) -> Result<(), CompilerError> {
     compile_expr(compiler, left)?;
     compile_expr(compiler, right)?;
+    compiler.set_span(operator.span);
     match operator.value {
         BinaryOperator::Plus => compiler.add_instruction(Instruction::Add),
         BinaryOperator::Minus => compiler.add_instruction(Instruction::Subtract),
@@ -1,15 +1,21 @@
 use crate::bytecode::*;
+use crate::OptimizationLevel;
 use lox_syntax::ast::*;
 use lox_syntax::SyntaxError;
 
-fn parse_stmt(data: &str) -> Result<Vec<Stmt>, SyntaxError> {
-    lox_syntax::parse(data)
+fn parse_stmt(data: &str) -> Result<Vec<Stmt>, Vec<SyntaxError>> {
+    let (ast, errors) = lox_syntax::parse(data);
+    if errors.is_empty() {
+        Ok(ast)
+    } else {
+        Err(errors)
+    }
 }
 
 fn assert_first_chunk(data: &str, constants: Vec<Constant>, instructions: Vec<Instruction>) {
     use super::compile;
     let ast = parse_stmt(data).unwrap();
-    let module = compile(&ast).unwrap();
+    let module = compile(&ast, OptimizationLevel::None).unwrap();
     let chunk = module.chunk(0);
     assert_eq!(instructions, chunk.instructions());
     assert_eq!(constants, module.constants());
@@ -18,7 +24,7 @@ fn assert_first_chunk(data: &str, constants: Vec<Constant>, instructions: Vec<In
 fn compile_code(data: &str) -> Module {
     use super::compile;
     let ast = parse_stmt(data).unwrap();
-    compile(&ast).unwrap()
+    compile(&ast, OptimizationLevel::None).unwrap()
 }
 
 fn assert_instructions(chunk: &Chunk, instructions: Vec<Instruction>) {
@@ -29,6 +35,10 @@ fn assert_constants(module: &Module, constants: Vec<Constant>) {
     assert_eq!(constants, module.constants());
 }
 
+fn assert_globals(module: &Module, globals: Vec<&str>) {
+    assert_eq!(globals, module.globals());
+}
+
 #[test]
 fn test_stmt_print_numbers() {
     assert_first_chunk(
@@ -108,17 +118,17 @@ fn test_global_variables() {
     use crate::bytecode::Instruction::*;
     assert_first_chunk(
         "var x=3;",
-        vec![3.0.into(), "x".into()],
+        vec![3.0.into()],
         vec![
             Instruction::Constant(0),
-            Instruction::DefineGlobal(1),
+            Instruction::DefineGlobal(0),
             Instruction::Nil,
             Instruction::Return,
         ],
     );
     assert_first_chunk(
         "var x;",
-        vec!["x".into()],
+        vec![],
         vec![
             Instruction::Nil,
             Instruction::DefineGlobal(0),
@@ -128,11 +138,11 @@ fn test_global_variables() {
     );
     assert_first_chunk(
         "var x=3; print x;",
-        vec![3.0.into(), "x".into(), "x".into()],
+        vec![3.0.into()],
         vec![
             Instruction::Constant(0),
-            Instruction::DefineGlobal(1),
-            Instruction::GetGlobal(2),
+            Instruction::DefineGlobal(0),
+            Instruction::GetGlobal(0),
             Instruction::Print,
             Instruction::Nil,
             Instruction::Return,
@@ -140,17 +150,37 @@ fn test_global_variables() {
     );
     assert_first_chunk(
         "var x=3;x=2;",
-        vec![3.0.into(), "x".into(), 2.0.into(), "x".into()],
+        vec![3.0.into(), 2.0.into()],
         vec![
             Constant(0),
-            DefineGlobal(1),
-            Constant(2),
-            SetGlobal(3),
+            DefineGlobal(0),
+            Constant(1),
+            SetGlobal(0),
             Pop,
             Instruction::Nil,
             Instruction::Return,
         ],
     );
+
+    // Globals get a slot when they are first used, even if they are defined later.
+    let module = compile_code("print y; var x; var y; x = y;");
+    assert_instructions(
+        module.chunk(0),
+        vec![
+            GetGlobal(0),
+            Print,
+            Nil,
+            DefineGlobal(1),
+            Nil,
+            DefineGlobal(0),
+            GetGlobal(0),
+            SetGlobal(1),
+            Pop,
+            Nil,
+            Return,
+        ],
+    );
+    assert_globals(&module, vec!["y", "x"]);
 }
 
 #[test]
@@ -180,19 +210,19 @@ fn test_local_variables() {
     );
     assert_first_chunk(
         "var x=2; {var x=3; { var x=4; print x; } print x;} print x;",
-        vec![2.0.into(), "x".into(), 3.0.into(), 4.0.into(), "x".into()],
+        vec![2.0.into(), 3.0.into(), 4.0.into()],
         vec![
             Constant(0),
-            DefineGlobal(1),
+            DefineGlobal(0),
+            Constant(1),
             Constant(2),
-            Constant(3),
             GetLocal(2),
             Print,
             Pop,
             GetLocal(1),
             Print,
             Pop,
-            GetGlobal(4),
+            GetGlobal(0),
             Print,
             Instruction::Nil,
             Instruction::Return,
@@ -244,10 +274,12 @@ fn test_if() {
         vec![3.0.into(), 4.0.into()],
         vec![
             False,
-            JumpIfFalse(5),
+            JumpIfFalse(6),
             Pop,
             Constant(0),
             Pop,
+            Jump(7),
+            Pop,
             Constant(1),
             Pop,
             Nil,
@@ -299,8 +331,7 @@ fn test_logical_operators() {
         vec![3.0.into(), 4.0.into()],
         vec![
             Constant(0),
-            JumpIfFalse(3),
-            Jump(5),
+            JumpIfTrue(4),
             Pop,
             Constant(1),
             Pop,
@@ -310,6 +341,42 @@ fn test_logical_operators() {
     );
 }
 
+#[test]
+fn test_peephole() {
+    use super::compile;
+    use crate::bytecode::Instruction::*;
+
+    let ast = parse_stmt("var a = true; if (!a) print 1; 2; while (a or false) a = false;").unwrap();
+    let module = compile(&ast, OptimizationLevel::Basic).unwrap();
+    assert_instructions(
+        module.chunk(0),
+        vec![
+            True,
+            DefineGlobal(0),
+            GetGlobal(0),
+            JumpIfTrue(8),
+            Pop,
+            Constant(0),
+            Print,
+            Jump(9),
+            Pop,
+            GetGlobal(0),
+            JumpIfTrue(14),
+            Pop,
+            False,
+            JumpIfFalse(19),
+            Pop,
+            False,
+            SetGlobal(0),
+            Pop,
+            Jump(9),
+            Pop,
+            Nil,
+            Return,
+        ],
+    );
+}
+
 #[test]
 fn test_equality() {
     use crate::bytecode::Instruction::*;
@@ -382,8 +449,8 @@ fn test_simple_function() {
         module.chunk(0),
         vec![
             Closure(1),
-            DefineGlobal(2),
-            GetGlobal(3),
+            DefineGlobal(0),
+            GetGlobal(0),
             Call(0),
             Pop,
             Instruction::Nil,
@@ -392,15 +459,8 @@ fn test_simple_function() {
     );
     assert_instructions(module.chunk(1), vec![Constant(0), Print, Nil, Return]);
 
-    assert_constants(
-        &module,
-        vec![
-            3.0.into(),
-            make_fun("first", 1, 0),
-            "first".into(),
-            "first".into(),
-        ],
-    );
+    assert_constants(&module, vec![3.0.into(), make_fun("first", 1, 0)]);
+    assert_globals(&module, vec!["first"]);
 }
 
 #[test]
@@ -413,9 +473,9 @@ fn test_function_with_one_argument() {
         module.chunk(0),
         vec![
             Closure(0),
-            DefineGlobal(1),
-            GetGlobal(2),
-            Constant(3),
+            DefineGlobal(0),
+            GetGlobal(0),
+            Constant(1),
             Call(1),
             Pop,
             Instruction::Nil,
@@ -426,12 +486,7 @@ fn test_function_with_one_argument() {
 
     assert_constants(
         &module,
-        vec![
-            make_fun("first", 1, 1),
-            "first".into(),
-            "first".into(),
-            3.0.into(),
-        ],
+        vec![make_fun("first", 1, 1), 3.0.into()],
     );
 }
 
@@ -444,10 +499,10 @@ fn test_recursive_function_with_one_argument() {
     assert_instructions(
         module.chunk(0),
         vec![
-            Closure(2),
-            DefineGlobal(3),
-            GetGlobal(4),
-            Constant(5),
+            Closure(1),
+            DefineGlobal(0),
+            GetGlobal(0),
+            Constant(2),
             Call(1),
             Pop,
             Nil,
@@ -459,7 +514,7 @@ fn test_recursive_function_with_one_argument() {
         vec![
             GetGlobal(0),
             GetLocal(1),
-            Constant(1),
+            Constant(0),
             Add,
             Call(1),
             Print,
@@ -470,15 +525,9 @@ fn test_recursive_function_with_one_argument() {
 
     assert_constants(
         &module,
-        vec![
-            "first".into(),
-            1.0.into(),
-            make_fun("first", 1, 1),
-            "first".into(),
-            "first".into(),
-            3.0.into(),
-        ],
+        vec![1.0.into(), make_fun("first", 1, 1), 3.0.into()],
     );
+    assert_globals(&module, vec!["first"]);
 }
 
 #[test]
@@ -490,11 +539,11 @@ fn test_functions_calling_functions() {
     assert_instructions(
         module.chunk(0),
         vec![
-            Closure(1),
-            DefineGlobal(2),
-            Closure(4),
-            DefineGlobal(5),
-            GetGlobal(6),
+            Closure(0),
+            DefineGlobal(1),
+            Closure(2),
+            DefineGlobal(0),
+            GetGlobal(1),
             Call(0),
             Pop,
             Instruction::Nil,
@@ -505,20 +554,17 @@ fn test_functions_calling_functions() {
         module.chunk(1),
         vec![GetGlobal(0), Call(0), Pop, Nil, Return],
     );
-    assert_instructions(module.chunk(2), vec![Constant(3), Print, Nil, Return]);
+    assert_instructions(module.chunk(2), vec![Constant(1), Print, Nil, Return]);
 
     assert_constants(
         &module,
         vec![
-            "second".into(),
             make_fun("first", 1, 0),
-            "first".into(),
             3.0.into(),
             make_fun("second", 2, 0),
-            "second".into(),
-            "first".into(),
         ],
     );
+    assert_globals(&module, vec!["second", "first"]);
 }
 
 #[test]
@@ -583,17 +629,14 @@ fn test_function_with_return() {
         module.chunk(0),
         vec![
             Closure(1),
-            DefineGlobal(2),
+            DefineGlobal(0),
             Instruction::Nil,
             Instruction::Return,
         ],
     );
     assert_instructions(module.chunk(1), vec![Constant(0), Return, Nil, Return]);
 
-    assert_constants(
-        &module,
-        vec![3.0.into(), make_fun("first", 1, 0), "first".into()],
-    );
+    assert_constants(&module, vec![3.0.into(), make_fun("first", 1, 0)]);
 }
 
 #[test]
@@ -720,9 +763,9 @@ fn test_scoped_upvalue() {
         vec![
             Nil,
             DefineGlobal(0),
-            Closure(4),
-            DefineGlobal(5),
-            GetGlobal(6),
+            Closure(2),
+            DefineGlobal(1),
+            GetGlobal(1),
             Call(0),
             Pop,
             Instruction::Nil,
@@ -732,10 +775,10 @@ fn test_scoped_upvalue() {
     assert_instructions(
         module.chunk(1),
         vec![
-            Constant(1),
-            Closure(2),
+            Constant(0),
+            Closure(1),
             GetLocal(2),
-            SetGlobal(3),
+            SetGlobal(0),
             Pop,
             Pop,
             CloseUpvalue,
@@ -748,15 +791,12 @@ fn test_scoped_upvalue() {
     assert_constants(
         &module,
         vec![
-            "global".into(),
             3.0.into(),
             make_closure("one", 2, 0, vec![Upvalue::Local(1)]),
-            "global".into(),
             make_closure("main", 1, 0, vec![]),
-            "main".into(),
-            "main".into(),
         ],
     );
+    assert_globals(&module, vec!["global", "main"]);
 }
 
 #[test]
@@ -767,10 +807,11 @@ fn test_empty_class_global() {
 
     assert_instructions(
         module.chunk(0),
-        vec![Class(0), DefineGlobal(1), Nil, Return],
+        vec![Class(0), DefineGlobal(0), Nil, Return],
     );
 
-    assert_constants(&module, vec![make_class("Foo"), "Foo".into()]);
+    assert_constants(&module, vec![make_class("Foo")]);
+    assert_globals(&module, vec!["Foo"]);
 }
 
 #[test]
@@ -792,10 +833,10 @@ fn test_set_property() {
 
     assert_instructions(
         module.chunk(0),
-        vec![GetGlobal(0), Constant(1), SetProperty(2), Pop, Nil, Return],
+        vec![GetGlobal(0), Constant(0), SetProperty(1), Pop, Nil, Return],
     );
 
-    assert_constants(&module, vec!["x".into(), 3.0.into(), "test".into()]);
+    assert_constants(&module, vec![3.0.into(), "test".into()]);
 }
 
 #[test]
@@ -806,17 +847,17 @@ fn test_get_property() {
 
     assert_instructions(
         module.chunk(0),
-        vec![GetGlobal(0), GetProperty(1), Pop, Nil, Return],
+        vec![GetGlobal(0), GetProperty(0), Pop, Nil, Return],
     );
 
-    assert_constants(&module, vec!["x".into(), "test".into()]);
+    assert_constants(&module, vec!["test".into()]);
 }
 
 fn make_fun(name: &str, index: usize, arity: usize) -> Constant {
     crate::bytecode::Function {
         name: name.into(),
         chunk_index: index,
-        arity: arity,
+        arity,
     }
     .into()
 }
@@ -825,7 +866,7 @@ fn make_closure(name: &str, index: usize, arity: usize, upvalues: Vec<Upvalue>)
     let function = crate::bytecode::Function {
         name: name.into(),
         chunk_index: index,
-        arity: arity,
+        arity,
     };
     let closure = crate::bytecode::Closure { function, upvalues };
     Constant::Closure(closure)
@@ -836,3 +877,371 @@ fn make_class(name: &str) -> Constant {
         name: name.to_string(),
     })
 }
+
+#[test]
+fn test_class_with_method() {
+    use crate::bytecode::Instruction::*;
+
+    let module = compile_code("class Foo { bar() { print this; } }");
+
+    assert_instructions(
+        module.chunk(0),
+        vec![
+            Class(0),
+            DefineGlobal(0),
+            GetGlobal(0),
+            Closure(1),
+            Method(2),
+            Pop,
+            Nil,
+            Return,
+        ],
+    );
+    assert_instructions(module.chunk(1), vec![GetLocal(0), Print, Nil, Return]);
+
+    assert_constants(
+        &module,
+        vec![make_class("Foo"), make_fun("bar", 1, 0), "bar".into()],
+    );
+}
+
+#[test]
+fn test_local_class_with_method() {
+    use crate::bytecode::Instruction::*;
+
+    let module = compile_code("{class Foo { bar() {} }}");
+
+    assert_instructions(
+        module.chunk(0),
+        vec![Class(0), GetLocal(1), Closure(1), Method(2), Pop, Pop, Nil, Return],
+    );
+    assert_instructions(module.chunk(1), vec![Nil, Return]);
+}
+
+#[test]
+fn test_class_initializer() {
+    use crate::bytecode::Instruction::*;
+
+    let module = compile_code("class Foo { init(a) { this.a = a; return; } }");
+
+    assert_instructions(
+        module.chunk(1),
+        vec![
+            GetLocal(0),
+            GetLocal(1),
+            SetProperty(1),
+            Pop,
+            GetLocal(0),
+            Return,
+            GetLocal(0),
+            Return,
+        ],
+    );
+}
+
+#[test]
+fn test_this_in_closure() {
+    use crate::bytecode::Instruction::*;
+
+    let module = compile_code("class Foo { bar() { fun baz() { return this; } } }");
+
+    assert_instructions(module.chunk(2), vec![GetUpvalue(0), Return, Nil, Return]);
+    assert_constants(
+        &module,
+        vec![
+            make_class("Foo"),
+            make_closure("baz", 2, 0, vec![Upvalue::Local(0)]),
+            make_fun("bar", 1, 0),
+            "bar".into(),
+        ],
+    );
+}
+
+#[test]
+fn test_class_errors() {
+    use super::{compile, CompilerError};
+
+    let ast = parse_stmt("print this;").unwrap();
+    assert!(matches!(
+        compile(&ast, OptimizationLevel::None),
+        Err(CompilerError::Multiple(ref errors)) if matches!(errors[..], [CompilerError::WithSpan(ref error)] if matches!(*error.value, CompilerError::ThisOutsideClass))
+    ));
+
+    let ast = parse_stmt("class Foo { init() { return 3; } }").unwrap();
+    let diagnostics = compile(&ast, OptimizationLevel::None).unwrap_err().diagnostics();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].code, "E103");
+}
+
+#[test]
+fn test_class_inheritance() {
+    use crate::bytecode::Instruction::*;
+
+    let module = compile_code("class Foo < Bar {}");
+
+    assert_instructions(
+        module.chunk(0),
+        vec![
+            Class(0),
+            DefineGlobal(0),
+            GetGlobal(1),
+            GetGlobal(0),
+            Inherit,
+            Pop,
+            Nil,
+            Return,
+        ],
+    );
+
+    assert_constants(
+        &module,
+        vec![make_class("Foo")],
+    );
+    assert_globals(&module, vec!["Foo", "Bar"]);
+}
+
+#[test]
+fn test_super_call() {
+    use crate::bytecode::Instruction::*;
+
+    let module = compile_code("class Foo < Bar { baz() { super.baz(); } }");
+
+    assert_instructions(
+        module.chunk(0),
+        vec![
+            Class(0),
+            DefineGlobal(0),
+            GetGlobal(1),
+            GetGlobal(0),
+            Inherit,
+            GetGlobal(0),
+            Closure(2),
+            Method(1),
+            Pop,
+            CloseUpvalue,
+            Nil,
+            Return,
+        ],
+    );
+    assert_instructions(
+        module.chunk(1),
+        vec![GetLocal(0), GetUpvalue(0), GetSuper(1), Call(0), Pop, Nil, Return],
+    );
+}
+
+#[test]
+fn test_super_errors() {
+    use super::{compile, CompilerError};
+    use lox_syntax::position::Span;
+
+    let ast = parse_stmt("class Foo < Foo {}").unwrap();
+    assert!(matches!(
+        compile(&ast, OptimizationLevel::None),
+        Err(CompilerError::Multiple(ref errors)) if matches!(errors[..], [CompilerError::WithSpan(ref error)] if matches!(*error.value, CompilerError::ClassInheritsFromSelf))
+    ));
+
+    let diagnostics = |source: &str| {
+        let ast = parse_stmt(source).unwrap();
+        match compile(&ast, OptimizationLevel::None) {
+            Err(error) => error.diagnostics(),
+            Ok(_) => panic!("expected compile errors"),
+        }
+    };
+
+    let errors = diagnostics("class Foo { bar() { super.bar(); } }");
+    assert_eq!(errors[0].code, "E107");
+    assert_eq!(
+        errors[0].message,
+        "Can't use 'super' in a class with no superclass."
+    );
+    assert_eq!(errors[0].span, Some(unsafe { Span::new_unchecked(20, 25) }));
+
+    let errors = diagnostics("fun f() { super.bar(); }");
+    assert_eq!(errors[0].code, "E105");
+    assert_eq!(errors[0].span, Some(unsafe { Span::new_unchecked(10, 15) }));
+
+    // A class without a superclass inside a method of one that has one.
+    let errors =
+        diagnostics("class A { m() {} } class B < A { m() { class C { m() { super.m(); } } } }");
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].code, "E107");
+    assert_eq!(errors[0].span, Some(unsafe { Span::new_unchecked(55, 60) }));
+}
+
+#[test]
+fn test_instruction_spans() {
+    let module = compile_code("print 1 +\n f(2);");
+    let chunk = module.chunk(0);
+
+    assert_eq!(
+        chunk.instructions()[3..5],
+        [Instruction::Call(1), Instruction::Add]
+    );
+    assert_eq!(chunk.span(3), Span { start: 12, end: 15 });
+    assert_eq!(chunk.span(4), Span { start: 8, end: 9 });
+    assert_eq!(chunk.spans().len(), chunk.instructions().len());
+}
+
+#[test]
+fn test_error_diagnostics() {
+    use super::compile;
+    use lox_syntax::position::Span;
+
+    let ast = parse_stmt("print this; { var a = 1; var a = 2; } { var b = b; }").unwrap();
+    let diagnostics = match compile(&ast, OptimizationLevel::None) {
+        Err(error) => error.diagnostics(),
+        Ok(_) => panic!("expected compile errors"),
+    };
+    let codes: Vec<_> = diagnostics.iter().map(|d| d.code).collect();
+    assert_eq!(codes, vec!["E104", "E101", "E102"]);
+
+    assert_eq!(diagnostics[0].span, Some(unsafe { Span::new_unchecked(6, 10) }));
+    assert_eq!(diagnostics[0].message, "Can't use 'this' outside of a class.");
+    assert_eq!(diagnostics[1].span, Some(unsafe { Span::new_unchecked(29, 30) }));
+    assert_eq!(diagnostics[2].span, Some(unsafe { Span::new_unchecked(48, 49) }));
+}
+
+#[test]
+fn test_render_class_errors() {
+    use super::compile;
+    use lox_syntax::diagnostics::Style;
+
+    let render = |source: &str| {
+        let ast = parse_stmt(source).unwrap();
+        match compile(&ast, OptimizationLevel::None) {
+            Err(error) => error.diagnostics()[0].render("test.lox", source, Style::Plain),
+            Ok(_) => panic!("expected compile errors"),
+        }
+    };
+
+    assert_eq!(
+        render("fun f() {\n  return this;\n}"),
+        "error[E104]: Can't use 'this' outside of a class.\n \
+         --> test.lox:2:10\n  \
+         |\n\
+         2 |   return this;\n  \
+         |          ^^^^\n"
+    );
+    assert_eq!(
+        render("class Foo {\n  init() { return 3; }\n}"),
+        "error[E103]: Can't return a value from an initializer.\n \
+         --> test.lox:2:12\n  \
+         |\n\
+         2 |   init() { return 3; }\n  \
+         |            ^^^^^^^^^\n"
+    );
+}
+
+#[test]
+fn test_append_modules() {
+    use crate::bytecode::Instruction::*;
+
+    let mut module = compile_code("var a = 1;");
+    let mut other = compile_code("fun f() { return a; } print f();");
+    other.shift_spans(11, 0);
+    let chunk = module.append(other);
+
+    assert_eq!(chunk, 1);
+    assert_instructions(
+        module.chunk(1),
+        vec![
+            Closure(1),
+            DefineGlobal(1),
+            GetGlobal(1),
+            Call(0),
+            Print,
+            Nil,
+            Return,
+        ],
+    );
+    assert_instructions(module.chunk(2), vec![GetGlobal(0), Return, Nil, Return]);
+    assert_constants(&module, vec![1.0.into(), make_fun("f", 2, 0)]);
+    // Both modules refer to `a`, it keeps the slot it got in the first one.
+    assert_globals(&module, vec!["a", "f"]);
+    assert_eq!(module.chunk(2).span(0), Span { start: 28, end: 29 });
+}
+
+#[test]
+fn test_compile_interactive() {
+    use crate::bytecode::Instruction::*;
+    use crate::compile_interactive;
+
+    let module = compile_interactive("1 + 2").unwrap();
+    assert_instructions(module.chunk(0), vec![Constant(0), Print, Nil, Return]);
+    assert_constants(&module, vec![3.0.into()]);
+
+    let module = compile_interactive("var a = 1").unwrap();
+    assert_instructions(module.chunk(0), vec![Constant(0), DefineGlobal(0), Nil, Return]);
+
+    match compile_interactive("{ var a = 1;") {
+        Err(crate::Error::ParseError(errors)) => assert!(errors.iter().all(|e| e.is_incomplete())),
+        _ => panic!("expected incomplete input"),
+    }
+    match compile_interactive("var = 1") {
+        Err(crate::Error::ParseError(errors)) => assert!(!errors[0].is_incomplete()),
+        _ => panic!("expected a syntax error"),
+    }
+}
+
+#[test]
+fn test_compiled_modules_verify() {
+    use lox_bytecode::verifier::verify;
+    let test_lox = concat!(env!("CARGO_MANIFEST_DIR"), "/../test.lox");
+    let programs = [
+        "var a = 1; { var b = a and 2 or 3; print b; }",
+        "for (var i = 0; i < 3; i = i + 1) { if (i == 1) print i; else print -i; }",
+        "var a = 1; if (a) print a; print a;",
+        "fun outer(a, b) { var c = a; fun inner() { c = c + b; return c; } return inner; }",
+        "class A { init(x) { this.x = x; } get() { return this.x; } }
+         class B < A { get() { return super.get() * 2; } }
+         print B(1).get();",
+        &std::fs::read_to_string(test_lox).unwrap(),
+    ];
+    for program in programs.iter() {
+        let ast = parse_stmt(program).unwrap();
+        for &level in &[OptimizationLevel::None, OptimizationLevel::Basic] {
+            let module = super::compile(&ast, level).unwrap();
+            assert_eq!(verify(&module), Ok(()), "{}", program);
+        }
+    }
+}
+
+#[test]
+fn test_constants_are_deduplicated() {
+    use crate::bytecode::Instruction::*;
+
+    let module = compile_code(
+        "var count = 0;
+         fun increment(by) { count = count + by; return count; }
+         while (count < 10) { print \"count\"; print increment(1); }
+         print \"count\";",
+    );
+    assert_constants(
+        &module,
+        vec![
+            0.0.into(),
+            make_fun("increment", 1, 1),
+            10.0.into(),
+            "count".into(),
+            1.0.into(),
+        ],
+    );
+    assert_globals(&module, vec!["count", "increment"]);
+    assert_instructions(
+        module.chunk(1),
+        vec![
+            GetGlobal(0),
+            GetLocal(1),
+            Add,
+            SetGlobal(0),
+            Pop,
+            GetGlobal(0),
+            Return,
+            Nil,
+            Return,
+        ],
+    );
+
+    let module = compile_code(&"print \"a\" + \"b\";\n".repeat(1000));
+    assert_constants(&module, vec!["a".into(), "b".into()]);
+}
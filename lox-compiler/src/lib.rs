@@ -1,22 +1,84 @@
 mod bettercompiler;
+mod optimizer;
 
 use lox_bytecode::bytecode;
 
 //TODO Better errors
 
 pub use crate::bettercompiler::CompilerError;
+pub use crate::optimizer::OptimizationLevel;
 pub use lox_syntax::SyntaxError;
 
 #[derive(Debug)]
 pub enum Error {
     CompileError(CompilerError),
-    ParseError(SyntaxError),
+    ParseError(Vec<SyntaxError>),
+}
+
+impl Error {
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        match self {
+            Error::CompileError(error) => error.diagnostics(),
+            Error::ParseError(errors) => errors.iter().map(Diagnostic::from).collect(),
+        }
+    }
 }
 
 use bytecode::Module;
+use lox_syntax::ast::{Ast, Stmt};
+use lox_syntax::diagnostics::Diagnostic;
+use lox_syntax::position::LineOffsets;
 pub fn compile(code: &str) -> Result<Module, Error> {
-    let ast = lox_syntax::parse(code).map_err(|e| Error::ParseError(e))?;
-    let module = bettercompiler::compile(&ast).map_err(|e| Error::CompileError(e))?;
+    compile_with(code, OptimizationLevel::default())
+}
+
+/// Compile with the given optimization level, see [`OptimizationLevel`].
+pub fn compile_with(code: &str, level: OptimizationLevel) -> Result<Module, Error> {
+    let (ast, errors) = lox_syntax::parse(code);
+    if !errors.is_empty() {
+        return Err(Error::ParseError(errors));
+    }
+    compile_ast(code, ast, level)
+}
+
+/// Compile input entered in an interactive session.
+/// The final `;` may be left out, and a single expression is printed instead of discarded.
+pub fn compile_interactive(code: &str) -> Result<Module, Error> {
+    let (mut ast, errors) = lox_syntax::parse(code);
+    if !errors.is_empty() {
+        // The `;` goes on its own line so it can't end up in a trailing comment.
+        let (terminated_ast, terminated_errors) = lox_syntax::parse(&format!("{}\n;", code));
+        if !terminated_errors.is_empty() {
+            return Err(Error::ParseError(errors));
+        }
+        ast = terminated_ast;
+    }
+
+    if let [Stmt::Expression(_)] = ast[..] {
+        if let Some(Stmt::Expression(expr)) = ast.pop() {
+            ast.push(Stmt::Print(expr));
+        }
+    }
+
+    compile_ast(code, ast, OptimizationLevel::default())
+}
+
+fn compile_ast(code: &str, ast: Ast, level: OptimizationLevel) -> Result<Module, Error> {
+    let ast = match level {
+        OptimizationLevel::None => ast,
+        OptimizationLevel::Basic => {
+            let (optimized, removed_code) = optimizer::optimize(ast.clone());
+            if removed_code {
+                // Code that never runs should still report its errors.
+                bettercompiler::compile(&ast, OptimizationLevel::None)
+                    .map_err(Error::CompileError)?;
+            }
+            optimized
+        }
+    };
+
+    let mut module = bettercompiler::compile(&ast, level).map_err(Error::CompileError)?;
+    module.set_lines(LineOffsets::new(code).offsets().to_vec());
 
     Ok(module)
 }
@@ -0,0 +1,322 @@
+//! Rewrites of the AST that don't change what a program does, done before compiling it.
+//!
+//! Operators on literals are evaluated exactly like the VM would, so `0 / 0` still becomes
+//! NaN and `1 + "a"` is left alone to fail at runtime. Branches and loops whose condition
+//! is a literal are reduced to the code that can actually run.
+
+use lox_syntax::ast::*;
+use lox_syntax::position::WithSpan;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum OptimizationLevel {
+    /// Compile the program as it was written.
+    None,
+    /// Fold constant expressions, remove code that can never run and clean up the bytecode
+    /// with `lox_bytecode::peephole`.
+    #[default]
+    Basic,
+}
+
+/// Optimize `ast`, also returns whether any code was removed.
+pub fn optimize(ast: Ast) -> (Ast, bool) {
+    let mut optimizer = Optimizer {
+        removed_code: false,
+    };
+    let ast = optimizer.stmts(ast);
+    (ast, optimizer.removed_code)
+}
+
+struct Optimizer {
+    removed_code: bool,
+}
+
+impl Optimizer {
+    fn stmts(&mut self, stmts: Vec<Stmt>) -> Vec<Stmt> {
+        stmts
+            .into_iter()
+            .filter_map(|stmt| self.stmt(stmt))
+            .collect()
+    }
+
+    // A statement that has to stay, like the body of an `if`.
+    fn branch(&mut self, stmt: Stmt) -> Stmt {
+        self.stmt(stmt).unwrap_or_else(|| Stmt::Block(vec![]))
+    }
+
+    fn stmt(&mut self, stmt: Stmt) -> Option<Stmt> {
+        let stmt = match stmt {
+            Stmt::Expression(expr) => Stmt::Expression(self.boxed(expr)),
+            Stmt::Print(expr) => Stmt::Print(self.boxed(expr)),
+            Stmt::Var(identifier, expr) => Stmt::Var(identifier, expr.map(|e| self.boxed(e))),
+            Stmt::Return(span, expr) => Stmt::Return(span, expr.map(|e| self.boxed(e))),
+            Stmt::Block(stmts) => Stmt::Block(self.stmts(stmts)),
+            Stmt::If(condition, then_stmt, else_stmt) => {
+                let condition = self.expr(*condition);
+                match truthiness(&condition) {
+                    Some(true) => {
+                        self.removed_code |= else_stmt.is_some();
+                        return self.stmt(*then_stmt);
+                    }
+                    Some(false) => {
+                        self.removed_code = true;
+                        return else_stmt.and_then(|stmt| self.stmt(*stmt));
+                    }
+                    None => Stmt::If(
+                        Box::new(condition),
+                        Box::new(self.branch(*then_stmt)),
+                        else_stmt.map(|stmt| Box::new(self.branch(*stmt))),
+                    ),
+                }
+            }
+            Stmt::While(condition, body) => {
+                let condition = self.expr(*condition);
+                if truthiness(&condition) == Some(false) {
+                    self.removed_code = true;
+                    return None;
+                }
+                Stmt::While(Box::new(condition), Box::new(self.branch(*body)))
+            }
+            Stmt::Function(identifier, parameters, body) => {
+                Stmt::Function(identifier, parameters, self.stmts(body))
+            }
+            Stmt::Class(identifier, superclass, methods) => {
+                Stmt::Class(identifier, superclass, self.stmts(methods))
+            }
+        };
+        Some(stmt)
+    }
+
+    fn boxed(&mut self, mut expr: Box<Expr>) -> Box<Expr> {
+        *expr = self.expr(std::mem::replace(&mut *expr, Expr::Nil));
+        expr
+    }
+
+    fn expr(&mut self, expr: Expr) -> Expr {
+        match expr {
+            Expr::Binary(left, operator, right) => {
+                let left = self.expr(*left);
+                let right = self.expr(*right);
+                match fold_binary(&left, operator.value, &right) {
+                    Some(folded) => folded,
+                    None => Expr::Binary(Box::new(left), operator, Box::new(right)),
+                }
+            }
+            Expr::Unary(operator, operand) => {
+                let operand = self.expr(*operand);
+                match (operator.value, &operand) {
+                    (UnaryOperator::Minus, Expr::Number(number)) => Expr::Number(-number),
+                    (UnaryOperator::Bang, operand) if is_literal(operand) => {
+                        Expr::Boolean(truthiness(operand) == Some(false))
+                    }
+                    _ => Expr::Unary(operator, Box::new(operand)),
+                }
+            }
+            Expr::Grouping(expr) => {
+                let expr = self.expr(*expr);
+                if is_literal(&expr) {
+                    expr
+                } else {
+                    Expr::Grouping(Box::new(expr))
+                }
+            }
+            Expr::Logical(left, operator, right) => {
+                let left = self.expr(*left);
+                let right = self.expr(*right);
+                // `and` results in its left operand if that is falsey, `or` if it is truthy.
+                match truthiness(&left) {
+                    Some(truthy) if truthy == (operator.value == LogicalOperator::Or) => {
+                        self.removed_code = true;
+                        left
+                    }
+                    Some(_) => right,
+                    None => Expr::Logical(Box::new(left), operator, Box::new(right)),
+                }
+            }
+            Expr::Assign(identifier, value) => Expr::Assign(identifier, self.boxed(value)),
+            Expr::Call(callee, arguments) => {
+                let callee = self.boxed(callee);
+                let span = arguments.span;
+                let arguments = arguments
+                    .value
+                    .into_iter()
+                    .map(|argument| self.expr(argument))
+                    .collect();
+                Expr::Call(callee, WithSpan::new(arguments, span))
+            }
+            Expr::Get(object, property) => Expr::Get(self.boxed(object), property),
+            Expr::Set(object, property, value) => {
+                Expr::Set(self.boxed(object), property, self.boxed(value))
+            }
+            expr => expr,
+        }
+    }
+}
+
+fn is_literal(expr: &Expr) -> bool {
+    truthiness(expr).is_some()
+}
+
+// Whether a literal is truthy, `None` for anything that isn't a literal.
+fn truthiness(expr: &Expr) -> Option<bool> {
+    match expr {
+        Expr::Boolean(boolean) => Some(*boolean),
+        Expr::Nil => Some(false),
+        Expr::Number(_) | Expr::String(_) => Some(true),
+        _ => None,
+    }
+}
+
+fn fold_binary(left: &Expr, operator: BinaryOperator, right: &Expr) -> Option<Expr> {
+    use BinaryOperator::*;
+
+    let folded = match (operator, left, right) {
+        (EqualEqual, _, _) => Expr::Boolean(literals_equal(left, right)?),
+        (BangEqual, _, _) => Expr::Boolean(!literals_equal(left, right)?),
+        (Plus, Expr::String(a), Expr::String(b)) => Expr::String(format!("{}{}", a, b)),
+        (_, Expr::Number(a), Expr::Number(b)) => {
+            let (a, b) = (*a, *b);
+            match operator {
+                Plus => Expr::Number(a + b),
+                Minus => Expr::Number(a - b),
+                Star => Expr::Number(a * b),
+                Slash => Expr::Number(a / b),
+                Greater => Expr::Boolean(a > b),
+                Less => Expr::Boolean(a < b),
+                // These are compiled as the negation of the opposite comparison,
+                // so they are true when either side is NaN.
+                GreaterEqual => Expr::Boolean(a >= b || a.is_nan() || b.is_nan()),
+                LessEqual => Expr::Boolean(a <= b || a.is_nan() || b.is_nan()),
+                EqualEqual | BangEqual => unreachable!(),
+            }
+        }
+        _ => return None,
+    };
+    Some(folded)
+}
+
+// Compare literals the way the VM compares values, `None` if either isn't a literal.
+fn literals_equal(left: &Expr, right: &Expr) -> Option<bool> {
+    match (left, right) {
+        (Expr::Number(a), Expr::Number(b)) => Some(a == b),
+        (Expr::String(a), Expr::String(b)) => Some(a == b),
+        (Expr::Boolean(a), Expr::Boolean(b)) => Some(a == b),
+        (Expr::Nil, Expr::Nil) => Some(true),
+        _ if is_literal(left) && is_literal(right) => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn optimize_code(code: &str) -> (Ast, bool) {
+        let (ast, errors) = lox_syntax::parse(code);
+        assert!(errors.is_empty(), "{:?}", errors);
+        optimize(ast)
+    }
+
+    fn assert_expr(code: &str, expected: Expr) {
+        match &optimize_code(&format!("print {};", code)).0[..] {
+            [Stmt::Print(expr)] => assert_eq!(**expr, expected, "{}", code),
+            ast => panic!("Unexpected ast {:?}", ast),
+        }
+    }
+
+    fn assert_unchanged(code: &str) {
+        let (ast, _) = lox_syntax::parse(code);
+        assert_eq!(optimize_code(code), (ast, false), "{}", code);
+    }
+
+    fn assert_number(code: &str, check: impl Fn(f64) -> bool) {
+        match optimize_code(&format!("print {};", code)).0[..] {
+            [Stmt::Print(ref expr)] => match **expr {
+                Expr::Number(number) => assert!(check(number), "{} is {}", code, number),
+                ref expr => panic!("{} is {:?}", code, expr),
+            },
+            ref ast => panic!("Unexpected ast {:?}", ast),
+        }
+    }
+
+    #[test]
+    fn test_fold_arithmetic() {
+        assert_expr("1 + 2 * 3", Expr::Number(7.0));
+        assert_expr("(1 + 2) * 3", Expr::Number(9.0));
+        assert_expr("-(4 - 6) / 4", Expr::Number(0.5));
+        assert_expr("\"a\" + \"b\" + \"c\"", Expr::String("abc".into()));
+        assert_number("1 / 0", |number| number == f64::INFINITY);
+        assert_number("-1 / 0", |number| number == f64::NEG_INFINITY);
+        assert_number("0 / 0", f64::is_nan);
+    }
+
+    #[test]
+    fn test_fold_comparisons() {
+        assert_expr("1 < 2", Expr::Boolean(true));
+        assert_expr("2 <= 1", Expr::Boolean(false));
+        assert_expr("1 == 1", Expr::Boolean(true));
+        assert_expr("\"a\" != \"a\"", Expr::Boolean(false));
+        assert_expr("nil == false", Expr::Boolean(false));
+        assert_expr("1 == \"1\"", Expr::Boolean(false));
+        assert_expr("0 / 0 == 0 / 0", Expr::Boolean(false));
+        assert_expr("0 / 0 < 1", Expr::Boolean(false));
+        assert_expr("0 / 0 > 1", Expr::Boolean(false));
+        assert_expr("0 / 0 <= 1", Expr::Boolean(true));
+        assert_expr("0 / 0 >= 1", Expr::Boolean(true));
+    }
+
+    #[test]
+    fn test_fold_not() {
+        assert_expr("!true", Expr::Boolean(false));
+        assert_expr("!nil", Expr::Boolean(true));
+        assert_expr("!!0", Expr::Boolean(true));
+        assert_expr("!(1 > 2)", Expr::Boolean(true));
+    }
+
+    #[test]
+    fn test_type_errors_are_kept() {
+        assert_unchanged("print 1 + \"a\";");
+        assert_unchanged("print -\"a\";");
+        assert_unchanged("print \"a\" < \"b\";");
+        assert_unchanged("print nil * 2;");
+    }
+
+    #[test]
+    fn test_logical() {
+        assert_expr("false and a", Expr::Boolean(false));
+        assert_expr("nil or 2", Expr::Number(2.0));
+        assert_expr("1 or a", Expr::Number(1.0));
+        assert_expr("true and 1 + 1", Expr::Number(2.0));
+        assert_unchanged("print a and false;");
+        assert!(optimize_code("print 1 or a;").1);
+        assert!(!optimize_code("print nil or a;").1);
+    }
+
+    #[test]
+    fn test_dead_code() {
+        let (ast, removed_code) = optimize_code("if (false) print 1; while (nil) print 2;");
+        assert_eq!(ast, vec![]);
+        assert!(removed_code);
+
+        let (ast, removed_code) = optimize_code("if (1 < 2) print 1; else print 2;");
+        assert_eq!(ast, vec![Stmt::Print(Box::new(Expr::Number(1.0)))]);
+        assert!(removed_code);
+
+        let (ast, removed_code) = optimize_code("if (!true) print 1; else print 2;");
+        assert_eq!(ast, vec![Stmt::Print(Box::new(Expr::Number(2.0)))]);
+        assert!(removed_code);
+
+        let (ast, _) = optimize_code("fun f() { if (a) { if (false) print 1; } }");
+        match &ast[..] {
+            [Stmt::Function(_, _, body)] => match &body[..] {
+                [Stmt::If(_, then_stmt, None)] => {
+                    assert_eq!(**then_stmt, Stmt::Block(vec![]))
+                }
+                body => panic!("Unexpected body {:?}", body),
+            },
+            ast => panic!("Unexpected ast {:?}", ast),
+        }
+
+        assert_unchanged("while (true) print 1;");
+        assert_unchanged("if (a) print 1; else print 2;");
+    }
+}
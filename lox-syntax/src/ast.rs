@@ -1,4 +1,4 @@
-use crate::position::WithSpan;
+use crate::position::{Span, WithSpan};
 
 pub type Identifier = String;
 
@@ -35,14 +35,15 @@ pub enum Expr {
     Number(f64),
     Boolean(bool),
     Nil,
-    This,
-    Super(WithSpan<Identifier>),
+    This(Span),
+    /// The span of the `super` keyword, and the method.
+    Super(Span, WithSpan<Identifier>),
     String(String),
     Unary(WithSpan<UnaryOperator>, Box<Expr>),
     Variable(WithSpan<Identifier>),
     Logical(Box<Expr>, WithSpan<LogicalOperator>, Box<Expr>),
     Assign(WithSpan<Identifier>, Box<Expr>),
-    Call(Box<Expr>, Vec<Expr>),
+    Call(Box<Expr>, WithSpan<Vec<Expr>>),
     Get(Box<Expr>, WithSpan<Identifier>),
     Set(Box<Expr>, WithSpan<Identifier>, Box<Expr>),
 }
@@ -55,7 +56,8 @@ pub enum Stmt {
     If(Box<Expr>, Box<Stmt>, Option<Box<Stmt>>),
     Block(Vec<Stmt>),
     While(Box<Expr>, Box<Stmt>),
-    Return(Option<Box<Expr>>),
+    /// The span of the whole statement, from `return` to the `;`.
+    Return(Span, Option<Box<Expr>>),
     Function(WithSpan<Identifier>, Vec<WithSpan<Identifier>>, Vec<Stmt>),
     Class(
         WithSpan<Identifier>,
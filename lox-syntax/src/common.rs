@@ -5,9 +5,12 @@ use crate::token::{Token, TokenKind};
 use crate::SyntaxError;
 
 pub fn expect_identifier(p: &mut Parser) -> Result<WithSpan<Identifier>, SyntaxError> {
-    let token = p.advance();
+    let token = p.peek_token();
     match &token.value {
-        Token::Identifier(ident) => Ok(WithSpan::new(ident.clone(), token.span)),
+        Token::Identifier(ident) => {
+            p.advance();
+            Ok(WithSpan::new(ident.clone(), token.span))
+        }
         _ => Err(SyntaxError::Expected(TokenKind::Identifier, token.clone())),
     }
 }
@@ -0,0 +1,380 @@
+use crate::position::{BytePos, LineOffsets, Span};
+use crate::token::{Token, TokenKind};
+use crate::SyntaxError;
+use std::fmt;
+
+/// How a `Diagnostic` is rendered, `Colored` uses ANSI escape codes for terminals.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Style {
+    Plain,
+    Colored,
+}
+
+/// A 1-based line and column in the source, columns count characters, not bytes.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// An error message with an error code, pointing at a span of the source.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub code: &'static str,
+    pub message: String,
+    pub span: Option<Span>,
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn new<S: Into<String>>(code: &'static str, message: S) -> Self {
+        Diagnostic {
+            code,
+            message: message.into(),
+            span: None,
+            notes: vec![],
+        }
+    }
+
+    pub fn with_span(mut self, span: Span) -> Self {
+        self.span = Some(span);
+        self
+    }
+
+    pub fn with_note<S: Into<String>>(mut self, note: S) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    /// Render the diagnostic with the offending line of `source` and a marker under the span.
+    /// `name` is used to refer to the source, usually a file name.
+    pub fn render(&self, name: &str, source: &str, style: Style) -> String {
+        let paint = Painter(style);
+        let mut out = String::new();
+
+        out.push_str(&paint.error(&format!("error[{}]", self.code)));
+        out.push_str(&paint.bold(&format!(": {}", self.message)));
+        out.push('\n');
+
+        let mut gutter = 0;
+        if let Some(span) = self.span {
+            let offsets = LineOffsets::new(source);
+            let location = location(&offsets, source, span.start);
+            let number = location.line.to_string();
+            gutter = number.len();
+
+            let (line_start, line_end) = line_bounds(&offsets, source, location.line);
+            let start = clamp(source, span.start.0 as usize).max(line_start);
+            let end = clamp(source, span.end.0 as usize).min(line_end).max(start);
+            let line = &source[line_start..line_end];
+
+            // Keep tabs so the marker lines up with the source line.
+            let padding: String = source[line_start..start]
+                .chars()
+                .map(|ch| if ch == '\t' { '\t' } else { ' ' })
+                .collect();
+            let width = source[start..end].chars().count().max(1);
+
+            out.push_str(&format!(
+                "{}{} {}:{}:{}\n",
+                " ".repeat(gutter),
+                paint.gutter("-->"),
+                name,
+                location.line,
+                location.column
+            ));
+            out.push_str(&format!("{} {}\n", " ".repeat(gutter), paint.gutter("|")));
+            out.push_str(&format!(
+                "{} {} {}\n",
+                paint.gutter(&number),
+                paint.gutter("|"),
+                line
+            ));
+            out.push_str(&format!(
+                "{} {} {}{}\n",
+                " ".repeat(gutter),
+                paint.gutter("|"),
+                padding,
+                paint.error(&"^".repeat(width))
+            ));
+        }
+
+        for note in &self.notes {
+            out.push_str(&format!(
+                "{} {} {}\n",
+                " ".repeat(gutter),
+                paint.gutter("="),
+                note
+            ));
+        }
+
+        out
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "error[{}]: {}", self.code, self.message)
+    }
+}
+
+/// Turn a byte offset into a line and column, counting characters like `BytePos::shift`.
+pub fn location(offsets: &LineOffsets, source: &str, pos: BytePos) -> Location {
+    let line = offsets.line(pos).max(1);
+    let (line_start, _) = line_bounds(offsets, source, line);
+    let pos = clamp(source, pos.0 as usize).max(line_start);
+    Location {
+        line,
+        column: source[line_start..pos].chars().count() + 1,
+    }
+}
+
+// Byte range of a 1-based line, without the line ending.
+fn line_bounds(offsets: &LineOffsets, source: &str, line: usize) -> (usize, usize) {
+    let offsets = offsets.offsets();
+    let start = clamp(source, offsets[line - 1] as usize);
+    let end = match offsets.get(line) {
+        Some(next) => clamp(source, *next as usize - 1),
+        None => source.len(),
+    };
+    let end = if source[start..end].ends_with('\r') {
+        end - 1
+    } else {
+        end
+    };
+    (start, end)
+}
+
+// Clamp a byte offset to the source and to the start of the character it falls in.
+fn clamp(source: &str, mut pos: usize) -> usize {
+    pos = pos.min(source.len());
+    while !source.is_char_boundary(pos) {
+        pos -= 1;
+    }
+    pos
+}
+
+struct Painter(Style);
+
+impl Painter {
+    fn paint(&self, code: &str, text: &str) -> String {
+        match self.0 {
+            Style::Plain => text.to_string(),
+            Style::Colored => format!("\x1b[{}m{}\x1b[0m", code, text),
+        }
+    }
+
+    fn error(&self, text: &str) -> String {
+        self.paint("1;31", text)
+    }
+
+    fn bold(&self, text: &str) -> String {
+        self.paint("1", text)
+    }
+
+    fn gutter(&self, text: &str) -> String {
+        self.paint("1;34", text)
+    }
+}
+
+impl fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            TokenKind::LeftParen => "'('",
+            TokenKind::RightParen => "')'",
+            TokenKind::LeftBrace => "'{'",
+            TokenKind::RightBrace => "'}'",
+            TokenKind::Comma => "','",
+            TokenKind::Dot => "'.'",
+            TokenKind::Minus => "'-'",
+            TokenKind::Plus => "'+'",
+            TokenKind::Semicolon => "';'",
+            TokenKind::Slash => "'/'",
+            TokenKind::Star => "'*'",
+            TokenKind::Bang => "'!'",
+            TokenKind::BangEqual => "'!='",
+            TokenKind::Equal => "'='",
+            TokenKind::EqualEqual => "'=='",
+            TokenKind::Greater => "'>'",
+            TokenKind::GreaterEqual => "'>='",
+            TokenKind::Less => "'<'",
+            TokenKind::LessEqual => "'<='",
+            TokenKind::Identifier => "identifier",
+            TokenKind::String => "string",
+            TokenKind::Number => "number",
+            TokenKind::And => "'and'",
+            TokenKind::Class => "'class'",
+            TokenKind::Else => "'else'",
+            TokenKind::False => "'false'",
+            TokenKind::Fun => "'fun'",
+            TokenKind::For => "'for'",
+            TokenKind::If => "'if'",
+            TokenKind::Nil => "'nil'",
+            TokenKind::Or => "'or'",
+            TokenKind::Print => "'print'",
+            TokenKind::Return => "'return'",
+            TokenKind::Super => "'super'",
+            TokenKind::This => "'this'",
+            TokenKind::True => "'true'",
+            TokenKind::Var => "'var'",
+            TokenKind::While => "'while'",
+            TokenKind::Eof => "end of file",
+            TokenKind::UnterminatedString => "unterminated string",
+            TokenKind::Unknown => "unknown character",
+        };
+        write!(f, "{}", text)
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Identifier(name) => write!(f, "identifier '{}'", name),
+            Token::String(string) => write!(f, "string \"{}\"", string),
+            Token::Number(number) => write!(f, "number {}", number),
+            Token::Unknown(ch) => write!(f, "unknown character '{}'", ch),
+            token => write!(f, "{}", TokenKind::from(token)),
+        }
+    }
+}
+
+impl SyntaxError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            SyntaxError::Expected(_, _) => "E001",
+            SyntaxError::Unexpected(_) => "E002",
+            SyntaxError::ExpectedUnaryOperator(_) => "E003",
+            SyntaxError::ExpectedBinaryOperator(_) => "E004",
+            SyntaxError::ExpectedPrimary(_) => "E005",
+            SyntaxError::InvalidLeftValue(_) => "E006",
+        }
+    }
+
+    pub fn span(&self) -> Span {
+        match self {
+            SyntaxError::Expected(_, token)
+            | SyntaxError::Unexpected(token)
+            | SyntaxError::ExpectedUnaryOperator(token)
+            | SyntaxError::ExpectedBinaryOperator(token)
+            | SyntaxError::ExpectedPrimary(token) => token.span,
+            SyntaxError::InvalidLeftValue(expr) => expr.span,
+        }
+    }
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SyntaxError::Expected(expected, found) => {
+                write!(f, "Expected {} but found {}.", expected, found.value)
+            }
+            SyntaxError::Unexpected(found) => write!(f, "Unexpected {}.", found.value),
+            SyntaxError::ExpectedUnaryOperator(found) => {
+                write!(f, "Expected unary operator but found {}.", found.value)
+            }
+            SyntaxError::ExpectedBinaryOperator(found) => {
+                write!(f, "Expected binary operator but found {}.", found.value)
+            }
+            SyntaxError::ExpectedPrimary(found) => {
+                write!(f, "Expected expression but found {}.", found.value)
+            }
+            SyntaxError::InvalidLeftValue(_) => write!(f, "Invalid assignment target."),
+        }
+    }
+}
+
+impl From<&SyntaxError> for Diagnostic {
+    fn from(error: &SyntaxError) -> Self {
+        Diagnostic::new(error.code(), error.to_string()).with_span(error.span())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(start: u32, end: u32) -> Span {
+        unsafe { Span::new_unchecked(start, end) }
+    }
+
+    #[test]
+    fn test_location() {
+        let source = "var a;\nvar éé = \"ü\";\n";
+        let offsets = LineOffsets::new(source);
+        assert_eq!(
+            location(&offsets, source, BytePos(0)),
+            Location { line: 1, column: 1 }
+        );
+        assert_eq!(
+            location(&offsets, source, BytePos(7)),
+            Location { line: 2, column: 1 }
+        );
+        // 'é' is two bytes but one column.
+        assert_eq!(
+            location(&offsets, source, BytePos(15)),
+            Location { line: 2, column: 7 }
+        );
+        assert_eq!(
+            location(&offsets, source, BytePos(21)),
+            Location {
+                line: 2,
+                column: 12
+            }
+        );
+        // Offsets inside a character are moved to its start.
+        assert_eq!(
+            location(&offsets, source, BytePos(20)),
+            Location {
+                line: 2,
+                column: 11
+            }
+        );
+    }
+
+    #[test]
+    fn test_render_plain() {
+        let source = "print 1;\nprint éé + ;\n";
+        let diagnostic = Diagnostic::new("E005", "Expected expression but found ';'.")
+            .with_span(span(22, 23))
+            .with_note("while parsing");
+        assert_eq!(
+            diagnostic.render("test.lox", source, Style::Plain),
+            "error[E005]: Expected expression but found ';'.\n \
+             --> test.lox:2:12\n  \
+             |\n\
+             2 | print éé + ;\n  \
+             |            ^\n  \
+             = while parsing\n"
+        );
+    }
+
+    #[test]
+    fn test_render_underline() {
+        let source = "\tfoo bar;";
+        let diagnostic =
+            Diagnostic::new("E002", "Unexpected identifier 'bar'.").with_span(span(5, 8));
+        let rendered = diagnostic.render("test.lox", source, Style::Plain);
+        assert!(rendered.ends_with("1 | \tfoo bar;\n  | \t    ^^^\n"));
+
+        let colored = diagnostic.render("test.lox", source, Style::Colored);
+        assert!(colored.starts_with("\x1b[1;31merror[E002]\x1b[0m"));
+    }
+
+    #[test]
+    fn test_render_without_span() {
+        let diagnostic = Diagnostic::new("E104", "Can't use 'this' outside of a class.");
+        assert_eq!(
+            diagnostic.render("test.lox", "print this;", Style::Plain),
+            "error[E104]: Can't use 'this' outside of a class.\n"
+        );
+    }
+
+    #[test]
+    fn test_syntax_error_diagnostic() {
+        let (_, errors) = crate::parse("print 1");
+        let diagnostic = Diagnostic::from(&errors[0]);
+        assert_eq!(diagnostic.code, "E001");
+        assert_eq!(diagnostic.message, "Expected ';' but found end of file.");
+        assert_eq!(diagnostic.span, Some(span(7, 7)));
+    }
+}
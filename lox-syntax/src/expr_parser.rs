@@ -21,7 +21,7 @@ enum Precedence {
     Primary,
 }
 
-impl<'a> From<TokenKind> for Precedence {
+impl From<TokenKind> for Precedence {
     fn from(token: TokenKind) -> Precedence {
         match token {
             TokenKind::Equal => Precedence::Assign,
@@ -94,16 +94,18 @@ fn parse_get(it: &mut Parser, left: Expr) -> Result<Expr, SyntaxError> {
     it.expect(TokenKind::Dot)?;
     let tc = it.advance();
     match &tc.value {
-        &Token::Identifier(ref i) => Ok(Expr::Get(Box::new(left), WithSpan::new(i.clone(), tc.span))),
+        Token::Identifier(i) => Ok(Expr::Get(Box::new(left), WithSpan::new(i.clone(), tc.span))),
         _ => Err(SyntaxError::Expected(TokenKind::Identifier, tc.clone())),
     }
 }
 
 fn parse_call(it: &mut Parser, left: Expr) -> Result<Expr, SyntaxError> {
-    it.expect(TokenKind::LeftParen)?;
+    let left_paren = it.expect(TokenKind::LeftParen)?;
     let args = parse_arguments(it)?;
-    it.expect(TokenKind::RightParen)?;
-    Ok(Expr::Call(Box::new(left), args))
+    let right_paren = it.expect(TokenKind::RightParen)?;
+
+    let span = Span::union(left_paren.span, right_paren.span);
+    Ok(Expr::Call(Box::new(left), WithSpan::new(args, span)))
 }
 
 fn parse_arguments(it: &mut Parser) -> Result<Vec<Expr>, SyntaxError> {
@@ -159,9 +161,9 @@ fn parse_unary(it: &mut Parser) -> Result<Expr, SyntaxError> {
 
 fn parse_logical_op(it: &mut Parser) -> Result<WithSpan<LogicalOperator>, SyntaxError> {
     let tc = it.advance();
-    let operator = match &tc.value {
-        &Token::And => LogicalOperator::And,
-        &Token::Or => LogicalOperator::Or,
+    let operator = match tc.value {
+        Token::And => LogicalOperator::And,
+        Token::Or => LogicalOperator::Or,
         _ => return Err(SyntaxError::ExpectedUnaryOperator(tc.clone())),
     };
 
@@ -170,26 +172,26 @@ fn parse_logical_op(it: &mut Parser) -> Result<WithSpan<LogicalOperator>, Syntax
 
 fn parse_unary_op(it: &mut Parser) -> Result<WithSpan<UnaryOperator>, SyntaxError> {
     let tc = it.advance();
-    match &tc.value {
-        &Token::Bang => Ok(WithSpan::new(UnaryOperator::Bang, tc.span)),
-        &Token::Minus => Ok(WithSpan::new(UnaryOperator::Minus, tc.span)),
+    match tc.value {
+        Token::Bang => Ok(WithSpan::new(UnaryOperator::Bang, tc.span)),
+        Token::Minus => Ok(WithSpan::new(UnaryOperator::Minus, tc.span)),
         _ => Err(SyntaxError::ExpectedUnaryOperator(tc.clone())),
     }
 }
 
 fn parse_binary_op(it: &mut Parser) -> Result<WithSpan<BinaryOperator>, SyntaxError> {
     let tc = it.advance();
-    let operator = match &tc.value {
-        &Token::BangEqual => BinaryOperator::BangEqual,
-        &Token::EqualEqual => BinaryOperator::EqualEqual,
-        &Token::Less => BinaryOperator::Less,
-        &Token::LessEqual => BinaryOperator::LessEqual,
-        &Token::Greater => BinaryOperator::Greater,
-        &Token::GreaterEqual => BinaryOperator::GreaterEqual,
-        &Token::Plus => BinaryOperator::Plus,
-        &Token::Minus => BinaryOperator::Minus,
-        &Token::Star => BinaryOperator::Star,
-        &Token::Slash => BinaryOperator::Slash,
+    let operator = match tc.value {
+        Token::BangEqual => BinaryOperator::BangEqual,
+        Token::EqualEqual => BinaryOperator::EqualEqual,
+        Token::Less => BinaryOperator::Less,
+        Token::LessEqual => BinaryOperator::LessEqual,
+        Token::Greater => BinaryOperator::Greater,
+        Token::GreaterEqual => BinaryOperator::GreaterEqual,
+        Token::Plus => BinaryOperator::Plus,
+        Token::Minus => BinaryOperator::Minus,
+        Token::Star => BinaryOperator::Star,
+        Token::Slash => BinaryOperator::Slash,
         _ => return Err(SyntaxError::ExpectedBinaryOperator(tc.clone())),
     };
 
@@ -199,14 +201,14 @@ fn parse_binary_op(it: &mut Parser) -> Result<WithSpan<BinaryOperator>, SyntaxEr
 fn parse_primary(it: &mut Parser) -> Result<WithSpan<Expr>, SyntaxError> {
     let tc = it.advance();
     match &tc.value {
-        &Token::Nil => Ok(WithSpan::new(Expr::Nil, tc.span)),
-        &Token::This => Ok(WithSpan::new(Expr::This, tc.span)),
-        &Token::Number(n) => Ok(WithSpan::new(Expr::Number(n), tc.span)),
-        &Token::True => Ok(WithSpan::new(Expr::Boolean(true), tc.span)),
-        &Token::False => Ok(WithSpan::new(Expr::Boolean(false), tc.span)),
-        &Token::String(ref s) => Ok(WithSpan::new(Expr::String(s.clone()), tc.span)),
-        &Token::Identifier(ref s) => Ok(WithSpan::new(Expr::Variable(WithSpan::new(s.clone(), tc.span)), tc.span)),
-        &Token::Super => parse_super(it, &tc),
+        Token::Nil => Ok(WithSpan::new(Expr::Nil, tc.span)),
+        Token::This => Ok(WithSpan::new(Expr::This(tc.span), tc.span)),
+        Token::Number(n) => Ok(WithSpan::new(Expr::Number(*n), tc.span)),
+        Token::True => Ok(WithSpan::new(Expr::Boolean(true), tc.span)),
+        Token::False => Ok(WithSpan::new(Expr::Boolean(false), tc.span)),
+        Token::String(s) => Ok(WithSpan::new(Expr::String(s.clone()), tc.span)),
+        Token::Identifier(s) => Ok(WithSpan::new(Expr::Variable(WithSpan::new(s.clone(), tc.span)), tc.span)),
+        Token::Super => parse_super(it, tc),
         _ => Err(SyntaxError::ExpectedPrimary(tc.clone())),
     }
 }
@@ -215,7 +217,7 @@ fn parse_super(it: &mut Parser, keyword: &WithSpan<Token>) -> Result<WithSpan<Ex
     it.expect(TokenKind::Dot)?;
     let name = expect_identifier(it)?;
     let span = Span::union(keyword.span, name.span);
-    Ok(WithSpan::new(Expr::Super(name), span))
+    Ok(WithSpan::new(Expr::Super(keyword.span, name), span))
 }
 
 pub fn parse(it: &mut Parser) -> Result<Expr, SyntaxError> {
@@ -270,10 +272,13 @@ mod tests {
                 parse_str("test"),
                 Ok(Expr::Variable(WithSpan::new_unchecked("test".into(), 0, 4)))
             );
-            assert_eq!(parse_str("this"), Ok(Expr::This));
+            assert_eq!(parse_str("this"), Ok(Expr::This(Span::new_unchecked(0, 4))));
             assert_eq!(
                 parse_str("super.iets"),
-                Ok(Expr::Super(WithSpan::new_unchecked("iets".into(), 6, 10)))
+                Ok(Expr::Super(
+                    Span::new_unchecked(0, 5),
+                    WithSpan::new_unchecked("iets".into(), 6, 10)
+                ))
             );
         }
     }
@@ -487,7 +492,7 @@ mod tests {
                 parse_str("a()"),
                 Ok(Expr::Call(
                     Box::new(Expr::Variable(WithSpan::new_unchecked("a".into(), 0, 1))),
-                    vec![]
+                    wspn(vec![], 1, 3)
                 ))
             );
 
@@ -495,14 +500,14 @@ mod tests {
                 parse_str("a(3)"),
                 Ok(Expr::Call(
                     Box::new(Expr::Variable(WithSpan::new_unchecked("a".into(), 0, 1))),
-                    vec![Expr::Number(3.)]
+                    wspn(vec![Expr::Number(3.)], 1, 4)
                 ))
             );
             assert_eq!(
                 parse_str("a(3,4)"),
                 Ok(Expr::Call(
                     Box::new(Expr::Variable(WithSpan::new_unchecked("a".into(), 0, 1))),
-                    vec![Expr::Number(3.), Expr::Number(4.),]
+                    wspn(vec![Expr::Number(3.), Expr::Number(4.),], 1, 6)
                 ))
             );
 
@@ -512,7 +517,7 @@ mod tests {
                     wspn(UnaryOperator::Minus, 0, 1),
                     Box::new(Expr::Call(
                         Box::new(Expr::Variable(WithSpan::new_unchecked("a".into(), 1, 2))),
-                        vec![Expr::Number(3.)]
+                        wspn(vec![Expr::Number(3.)], 2, 5)
                     ))
                 ))
             );
@@ -522,12 +527,12 @@ mod tests {
                 Ok(Expr::Binary(
                     Box::new(Expr::Call(
                         Box::new(Expr::Variable(WithSpan::new_unchecked("a".into(), 0, 1))),
-                        vec![Expr::Number(3.)]
+                        wspn(vec![Expr::Number(3.)], 1, 4)
                     )),
                     wspn(BinaryOperator::Plus, 4, 5),
                     Box::new(Expr::Call(
                         Box::new(Expr::Variable(WithSpan::new_unchecked("a".into(), 5, 6))),
-                        vec![Expr::Number(3.)]
+                        wspn(vec![Expr::Number(3.)], 6, 9)
                     ))
                 ))
             );
@@ -566,7 +571,7 @@ mod tests {
                             Box::new(Expr::Variable(WithSpan::new_unchecked("a".into(), 0, 1))),
                             WithSpan::new_unchecked("b".into(), 2, 3)
                         )),
-                        vec![Expr::Number(3.0)]
+                        wspn(vec![Expr::Number(3.0)], 3, 6)
                     )),
                     WithSpan::new_unchecked("c".into(), 7, 8)
                 ))
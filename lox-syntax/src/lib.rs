@@ -1,4 +1,5 @@
 pub mod ast;
+pub mod diagnostics;
 pub mod position;
 
 #[macro_use]
@@ -10,7 +11,7 @@ mod token;
 mod tokenizer;
 
 use ast::{Ast, Expr};
-use position::WithSpan;
+use position::{BytePos, Span, WithSpan};
 use token::{Token, TokenKind};
 
 #[derive(PartialEq, Debug, Clone)]
@@ -23,10 +24,32 @@ pub enum SyntaxError {
     InvalidLeftValue(WithSpan<Expr>),
 }
 
-pub fn parse(code: &str) -> Result<Ast, SyntaxError> {
+impl SyntaxError {
+    /// Whether the error is caused by the input ending too early, so more input could fix it.
+    pub fn is_incomplete(&self) -> bool {
+        match self {
+            SyntaxError::Expected(_, token)
+            | SyntaxError::Unexpected(token)
+            | SyntaxError::ExpectedUnaryOperator(token)
+            | SyntaxError::ExpectedBinaryOperator(token)
+            | SyntaxError::ExpectedPrimary(token) => {
+                matches!(token.value, Token::Eof | Token::UnterminatedString)
+            }
+            SyntaxError::InvalidLeftValue(_) => false,
+        }
+    }
+}
+
+/// Parse `code` into an `Ast`, recovering from syntax errors.
+/// Every error found is returned together with the statements that could be parsed.
+pub fn parse(code: &str) -> (Ast, Vec<SyntaxError>) {
     use stmt_parser::parse;
     use tokenizer::tokenize_with_context;
-    let tokens = tokenize_with_context(code);
+    let mut tokens = tokenize_with_context(code);
+    // End with an explicit Eof token so errors at the end of the input point there.
+    let end = BytePos(code.len() as u32);
+    tokens.push(WithSpan::new(Token::Eof, Span { start: end, end }));
     let mut parser = crate::parser::Parser::new(&tokens);
-    parse(&mut parser)
+    let ast = parse(&mut parser);
+    (ast, parser.into_errors())
 }
@@ -7,17 +7,35 @@ static EOF_TOKEN: WithSpan<Token> = WithSpan::empty(Token::Eof);
 pub struct Parser<'a> {
     tokens: &'a [WithSpan<Token>],
     cursor: usize,
+    errors: Vec<SyntaxError>,
 }
 
 impl<'a> Parser<'a> {
     pub fn new(tokens: &'a [WithSpan<Token>]) -> Self {
-        Parser { tokens, cursor: 0 }
+        Parser {
+            tokens,
+            cursor: 0,
+            errors: vec![],
+        }
+    }
+
+    /// Record an error the parser has recovered from.
+    pub fn error(&mut self, error: SyntaxError) {
+        self.errors.push(error);
+    }
+
+    pub fn into_errors(self) -> Vec<SyntaxError> {
+        self.errors
     }
 
     pub fn is_eof(&self) -> bool {
         self.check(TokenKind::Eof)
     }
 
+    pub fn position(&self) -> usize {
+        self.cursor
+    }
+
     pub fn peek(&self) -> TokenKind {
         self.peek_token().into()
     }
@@ -37,7 +55,7 @@ impl<'a> Parser<'a> {
     pub fn advance(&mut self) -> &'a WithSpan<Token> {
         let token = self.tokens.get(self.cursor);
         if let Some(token) = token {
-            self.cursor = self.cursor + 1;
+            self.cursor += 1;
             token
         } else {
             &EOF_TOKEN
@@ -45,9 +63,10 @@ impl<'a> Parser<'a> {
     }
 
     pub fn expect(&mut self, expected: TokenKind) -> Result<&'a WithSpan<Token>, SyntaxError> {
-        let token = self.advance();
+        // A mismatched token is left in place so error recovery can synchronize on it.
+        let token = self.peek_token();
         if TokenKind::from(token) == expected {
-            Ok(token)
+            Ok(self.advance())
         } else {
             Err(SyntaxError::Expected(expected, token.clone()))
         }
@@ -55,7 +74,7 @@ impl<'a> Parser<'a> {
 
     pub fn optionally(&mut self, expected: TokenKind) -> Result<bool, SyntaxError> {
         let token = self.peek();
-        if TokenKind::from(token) == expected {
+        if token == expected {
             self.expect(expected)?;
             Ok(true)
         } else {
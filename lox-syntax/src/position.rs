@@ -14,6 +14,8 @@ pub struct Span {
 }
 
 impl Span {
+    /// # Safety
+    /// `start` and `end` must be valid byte offsets into the source that was tokenized.
     pub unsafe fn new_unchecked(start: u32, end: u32) -> Self {
         Span {
             start: BytePos(start),
@@ -52,6 +54,8 @@ impl<T> WithSpan<T> {
         }
     }
 
+    /// # Safety
+    /// `start` and `end` must be valid byte offsets into the source that was tokenized.
     pub const unsafe fn new_unchecked(value: T, start: u32, end: u32) -> Self {
         Self {
             value,
@@ -69,3 +73,50 @@ impl<T> WithSpan<T> {
         }
     }
 }
+
+/// Byte offsets of the start of every line, used to turn a `BytePos` into a line number.
+#[derive(Debug, Clone)]
+pub struct LineOffsets {
+    offsets: Vec<u32>,
+}
+
+impl LineOffsets {
+    pub fn new(data: &str) -> Self {
+        let mut offsets = vec![0];
+        for (index, byte) in data.bytes().enumerate() {
+            if byte == b'\n' {
+                offsets.push(index as u32 + 1);
+            }
+        }
+        LineOffsets { offsets }
+    }
+
+    /// The 1-based line containing `pos`.
+    pub fn line(&self, pos: BytePos) -> usize {
+        match self.offsets.binary_search(&pos.0) {
+            Ok(index) => index + 1,
+            Err(index) => index,
+        }
+    }
+
+    pub fn offsets(&self) -> &[u32] {
+        &self.offsets
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_offsets() {
+        let offsets = LineOffsets::new("a\nbc\n\nd");
+        assert_eq!(offsets.offsets(), &[0, 2, 5, 6]);
+        assert_eq!(offsets.line(BytePos(0)), 1);
+        assert_eq!(offsets.line(BytePos(1)), 1);
+        assert_eq!(offsets.line(BytePos(2)), 2);
+        assert_eq!(offsets.line(BytePos(4)), 2);
+        assert_eq!(offsets.line(BytePos(5)), 3);
+        assert_eq!(offsets.line(BytePos(7)), 4);
+    }
+}
@@ -2,18 +2,71 @@ use super::ast::*;
 use super::token::*;
 use crate::common::*;
 use crate::parser::Parser;
-use crate::position::WithSpan;
+use crate::position::{Span, WithSpan};
 use crate::SyntaxError;
 
-fn parse_program(it: &mut Parser) -> Result<Vec<Stmt>, SyntaxError> {
+fn parse_program(it: &mut Parser) -> Vec<Stmt> {
     let mut statements = Vec::new();
     while !it.is_eof() {
-        statements.push(parse_declaration(it)?);
+        if let Some(stmt) = parse_declaration_or_synchronize(it) {
+            statements.push(stmt);
+        }
     }
 
+    statements
+}
+
+fn parse_declarations(it: &mut Parser) -> Result<Vec<Stmt>, SyntaxError> {
+    it.expect(TokenKind::LeftBrace)?;
+    let mut statements: Vec<Stmt> = Vec::new();
+    while !it.check(TokenKind::RightBrace) && !it.is_eof() {
+        if let Some(stmt) = parse_declaration_or_synchronize(it) {
+            statements.push(stmt);
+        }
+    }
+    it.expect(TokenKind::RightBrace)?;
     Ok(statements)
 }
 
+// Parse a declaration, on error record it and skip ahead to the next statement boundary.
+fn parse_declaration_or_synchronize(it: &mut Parser) -> Option<Stmt> {
+    let start = it.position();
+    match parse_declaration(it) {
+        Ok(stmt) => Some(stmt),
+        Err(error) => {
+            it.error(error);
+            // Always make progress, the offending token could be a statement boundary itself.
+            if it.position() == start {
+                it.advance();
+            }
+            synchronize(it);
+            None
+        }
+    }
+}
+
+fn synchronize(it: &mut Parser) {
+    loop {
+        match it.peek() {
+            TokenKind::Class
+            | TokenKind::Fun
+            | TokenKind::Var
+            | TokenKind::For
+            | TokenKind::If
+            | TokenKind::While
+            | TokenKind::Print
+            | TokenKind::Return
+            | TokenKind::RightBrace
+            | TokenKind::Eof => return,
+            _ => (),
+        }
+
+        if it.advance().value == Token::Semicolon {
+            return;
+        }
+    }
+}
+
 fn parse_declaration(it: &mut Parser) -> Result<Stmt, SyntaxError> {
     match it.peek() {
         TokenKind::Var => parse_var_declaration(it),
@@ -46,7 +99,7 @@ fn parse_class_declaration(it: &mut Parser) -> Result<Stmt, SyntaxError> {
     };
     it.expect(TokenKind::LeftBrace)?;
     let mut functions: Vec<Stmt> = vec![];
-    while !it.check(TokenKind::RightBrace) {
+    while !it.check(TokenKind::RightBrace) && !it.is_eof() {
         functions.push(parse_function(it)?);
     }
     it.expect(TokenKind::RightBrace)?;
@@ -68,12 +121,7 @@ fn parse_function(it: &mut Parser) -> Result<Stmt, SyntaxError> {
         Vec::new()
     };
     it.expect(TokenKind::RightParen)?;
-    it.expect(TokenKind::LeftBrace)?;
-    let mut body: Vec<Stmt> = Vec::new();
-    while !it.check(TokenKind::RightBrace) {
-        body.push(parse_declaration(it)?);
-    }
-    it.expect(TokenKind::RightBrace)?;
+    let body = parse_declarations(it)?;
     Ok(Stmt::Function(name.clone(), params, body))
 }
 
@@ -102,7 +150,7 @@ fn parse_var_declaration(it: &mut Parser) -> Result<Stmt, SyntaxError> {
 }
 
 fn parse_expr(it: &mut Parser) -> Result<Expr, SyntaxError> {
-    super::expr_parser::parse(it).map_err(|e| e.into())
+    super::expr_parser::parse(it)
 }
 
 fn parse_for_statement(it: &mut Parser) -> Result<Stmt, SyntaxError> {
@@ -144,13 +192,14 @@ fn parse_for_statement(it: &mut Parser) -> Result<Stmt, SyntaxError> {
 }
 
 fn parse_return_statement(it: &mut Parser) -> Result<Stmt, SyntaxError> {
-    it.expect(TokenKind::Return)?;
+    let keyword = it.expect(TokenKind::Return)?;
     let mut expr: Option<Expr> = None;
     if !it.check(TokenKind::Semicolon) {
         expr = Some(parse_expr(it)?);
     }
-    it.expect(TokenKind::Semicolon)?;
-    Ok(Stmt::Return(expr.map(Box::new)))
+    let semicolon = it.expect(TokenKind::Semicolon)?;
+    let span = Span::union(keyword.span, semicolon.span);
+    Ok(Stmt::Return(span, expr.map(Box::new)))
 }
 
 fn parse_expr_statement(it: &mut Parser) -> Result<Stmt, SyntaxError> {
@@ -161,12 +210,7 @@ fn parse_expr_statement(it: &mut Parser) -> Result<Stmt, SyntaxError> {
 }
 
 fn parse_block_statement(it: &mut Parser) -> Result<Stmt, SyntaxError> {
-    it.expect(TokenKind::LeftBrace)?;
-    let mut statements: Vec<Stmt> = Vec::new();
-    while !it.check(TokenKind::RightBrace) {
-        statements.push(parse_declaration(it)?);
-    }
-    it.expect(TokenKind::RightBrace)?;
+    let statements = parse_declarations(it)?;
     Ok(Stmt::Block(statements))
 }
 
@@ -205,7 +249,8 @@ fn parse_print_statement(it: &mut Parser) -> Result<Stmt, SyntaxError> {
     Ok(Stmt::Print(Box::new(expr)))
 }
 
-pub fn parse(it: &mut Parser) -> Result<Vec<Stmt>, SyntaxError> {
+/// Parse a whole program, errors are recorded in the parser and don't stop parsing.
+pub fn parse(it: &mut Parser) -> Vec<Stmt> {
     parse_program(it)
 }
 
@@ -214,9 +259,18 @@ mod tests {
     use super::super::tokenizer::*;
     use super::*;
     fn parse_str(data: &str) -> Result<Vec<Stmt>, SyntaxError> {
+        let (stmts, errors) = parse_with_errors(data);
+        match errors.into_iter().next() {
+            Some(error) => Err(error),
+            None => Ok(stmts),
+        }
+    }
+
+    fn parse_with_errors(data: &str) -> (Vec<Stmt>, Vec<SyntaxError>) {
         let tokens = tokenize_with_context(data);
         let mut parser = crate::parser::Parser::new(&tokens);
-        parse(&mut parser)
+        let stmts = parse(&mut parser);
+        (stmts, parser.into_errors())
     }
 
     #[test]
@@ -330,11 +384,19 @@ mod tests {
 
     #[test]
     fn test_return_stmt() {
-        assert_eq!(parse_str("return;"), Ok(vec![Stmt::Return(None),]));
-        assert_eq!(
-            parse_str("return nil;"),
-            Ok(vec![Stmt::Return(Some(Box::new(Expr::Nil)))])
-        );
+        unsafe {
+            assert_eq!(
+                parse_str("return;"),
+                Ok(vec![Stmt::Return(Span::new_unchecked(0, 7), None),])
+            );
+            assert_eq!(
+                parse_str("return nil;"),
+                Ok(vec![Stmt::Return(
+                    Span::new_unchecked(0, 11),
+                    Some(Box::new(Expr::Nil))
+                )])
+            );
+        }
     }
 
     #[test]
@@ -446,4 +508,53 @@ mod tests {
             ])])
         );
     }
+
+    #[test]
+    fn test_recovery() {
+        let (stmts, errors) = parse_with_errors("var = 1; print 2; 3 +; print 4;");
+        assert_eq!(
+            stmts,
+            vec![
+                Stmt::Print(Box::new(Expr::Number(2.))),
+                Stmt::Print(Box::new(Expr::Number(4.))),
+            ]
+        );
+        assert_eq!(errors.len(), 2);
+        assert!(matches!(errors[0], SyntaxError::Expected(TokenKind::Identifier, WithSpan{span: _, value: Token::Equal})));
+        assert!(matches!(errors[1], SyntaxError::Unexpected(WithSpan{span: _, value: Token::Semicolon})));
+
+        let (stmts, errors) = parse_with_errors("print 1 var a = 2;");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(stmts.len(), 1);
+        assert!(matches!(stmts[0], Stmt::Var(_, _)));
+    }
+
+    #[test]
+    fn test_recovery_in_block() {
+        let (stmts, errors) = parse_with_errors("{ print; print 1; } print 2;");
+        assert_eq!(
+            stmts,
+            vec![
+                Stmt::Block(vec![Stmt::Print(Box::new(Expr::Number(1.)))]),
+                Stmt::Print(Box::new(Expr::Number(2.))),
+            ]
+        );
+        assert_eq!(errors.len(), 1);
+
+        let (stmts, errors) = parse_with_errors("fun a() { var = 1; } fun b() { return 1 }");
+        assert_eq!(stmts.len(), 2);
+        assert_eq!(errors.len(), 2);
+        assert!(matches!(errors[1], SyntaxError::Expected(TokenKind::Semicolon, WithSpan{span: _, value: Token::RightBrace})));
+    }
+
+    #[test]
+    fn test_recovery_unterminated() {
+        let (stmts, errors) = parse_with_errors("{ print 1;");
+        assert!(stmts.is_empty());
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors[0], SyntaxError::Expected(TokenKind::RightBrace, WithSpan{span: _, value: Token::Eof})));
+
+        let (_, errors) = parse_with_errors("} print 1; }");
+        assert_eq!(errors.len(), 2);
+    }
 }
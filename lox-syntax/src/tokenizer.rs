@@ -10,7 +10,7 @@ struct Scanner<'a> {
 }
 
 impl<'a> Scanner<'a> {
-    fn new(buf: &str) -> Scanner {
+    fn new(buf: &str) -> Scanner<'_> {
         Scanner {
             current_position: BytePos::default(),
             it: buf.chars().peekable(),
@@ -52,9 +52,8 @@ impl<'a> Scanner<'a> {
         F: Fn(char) -> bool,
     {
         let mut it = self.it.clone();
-        match it.next() {
-            None => return false,
-            _ => (),
+        if it.next().is_none() {
+            return false;
         }
 
         if let Some(&ch) = it.peek() {
@@ -91,7 +90,7 @@ struct Lexer<'a> {
 }
 
 impl<'a> Lexer<'a> {
-    fn new(buf: &str) -> Lexer {
+    fn new(buf: &str) -> Lexer<'_> {
         Lexer {
             it: Scanner::new(buf),
         }
@@ -168,10 +167,7 @@ impl<'a> Lexer<'a> {
         keywords.insert("var", Token::Var);
         keywords.insert("while", Token::While);
 
-        match keywords.get(identifier) {
-            None => None,
-            Some(token) => Some(token.clone()),
-        }
+        keywords.get(identifier).cloned()
     }
 
     fn identifier(&mut self, x: char) -> Option<Token> {
@@ -249,6 +245,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::approx_constant)]
     fn test_errors() {
         assert_eq!(tokenize("\"test"), vec![Token::UnterminatedString]);
         assert_eq!(tokenize("&"), vec![Token::Unknown('&')]);
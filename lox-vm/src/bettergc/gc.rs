@@ -1,61 +1,384 @@
 use super::*;
-use std::cell::RefCell;
+use std::mem::size_of;
+use std::time::{Duration, Instant};
 
-struct GcStats {
-    bytes_allocated: usize,
-    threshold: usize,
+/// When the collector runs and how much memory it can use, in bytes as counted by
+/// `Trace::size_hint`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct GcConfig {
+    /// How much is allocated between collections of the young objects.
+    pub nursery_size: usize,
+    /// How large the old objects can grow before the whole heap is collected the first time,
+    /// and the least they can grow to after a full collection.
+    pub initial_threshold: usize,
+    /// How large the old objects can grow after a full collection, relative to what survived.
+    pub growth_factor: f64,
+    /// Allocations fail once the heap would grow past this, even after a full collection.
+    pub max_heap: Option<usize>,
 }
 
-thread_local!(static STATS: RefCell<GcStats> = RefCell::new(GcStats {
-    bytes_allocated: 0,
-    threshold: 100,
-}));
+impl Default for GcConfig {
+    fn default() -> Self {
+        GcConfig {
+            nursery_size: 256 * 1024,
+            initial_threshold: 1024 * 1024,
+            growth_factor: 1.4,
+            max_heap: None,
+        }
+    }
+}
 
-thread_local!(static HEAP: RefCell<Heap> = RefCell::new(Heap::new()));
+/// An allocation would have grown the heap past `GcConfig::max_heap`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct OutOfMemory;
 
-pub fn manage<T: 'static + Trace>(data: T) -> Root<T> {
-    collect_if_needed();
-    add_bytes::<T>();
-    HEAP.with(|heap| heap.borrow_mut().manage(data))
+/// What the collector did so far, and what is on the heap.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct GcStats {
+    /// Collections of only the young objects.
+    pub minor_collections: usize,
+    /// Collections of the whole heap.
+    pub major_collections: usize,
+    /// Objects on the heap, including garbage that wasn't collected yet.
+    pub heap_objects: usize,
+    /// Bytes on the heap, including garbage that wasn't collected yet.
+    pub heap_bytes: usize,
+    /// Bytes freed by all collections together.
+    pub bytes_freed: usize,
+    /// How long all collections took together.
+    pub pause_time: Duration,
 }
 
-pub fn unique<T: 'static + Trace>(data: T) -> UniqueRoot<T> {
-    collect_if_needed();
-    add_bytes::<T>();
-    HEAP.with(|heap| heap.borrow_mut().unique(data))
+/// A heap and the bookkeeping that decides when to collect it. Every VM has its own, objects
+/// are freed when the context is dropped.
+#[derive(Debug)]
+pub struct Context {
+    heap: Heap,
+    config: GcConfig,
+    stats: GcStats,
+    // Allocated since the last collection.
+    young_bytes: usize,
+    // Survived a collection.
+    old_bytes: usize,
+    // The whole heap is collected once `old_bytes` grows past this.
+    threshold: usize,
+    // Allocations since the last collection, used to alternate collections in stress mode.
+    #[cfg(feature = "gc-stress")]
+    allocations: usize,
 }
 
-pub fn root<T: 'static + Trace + ?Sized>(obj: Gc<T>) -> Root<T> {
-    HEAP.with(|heap| heap.borrow_mut().root(obj))
+impl Default for Context {
+    fn default() -> Self {
+        Self::new(GcConfig::default())
+    }
 }
 
-//TODO Currently unused
-// pub fn force_collect() {
-//     STATS.with(|stats| {
-//         let mut stats = stats.borrow_mut();
-//         stats.bytes_allocated -= collect();
-//     })
-// }
+impl Context {
+    pub fn new(config: GcConfig) -> Self {
+        Context {
+            heap: Heap::new(),
+            config,
+            stats: GcStats::default(),
+            young_bytes: 0,
+            old_bytes: 0,
+            threshold: config.initial_threshold,
+            #[cfg(feature = "gc-stress")]
+            allocations: 0,
+        }
+    }
 
-fn collect() -> usize {
-    HEAP.with(|heap| heap.borrow_mut().collect())
-}
+    pub fn manage<T: 'static + Trace>(&mut self, data: T) -> Root<T> {
+        self.collect_if_needed();
+        self.young_bytes += size_of::<T>() + data.size_hint();
+        self.heap.manage(data)
+    }
+
+    /// Like `manage`, but fails instead of growing the heap past `GcConfig::max_heap`.
+    pub fn try_manage<T: 'static + Trace>(&mut self, data: T) -> Result<Root<T>, OutOfMemory> {
+        self.reserve(size_of::<T>() + data.size_hint())?;
+        Ok(self.heap.manage(data))
+    }
+
+    pub fn unique<T: 'static + Trace>(&mut self, data: T) -> UniqueRoot<T> {
+        self.collect_if_needed();
+        self.young_bytes += size_of::<T>() + data.size_hint();
+        self.heap.unique(data)
+    }
+
+    /// The id of the heap, no other heap has the same one.
+    pub fn id(&self) -> usize {
+        self.heap.id
+    }
+
+    /// Get the interned copy of `string`, allocating it the first time it is seen.
+    /// Interned strings are collected like any other object once nothing refers to them.
+    pub fn intern(&mut self, string: &str) -> Root<String> {
+        if let Some(root) = self.heap.interned(string) {
+            return root;
+        }
+
+        self.collect_if_needed();
+        self.young_bytes += size_of::<String>() + string.len();
+        self.heap.intern(string.to_string())
+    }
 
-fn collect_if_needed() {
-    STATS.with(|stats| {
-        let mut stats = stats.borrow_mut();
+    /// Like `intern`, but fails instead of growing the heap past `GcConfig::max_heap`.
+    pub fn try_intern(&mut self, string: &str) -> Result<Root<String>, OutOfMemory> {
+        if let Some(root) = self.heap.interned(string) {
+            return Ok(root);
+        }
 
-        if stats.bytes_allocated > stats.threshold {
-            stats.bytes_allocated -= collect();
+        self.reserve(size_of::<String>() + string.len())?;
+        Ok(self.heap.intern(string.to_string()))
+    }
 
-            stats.threshold = (stats.bytes_allocated as f32 * 1.4) as usize;
+    /// Tell the collector that `obj` is about to be changed, see `Heap::write_barrier`.
+    pub fn write_barrier<T: 'static + Trace>(&mut self, obj: Gc<T>) {
+        // Only old objects have to be remembered, check that without going through the heap.
+        let header = &obj.allocation().header;
+        if header.marked.get() && !header.remembered.get() {
+            self.heap.write_barrier(obj);
         }
-    })
+    }
+
+    /// Collect the whole heap now.
+    pub fn force_collect(&mut self) {
+        self.collect();
+    }
+
+    /// How many collections ran. Objects that aren't rooted stay alive until this changes.
+    pub fn collections(&self) -> usize {
+        self.stats.minor_collections + self.stats.major_collections
+    }
+
+    /// What the collector did so far.
+    pub fn stats(&self) -> GcStats {
+        GcStats {
+            heap_objects: self.heap.objects(),
+            heap_bytes: self.young_bytes + self.old_bytes,
+            ..self.stats
+        }
+    }
+
+    #[cfg(not(feature = "gc-stress"))]
+    fn collect_if_needed(&mut self) {
+        if self.young_bytes > self.config.nursery_size {
+            self.collect_young();
+
+            if self.old_bytes > self.threshold {
+                self.collect();
+            }
+        }
+    }
+
+    // Collect before every allocation, so objects that aren't rooted or traced are freed
+    // while they are still used. Collections alternate, young ones also test the write
+    // barrier.
+    #[cfg(feature = "gc-stress")]
+    fn collect_if_needed(&mut self) {
+        self.allocations += 1;
+        if self.allocations.is_multiple_of(2) {
+            self.collect_young();
+        } else {
+            self.collect();
+        }
+    }
+
+    // Account for `size` more bytes, unless that grows the heap past its limit even after
+    // everything that can be freed is.
+    fn reserve(&mut self, size: usize) -> Result<(), OutOfMemory> {
+        self.collect_if_needed();
+        if let Some(max_heap) = self.config.max_heap {
+            if self.young_bytes + self.old_bytes + size > max_heap {
+                self.collect();
+                if self.old_bytes + size > max_heap {
+                    return Err(OutOfMemory);
+                }
+            }
+        }
+        self.young_bytes += size;
+        Ok(())
+    }
+
+    fn collect_young(&mut self) {
+        let start = Instant::now();
+        let survived = self.heap.collect_young();
+        self.stats.minor_collections += 1;
+        // Objects can grow after they are allocated, more can survive than was counted.
+        self.stats.bytes_freed += self.young_bytes.saturating_sub(survived);
+        self.stats.pause_time += start.elapsed();
+        self.old_bytes += survived;
+        self.young_bytes = 0;
+    }
+
+    fn collect(&mut self) {
+        let start = Instant::now();
+        let survived = self.heap.collect();
+        self.stats.major_collections += 1;
+        self.stats.bytes_freed += (self.young_bytes + self.old_bytes).saturating_sub(survived);
+        self.stats.pause_time += start.elapsed();
+        self.old_bytes = survived;
+        self.young_bytes = 0;
+        // A small heap would otherwise be collected whole after almost every young collection.
+        let threshold = (self.old_bytes as f64 * self.config.growth_factor) as usize;
+        self.threshold = threshold.max(self.config.initial_threshold);
+    }
+}
+
+pub fn root<T: 'static + Trace + ?Sized>(obj: Gc<T>) -> Root<T> {
+    // Roots are counted on the object itself, the heap doesn't need to know.
+    obj.allocation().root();
+    Root { ptr: obj.ptr }
 }
 
-fn add_bytes<T>() {
-    STATS.with(|stats| {
-        let mut stats = stats.borrow_mut();
-        stats.bytes_allocated += std::mem::size_of::<T>();
-    })
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+
+    struct Node {
+        next: Option<Gc<GcCell<Node>>>,
+        drops: Rc<Cell<usize>>,
+    }
+
+    impl Trace for Node {
+        fn trace(&self, tracer: &mut Tracer) {
+            self.next.trace(tracer);
+        }
+    }
+
+    impl Drop for Node {
+        fn drop(&mut self) {
+            self.drops.set(self.drops.get() + 1);
+        }
+    }
+
+    fn node(gc: &mut Context, drops: &Rc<Cell<usize>>) -> Root<GcCell<Node>> {
+        gc.manage(GcCell::new(Node {
+            next: None,
+            drops: drops.clone(),
+        }))
+    }
+
+    #[test]
+    fn test_young_collection() {
+        let mut gc = Context::default();
+        let drops = Rc::new(Cell::new(0));
+        let kept = node(&mut gc, &drops);
+        drop(node(&mut gc, &drops));
+        gc.collect_young();
+        assert_eq!(drops.get(), 1);
+
+        // Old objects are only freed when everything is collected.
+        drop(kept);
+        gc.collect_young();
+        assert_eq!(drops.get(), 1);
+        gc.collect();
+        assert_eq!(drops.get(), 2);
+    }
+
+    #[test]
+    fn test_write_barrier() {
+        let mut gc = Context::default();
+        let drops = Rc::new(Cell::new(0));
+        let old = node(&mut gc, &drops);
+        gc.collect_young();
+
+        let young = node(&mut gc, &drops);
+        old.borrow_mut(&mut gc).next = Some(young.as_gc());
+        drop(young);
+        gc.collect_young();
+        assert_eq!(drops.get(), 0);
+
+        old.borrow_mut(&mut gc).next = None;
+        gc.collect_young();
+        assert_eq!(drops.get(), 0);
+        gc.collect();
+        assert_eq!(drops.get(), 1);
+    }
+
+    #[test]
+    // Collecting the whole heap before every allocation makes this quadratic.
+    #[cfg_attr(feature = "gc-stress", ignore)]
+    fn test_deep_structure() {
+        let mut gc = Context::default();
+        let drops = Rc::new(Cell::new(0));
+        let mut head = node(&mut gc, &drops);
+        for _ in 0..100_000 {
+            let next = node(&mut gc, &drops);
+            next.borrow_mut(&mut gc).next = Some(head.as_gc());
+            head = next;
+        }
+        gc.collect();
+        assert_eq!(drops.get(), 0);
+
+        drop(head);
+        gc.collect();
+        assert_eq!(drops.get(), 100_001);
+    }
+
+    #[test]
+    #[cfg_attr(feature = "gc-stress", ignore)]
+    fn test_small_heap_threshold() {
+        let mut gc = Context::new(GcConfig {
+            nursery_size: 1024,
+            initial_threshold: 64 * 1024,
+            ..GcConfig::default()
+        });
+        let drops = Rc::new(Cell::new(0));
+        gc.collect();
+
+        // The last few nodes survive young collections, the old ones become garbage.
+        let mut recent = std::collections::VecDeque::new();
+        for _ in 0..10_000 {
+            recent.push_back(node(&mut gc, &drops));
+            if recent.len() > 4 {
+                recent.pop_front();
+            }
+        }
+        let stats = gc.stats();
+        assert!(stats.minor_collections > 100);
+        assert_eq!(stats.major_collections, 1);
+    }
+
+    #[test]
+    fn test_drop_context() {
+        let drops = Rc::new(Cell::new(0));
+        let mut gc = Context::default();
+        drop(node(&mut gc, &drops));
+        let _ = node(&mut gc, &drops).as_gc();
+        drop(gc);
+        assert_eq!(drops.get(), 2);
+    }
+
+    #[test]
+    fn test_ids() {
+        let gc = Context::default();
+        let other = Context::default();
+        assert_ne!(gc.id(), other.id());
+    }
+
+    #[test]
+    fn test_size_hint() {
+        let mut gc = Context::default();
+        let text = gc.manage("text".repeat(1000));
+        let _list = gc.manage(vec![text.as_gc(); 100]);
+        gc.collect();
+        assert!(gc.stats().heap_bytes >= 4000 + 100 * size_of::<Gc<String>>());
+    }
+
+    #[test]
+    fn test_max_heap() {
+        let mut gc = Context::new(GcConfig {
+            max_heap: Some(10_000),
+            ..GcConfig::default()
+        });
+        let kept = gc.try_manage("a".repeat(6000)).unwrap();
+        assert_eq!(gc.try_manage("b".repeat(6000)).unwrap_err(), OutOfMemory);
+
+        // Garbage is collected to make room.
+        drop(kept);
+        let _kept = gc.try_manage("b".repeat(6000)).unwrap();
+        assert_eq!(gc.try_intern(&"c".repeat(6000)).unwrap_err(), OutOfMemory);
+    }
 }
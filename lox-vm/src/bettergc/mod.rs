@@ -1,13 +1,64 @@
+//! A generational mark and sweep collector.
+//!
+//! New objects are young, objects that survive a collection become old. Most collections
+//! only look at the young objects: they are traced from the roots and from the remembered
+//! old objects, tracing stops at old objects. Once the old objects have grown enough the
+//! whole heap is collected.
+//!
+//! Old objects keep their mark between collections, that is what makes them old. An old
+//! object that is changed to point to a young object has to be remembered, which is what
+//! the write barrier in `borrow_mut` on `Gc<GcCell<T>>` does. Rooted objects are remembered
+//! as well, `UniqueRoot`s are changed without a barrier.
+//!
+//! Every heap lives in a `gc::Context`, which also decides when to collect it. A VM owns its
+//! context, so the write barrier takes it explicitly.
+
 pub mod gc;
 
-use std::cell::Cell;
+use std::borrow::Borrow;
+use std::cell::{Cell, Ref, RefCell, RefMut};
+use std::collections::HashSet;
 use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::ops::{Deref, DerefMut};
 use std::ptr::NonNull;
 use std::sync::atomic::{AtomicUsize, Ordering};
 
+// Every heap gets its own id, so what refers to one heap can't be mixed up with another.
+static NEXT_HEAP_ID: AtomicUsize = AtomicUsize::new(0);
+
 pub trait Trace {
-    fn trace(&self);
+    /// Mark every `Gc` this refers to with `tracer`.
+    fn trace(&self, tracer: &mut Tracer);
+
+    /// The bytes this owns outside of itself, like the buffer of a `String`. Objects count
+    /// as their own size plus this, which decides when the collector runs.
+    fn size_hint(&self) -> usize {
+        0
+    }
+}
+
+/// Marks objects and keeps the ones whose contents still have to be traced, so deep
+/// structures don't need deep recursion.
+#[derive(Debug, Default)]
+pub struct Tracer {
+    gray: Vec<NonNull<Allocation<dyn Trace>>>,
+}
+
+impl Tracer {
+    fn mark(&mut self, allocation: NonNull<Allocation<dyn Trace>>) {
+        let header = unsafe { &allocation.as_ref().header };
+        if !header.marked.replace(true) {
+            self.gray.push(allocation);
+        }
+    }
+
+    // Trace the contents of everything that was marked, until nothing new is found.
+    fn trace_gray(&mut self) {
+        while let Some(allocation) = self.gray.pop() {
+            unsafe { allocation.as_ref() }.data.trace(self);
+        }
+    }
 }
 
 impl fmt::Debug for dyn Trace {
@@ -18,8 +69,11 @@ impl fmt::Debug for dyn Trace {
 
 #[derive(Debug)]
 struct Header {
-    roots: AtomicUsize,
+    roots: Cell<usize>,
+    // Set for old objects, and for young objects that were found during a collection.
     marked: Cell<bool>,
+    // Whether the object is in `Heap::remembered`.
+    remembered: Cell<bool>,
 }
 
 #[derive(Debug)]
@@ -30,7 +84,48 @@ struct Allocation<T: 'static + Trace + ?Sized> {
 
 #[derive(Debug)]
 pub struct Heap {
-    objects: Vec<Box<Allocation<dyn Trace>>>,
+    id: usize,
+    // Objects allocated since the last collection.
+    young: Vec<Box<Allocation<dyn Trace>>>,
+    // Objects that survived a collection.
+    old: Vec<Box<Allocation<dyn Trace>>>,
+    // Old objects that may point to young objects, they are traced by young collections.
+    remembered: Vec<NonNull<Allocation<dyn Trace>>>,
+    // Weak references to the interned strings, they are removed when the string is swept.
+    strings: HashSet<Interned>,
+    // The interned strings in `young`, so young collections don't have to check all strings.
+    young_strings: Vec<NonNull<Allocation<String>>>,
+    tracer: Tracer,
+}
+
+// An interned string, hashed and compared by its contents so it can be looked up by `&str`.
+#[derive(Debug)]
+struct Interned(NonNull<Allocation<String>>);
+
+impl Interned {
+    fn allocation(&self) -> &Allocation<String> {
+        unsafe { self.0.as_ref() }
+    }
+}
+
+impl PartialEq for Interned {
+    fn eq(&self, other: &Interned) -> bool {
+        self.allocation().data == other.allocation().data
+    }
+}
+
+impl Eq for Interned {}
+
+impl Hash for Interned {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.allocation().data.as_str().hash(state);
+    }
+}
+
+impl Borrow<str> for Interned {
+    fn borrow(&self) -> &str {
+        &self.allocation().data
+    }
 }
 
 pub struct Gc<T: 'static + Trace + ?Sized> {
@@ -45,47 +140,62 @@ pub struct UniqueRoot<T: 'static + Trace + ?Sized> {
     ptr: NonNull<Allocation<T>>,
 }
 
+/// A `RefCell` for objects on the heap. It can only be mutably borrowed through the `Gc` or
+/// `Root` that points to it, which runs the write barrier.
+#[derive(Debug)]
+pub struct GcCell<T> {
+    cell: RefCell<T>,
+}
+
 impl<T: 'static + Trace + ?Sized> Allocation<T> {
     fn unmark(&self) {
         self.header.marked.set(false);
     }
 
     fn root(&self) {
-        self.header.roots.fetch_add(1, Ordering::Relaxed);
+        self.header.roots.set(self.header.roots.get() + 1);
     }
     fn unroot(&self) {
-        self.header.roots.fetch_sub(1, Ordering::Relaxed);
+        self.header.roots.set(self.header.roots.get() - 1);
     }
 }
-impl<T: 'static + Trace + ?Sized> Trace for Allocation<T> {
-    fn trace(&self) {
-        if !self.header.marked.replace(true) {
-            self.data.trace();
-        }
+
+impl Allocation<dyn Trace> {
+    fn size(&self) -> usize {
+        std::mem::size_of_val(&self.data) + self.data.size_hint()
     }
 }
 
-impl Default for Header {
-    fn default() -> Self {
+impl Header {
+    fn new() -> Self {
         Header {
-            roots: AtomicUsize::new(0),
+            roots: Cell::new(0),
             marked: Cell::new(false),
+            remembered: Cell::new(false),
         }
     }
 }
 
 impl Heap {
     pub fn new() -> Self {
-        Heap { objects: vec![] }
+        Heap {
+            id: NEXT_HEAP_ID.fetch_add(1, Ordering::Relaxed),
+            young: vec![],
+            old: vec![],
+            remembered: vec![],
+            strings: HashSet::new(),
+            young_strings: vec![],
+            tracer: Tracer::default(),
+        }
     }
 
     fn allocate<T: 'static + Trace>(&mut self, data: T) -> NonNull<Allocation<T>> {
         let mut alloc = Box::new(Allocation {
-            header: Header::default(),
+            header: Header::new(),
             data,
         });
         let ptr = unsafe { NonNull::new_unchecked(&mut *alloc) };
-        self.objects.push(alloc);
+        self.young.push(alloc);
         ptr
     }
 
@@ -112,44 +222,134 @@ impl Heap {
         root
     }
 
-    pub fn collect(&mut self) -> usize {
-        self.mark();
-        let bytes = self.bytes_marked();
-        self.sweep();
-        bytes
+    /// How many objects are on the heap, garbage included.
+    pub fn objects(&self) -> usize {
+        self.young.len() + self.old.len()
     }
 
-    fn mark(&mut self) {
-        for object in &self.objects {
-            object.unmark();
+    /// Root the interned copy of `string`, if there is one.
+    pub fn interned(&mut self, string: &str) -> Option<Root<String>> {
+        let ptr = self.strings.get(string)?.0;
+        Some(self.root(Gc { ptr }))
+    }
+
+    /// Manage `string` as the interned copy of its contents.
+    pub fn intern(&mut self, string: String) -> Root<String> {
+        let root = self.manage(string);
+        self.strings.insert(Interned(root.ptr));
+        self.young_strings.push(root.ptr);
+        root
+    }
+
+    /// Remember `obj` if it is old, it is about to be changed and might point to young
+    /// objects afterwards.
+    pub fn write_barrier<T: 'static + Trace>(&mut self, obj: Gc<T>) {
+        let header = &obj.allocation().header;
+        if header.marked.get() && !header.remembered.replace(true) {
+            self.remembered.push(obj.ptr);
         }
-        self.objects
-            .iter()
-            .filter(|o| o.header.roots.load(Ordering::Relaxed) > 0)
-            .for_each(|o| o.trace());
     }
 
-    fn sweep(&mut self) {
-        self.objects.retain(|o| o.header.marked.get());
+    /// Collect the young objects, the ones that survive become old.
+    /// Returns the number of bytes that survived.
+    pub fn collect_young(&mut self) -> usize {
+        for object in &self.young {
+            if object.header.roots.get() > 0 {
+                self.tracer.mark(NonNull::from(&**object));
+            }
+        }
+        // Remembered objects are marked already, trace what they point to directly.
+        for object in &self.remembered {
+            unsafe { object.as_ref() }.data.trace(&mut self.tracer);
+        }
+        self.tracer.trace_gray();
+
+        for string in self.young_strings.drain(..) {
+            let allocation = unsafe { string.as_ref() };
+            if !allocation.header.marked.get() {
+                self.strings.remove(allocation.data.as_str());
+            }
+        }
+        self.young.retain(|object| object.header.marked.get());
+
+        // The young objects that old ones pointed to are old now. Rooted objects stay
+        // remembered, roots can change them without a barrier.
+        self.remembered.retain(|object| {
+            let header = unsafe { &object.as_ref().header };
+            let rooted = header.roots.get() > 0;
+            header.remembered.set(rooted);
+            rooted
+        });
+        let mut survived = 0;
+        for object in &self.young {
+            survived += object.size();
+            if object.header.roots.get() > 0 && !object.header.remembered.replace(true) {
+                self.remembered.push(NonNull::from(&**object));
+            }
+        }
+        self.old.append(&mut self.young);
+        survived
     }
 
-    fn bytes_marked(&self) -> usize {
-        let mut bytes = 0;
-        for object in &self.objects {
-            if !object.header.marked.get() {
-                bytes += std::mem::size_of_val(&object.data);
+    /// Collect all objects, the ones that survive become old.
+    /// Returns the number of bytes that survived.
+    pub fn collect(&mut self) -> usize {
+        for object in self.old.iter().chain(&self.young) {
+            object.unmark();
+            object.header.remembered.set(false);
+        }
+        self.remembered.clear();
+
+        for object in self.old.iter().chain(&self.young) {
+            if object.header.roots.get() > 0 {
+                self.tracer.mark(NonNull::from(&**object));
             }
         }
-        bytes
+        self.tracer.trace_gray();
+
+        // The interned strings aren't traced, forget the ones that are about to be freed.
+        self.strings
+            .retain(|string| string.allocation().header.marked.get());
+        self.young_strings.clear();
+        self.old.retain(|object| object.header.marked.get());
+        self.young.retain(|object| object.header.marked.get());
+        self.old.append(&mut self.young);
+
+        let mut survived = 0;
+        for object in &self.old {
+            survived += object.size();
+            if object.header.roots.get() > 0 {
+                object.header.remembered.set(true);
+                self.remembered.push(NonNull::from(&**object));
+            }
+        }
+        survived
     }
 }
 
 impl<T: 'static + Trace + ?Sized> Gc<T> {
     fn allocation(&self) -> &Allocation<T> {
-        unsafe { &self.ptr.as_ref() }
+        unsafe { self.ptr.as_ref() }
+    }
+
+    /// Whether both pointers refer to the same allocation.
+    pub fn ptr_eq(a: &Gc<T>, b: &Gc<T>) -> bool {
+        std::ptr::addr_eq(a.ptr.as_ptr(), b.ptr.as_ptr())
     }
 }
 impl<T: 'static + Trace + ?Sized> Copy for Gc<T> {}
+// Gcs are compared and hashed by identity, not by the data they point to.
+impl<T: 'static + Trace + ?Sized> PartialEq for Gc<T> {
+    fn eq(&self, other: &Gc<T>) -> bool {
+        Gc::ptr_eq(self, other)
+    }
+}
+impl<T: 'static + Trace + ?Sized> Eq for Gc<T> {}
+impl<T: 'static + Trace + ?Sized> Hash for Gc<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.ptr.as_ptr().cast::<()>().hash(state);
+    }
+}
 impl<T: 'static + Trace + ?Sized> Clone for Gc<T> {
     fn clone(&self) -> Gc<T> {
         *self
@@ -164,25 +364,40 @@ impl<T: 'static + Trace + ?Sized> Deref for Gc<T> {
 }
 impl<T: fmt::Debug + 'static + Trace + ?Sized> fmt::Debug for Gc<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let inner: &T = &*self;
+        let inner: &T = self;
         write!(f, "Gc({:?})", inner)
     }
 }
 impl<T: fmt::Display + 'static + Trace + ?Sized> fmt::Display for Gc<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let inner: &T = &*self;
+        let inner: &T = self;
         inner.fmt(f)
     }
 }
-impl<T: 'static + Trace + ?Sized> Trace for Gc<T> {
-    fn trace(&self) {
-        self.allocation().trace();
+impl<T: 'static + Trace> Trace for Gc<T> {
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.mark(self.ptr);
+    }
+}
+impl<T: 'static + Trace> Gc<GcCell<T>> {
+    /// Mutably borrow the contents, with a write barrier so the collector of `gc` finds the
+    /// young objects they might point to afterwards.
+    pub fn borrow_mut(&self, gc: &mut gc::Context) -> RefMut<'_, T> {
+        gc.write_barrier(*self);
+        self.allocation().data.cell.borrow_mut()
     }
 }
 
-impl<T: 'static + Trace + ?Sized> Trace for Root<T> {
-    fn trace(&self) {
-        self.allocation().trace();
+impl<T: 'static + Trace> Trace for Root<T> {
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.mark(self.ptr);
+    }
+}
+impl<T: 'static + Trace> Root<GcCell<T>> {
+    /// Mutably borrow the contents, with a write barrier like `Gc::borrow_mut`.
+    pub fn borrow_mut(&self, gc: &mut gc::Context) -> RefMut<'_, T> {
+        gc.write_barrier(self.as_gc());
+        self.allocation().data.cell.borrow_mut()
     }
 }
 impl<T: 'static + Trace + ?Sized> Clone for Root<T> {
@@ -193,7 +408,7 @@ impl<T: 'static + Trace + ?Sized> Clone for Root<T> {
 }
 impl<T: 'static + Trace + ?Sized> Root<T> {
     fn allocation(&self) -> &Allocation<T> {
-        unsafe { &self.ptr.as_ref() }
+        unsafe { self.ptr.as_ref() }
     }
 
     pub fn as_gc(&self) -> Gc<T> {
@@ -214,14 +429,14 @@ impl<T: 'static + Trace + ?Sized> Deref for Root<T> {
 }
 impl<T: fmt::Debug + 'static + Trace + ?Sized> fmt::Debug for Root<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let inner: &T = &*self;
+        let inner: &T = self;
         write!(f, "Root({:?})", inner)
     }
 }
 
-impl<T: 'static + Trace + ?Sized> Trace for UniqueRoot<T> {
-    fn trace(&self) {
-        self.allocation().trace();
+impl<T: 'static + Trace> Trace for UniqueRoot<T> {
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.mark(self.ptr);
     }
 }
 impl<T: 'static + Trace + ?Sized> UniqueRoot<T> {
@@ -229,7 +444,7 @@ impl<T: 'static + Trace + ?Sized> UniqueRoot<T> {
         unsafe { self.ptr.as_mut() }
     }
     fn allocation(&self) -> &Allocation<T> {
-        unsafe { &self.ptr.as_ref() }
+        unsafe { self.ptr.as_ref() }
     }
 }
 impl<T: 'static + Trace + ?Sized> Drop for UniqueRoot<T> {
@@ -251,41 +466,128 @@ impl<T: 'static + Trace + ?Sized> DerefMut for UniqueRoot<T> {
 }
 impl<T: fmt::Debug + 'static + Trace + ?Sized> fmt::Debug for UniqueRoot<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let inner: &T = &*self;
+        let inner: &T = self;
         write!(f, "UniqueRoot({:?})", inner)
     }
 }
 
-use std::cell::RefCell;
+impl<T> GcCell<T> {
+    pub fn new(value: T) -> Self {
+        GcCell {
+            cell: RefCell::new(value),
+        }
+    }
+
+    pub fn borrow(&self) -> Ref<'_, T> {
+        self.cell.borrow()
+    }
+}
+
 use std::collections::HashMap;
-use std::hash::Hash;
-impl<T: Trace> Trace for RefCell<T> {
-    fn trace(&self) {
-        self.borrow().trace();
+impl<T: Trace> Trace for GcCell<T> {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.borrow().trace(tracer);
+    }
+
+    fn size_hint(&self) -> usize {
+        self.borrow().size_hint()
+    }
+}
+impl<T: Trace> Trace for Option<T> {
+    fn trace(&self, tracer: &mut Tracer) {
+        if let Some(value) = self {
+            value.trace(tracer);
+        }
+    }
+
+    fn size_hint(&self) -> usize {
+        self.as_ref().map_or(0, Trace::size_hint)
     }
 }
 impl<T: Trace> Trace for Vec<T> {
-    fn trace(&self) {
+    fn trace(&self, tracer: &mut Tracer) {
         for el in self {
-            el.trace();
+            el.trace(tracer);
         }
     }
+
+    fn size_hint(&self) -> usize {
+        let contents: usize = self.iter().map(Trace::size_hint).sum();
+        self.capacity() * std::mem::size_of::<T>() + contents
+    }
 }
 impl<T: Trace> Trace for &Vec<T> {
-    fn trace(&self) {
+    fn trace(&self, tracer: &mut Tracer) {
         for el in *self {
-            el.trace();
+            el.trace(tracer);
         }
     }
 }
-impl<K: Eq + Hash, T: Trace> Trace for HashMap<K, T> {
-    fn trace(&self) {
-        for val in self.values() {
-            val.trace();
+impl<K: Eq + Hash + Trace, T: Trace> Trace for HashMap<K, T> {
+    fn trace(&self, tracer: &mut Tracer) {
+        for (key, val) in self {
+            key.trace(tracer);
+            val.trace(tracer);
         }
     }
+
+    // Ignores the control bytes of the table, they are a byte per entry.
+    fn size_hint(&self) -> usize {
+        let contents: usize = self
+            .iter()
+            .map(|(key, val)| key.size_hint() + val.size_hint())
+            .sum();
+        self.capacity() * std::mem::size_of::<(K, T)>() + contents
+    }
 }
 
 impl Trace for String {
-    fn trace(&self) {}
+    fn trace(&self, _tracer: &mut Tracer) {}
+
+    fn size_hint(&self) -> usize {
+        self.capacity()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intern() {
+        let mut heap = Heap::new();
+        let a = heap.intern("a".to_string());
+        let b = heap.interned("a").unwrap();
+        assert!(Gc::ptr_eq(&a.as_gc(), &b.as_gc()));
+        assert!(heap.interned("b").is_none());
+    }
+
+    #[test]
+    fn test_interned_strings_are_weak() {
+        let mut heap = Heap::new();
+        let kept = heap.intern("kept".to_string());
+        drop(heap.intern("dropped".to_string()));
+        heap.collect();
+
+        assert!(heap.interned("dropped").is_none());
+        assert!(Gc::ptr_eq(
+            &heap.interned("kept").unwrap().as_gc(),
+            &kept.as_gc()
+        ));
+    }
+
+    #[test]
+    fn test_young_interned_strings_are_weak() {
+        let mut heap = Heap::new();
+        let old = heap.intern("old".to_string());
+        heap.collect_young();
+        drop(old);
+        drop(heap.intern("young".to_string()));
+        heap.collect_young();
+
+        assert!(heap.interned("young").is_none());
+        assert!(heap.interned("old").is_some());
+        heap.collect();
+        assert!(heap.interned("old").is_none());
+    }
 }
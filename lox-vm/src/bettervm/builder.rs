@@ -0,0 +1,83 @@
+use super::convert::IntoNative;
+use super::error::VmError;
+use super::host::Value;
+use super::memory::{Arity, NativeFunction};
+use super::vm::{Limits, Vm};
+use crate::bettergc::gc::GcConfig;
+
+/// Sets up a `Vm` with the limits, collector settings and native functions of the host.
+///
+/// ```
+/// use lox_vm::bettervm::{Value, VmBuilder};
+/// use std::cell::Cell;
+/// use std::rc::Rc;
+///
+/// let calls = Rc::new(Cell::new(0));
+/// let counter = calls.clone();
+/// let mut vm = VmBuilder::new()
+///     .register_native("count", 0, move |_vm, _args| {
+///         counter.set(counter.get() + 1);
+///         Ok(Value::Number(counter.get() as f64))
+///     })
+///     .build();
+/// let count = vm.global("count").unwrap();
+/// assert_eq!(vm.type_description(count), Ok("a function"));
+/// ```
+#[derive(Default)]
+pub struct VmBuilder {
+    limits: Limits,
+    gc: GcConfig,
+    natives: Vec<NativeFunction>,
+}
+
+impl VmBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Tune the garbage collector and limit the size of the heap of the VM.
+    pub fn gc(mut self, config: GcConfig) -> Self {
+        self.gc = config;
+        self
+    }
+
+    /// Define a global function `name` that runs `code` when scripts call it with a number of
+    /// arguments that `arity` accepts, like `2`, `1..=3` or `1..`. `code` can capture state of
+    /// the host, it lives as long as the VM. The errors it returns are runtime errors.
+    pub fn register_native<F>(mut self, name: &str, arity: impl Into<Arity>, code: F) -> Self
+    where
+        F: Fn(&mut Vm, &[Value]) -> Result<Value, VmError> + 'static,
+    {
+        self.natives.push(NativeFunction {
+            name: name.to_string(),
+            arity: arity.into(),
+            code: Box::new(code),
+        });
+        self
+    }
+
+    /// Define a global function `name` that converts its arguments and result to and from Lox
+    /// values, like `fn add(a: f64, b: f64) -> f64`.
+    pub fn register_fn<Args>(mut self, name: &str, function: impl IntoNative<Args>) -> Self {
+        let (arity, code) = function.into_native();
+        self.natives.push(NativeFunction {
+            name: name.to_string(),
+            arity,
+            code,
+        });
+        self
+    }
+
+    pub fn build(self) -> Vm {
+        let mut vm = Vm::with_config(self.limits, self.gc);
+        for native in self.natives {
+            vm.define_native(native);
+        }
+        vm
+    }
+}
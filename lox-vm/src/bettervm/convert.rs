@@ -0,0 +1,333 @@
+//! Conversions between Rust and Lox values, so natives can be plain Rust functions.
+//!
+//! ```
+//! use lox_vm::bettervm::VmBuilder;
+//!
+//! fn add(a: f64, b: f64) -> f64 {
+//!     a + b
+//! }
+//!
+//! let vm = VmBuilder::new()
+//!     .register_fn("add", add)
+//!     .register_fn("greet", |name: String| format!("Hello, {}!", name))
+//!     .build();
+//! ```
+
+use super::error::VmError;
+use super::host::Value;
+use super::memory::{self, Arity, NativeCode};
+use super::vm::Vm;
+
+/// Convert a Lox value to a Rust value, failing with a runtime error for values of the wrong
+/// type or numbers that don't fit.
+pub trait FromLox: Sized {
+    fn from_lox(value: Value, vm: &Vm) -> Result<Self, VmError>;
+}
+
+/// Convert a Rust value to a Lox value.
+pub trait IntoLox {
+    fn into_lox(self, vm: &mut Vm) -> Value;
+
+    /// Convert the result of a native. The VM keeps it alive once the native returns, so unlike
+    /// `into_lox` this doesn't need to root it for the host. The handles of objects it creates
+    /// are released by the next collection.
+    fn into_lox_return(self, vm: &mut Vm) -> Value
+    where
+        Self: Sized,
+    {
+        self.into_lox(vm)
+    }
+}
+
+/// Convert the arguments of a native call, implemented for tuples of `FromLox` types.
+pub trait FromLoxArgs: Sized {
+    const ARITY: usize;
+
+    fn from_lox_args(args: &[Value], vm: &Vm) -> Result<Self, VmError>;
+}
+
+/// What natives registered with `register_fn` can return, `IntoLox` types and results of them.
+pub trait IntoLoxResult {
+    fn into_lox_result(self, vm: &mut Vm) -> Result<Value, VmError>;
+}
+
+/// A Rust function that can be called from Lox, implemented for functions and closures that
+/// take `FromLox` arguments and return an `IntoLoxResult`.
+pub trait IntoNative<Args> {
+    fn into_native(self) -> (Arity, Box<NativeCode>);
+}
+
+impl FromLox for Value {
+    fn from_lox(value: Value, _vm: &Vm) -> Result<Self, VmError> {
+        Ok(value)
+    }
+}
+
+impl IntoLox for Value {
+    fn into_lox(self, _vm: &mut Vm) -> Value {
+        self
+    }
+}
+
+impl FromLox for f64 {
+    fn from_lox(value: Value, vm: &Vm) -> Result<Self, VmError> {
+        match value {
+            Value::Number(n) => Ok(n),
+            value => Err(VmError::ExpectedType(
+                "a number",
+                vm.type_description(value)?,
+            )),
+        }
+    }
+}
+
+impl IntoLox for f64 {
+    fn into_lox(self, _vm: &mut Vm) -> Value {
+        Value::Number(self)
+    }
+}
+
+// Integers are numbers without a fraction that fit in the type.
+macro_rules! impl_integer {
+    ($($type:ty),*) => {
+        $(
+            impl FromLox for $type {
+                fn from_lox(value: Value, vm: &Vm) -> Result<Self, VmError> {
+                    let n = f64::from_lox(value, vm)?;
+                    // `MAX + 1` is rounded to a power of two, which is exactly past the end.
+                    if n.fract() == 0.0 && n >= <$type>::MIN as f64 && n < <$type>::MAX as f64 + 1.0
+                    {
+                        Ok(n as $type)
+                    } else {
+                        Err(VmError::OutOfRange(n, <$type>::MIN as i128, <$type>::MAX as i128))
+                    }
+                }
+            }
+
+            impl IntoLox for $type {
+                fn into_lox(self, _vm: &mut Vm) -> Value {
+                    Value::Number(self as f64)
+                }
+            }
+        )*
+    };
+}
+
+impl_integer!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+impl FromLox for bool {
+    fn from_lox(value: Value, vm: &Vm) -> Result<Self, VmError> {
+        match value {
+            Value::Boolean(boolean) => Ok(boolean),
+            value => Err(VmError::ExpectedType(
+                "a boolean",
+                vm.type_description(value)?,
+            )),
+        }
+    }
+}
+
+impl IntoLox for bool {
+    fn into_lox(self, _vm: &mut Vm) -> Value {
+        Value::Boolean(self)
+    }
+}
+
+impl FromLox for String {
+    fn from_lox(value: Value, vm: &Vm) -> Result<Self, VmError> {
+        match vm.resolve(value)? {
+            memory::Value::String(string) => Ok(string.to_string()),
+            value => Err(VmError::ExpectedType("a string", value.type_description())),
+        }
+    }
+}
+
+impl IntoLox for String {
+    fn into_lox(self, vm: &mut Vm) -> Value {
+        vm.string(&self)
+    }
+
+    fn into_lox_return(self, vm: &mut Vm) -> Value {
+        vm.intern(&self)
+    }
+}
+
+impl IntoLox for &str {
+    fn into_lox(self, vm: &mut Vm) -> Value {
+        vm.string(self)
+    }
+
+    fn into_lox_return(self, vm: &mut Vm) -> Value {
+        vm.intern(self)
+    }
+}
+
+impl<T: FromLox> FromLox for Option<T> {
+    fn from_lox(value: Value, vm: &Vm) -> Result<Self, VmError> {
+        match value {
+            Value::Nil => Ok(None),
+            value => T::from_lox(value, vm).map(Some),
+        }
+    }
+}
+
+impl<T: IntoLox> IntoLox for Option<T> {
+    fn into_lox(self, vm: &mut Vm) -> Value {
+        match self {
+            Some(value) => value.into_lox(vm),
+            None => Value::Nil,
+        }
+    }
+
+    fn into_lox_return(self, vm: &mut Vm) -> Value {
+        match self {
+            Some(value) => value.into_lox_return(vm),
+            None => Value::Nil,
+        }
+    }
+}
+
+impl IntoLox for () {
+    fn into_lox(self, _vm: &mut Vm) -> Value {
+        Value::Nil
+    }
+}
+
+impl<T: IntoLox> IntoLoxResult for T {
+    fn into_lox_result(self, vm: &mut Vm) -> Result<Value, VmError> {
+        Ok(self.into_lox_return(vm))
+    }
+}
+
+impl<T: IntoLox> IntoLoxResult for Result<T, VmError> {
+    fn into_lox_result(self, vm: &mut Vm) -> Result<Value, VmError> {
+        self.map(|value| value.into_lox_return(vm))
+    }
+}
+
+macro_rules! impl_native {
+    ($arity:expr; $($arg:ident),*) => {
+        impl<$($arg: FromLox),*> FromLoxArgs for ($($arg,)*) {
+            const ARITY: usize = $arity;
+
+            #[allow(non_snake_case, unused_variables, unused_mut)]
+            fn from_lox_args(args: &[Value], vm: &Vm) -> Result<Self, VmError> {
+                if args.len() != Self::ARITY {
+                    return Err(VmError::IncorrectArity(Arity::exactly(Self::ARITY), args.len()));
+                }
+                let mut args = args.iter();
+                $(let $arg = $arg::from_lox(*args.next().unwrap(), vm)?;)*
+                Ok(($($arg,)*))
+            }
+        }
+
+        impl<F, R, $($arg),*> IntoNative<($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> R + 'static,
+            R: IntoLoxResult,
+            $($arg: FromLox,)*
+        {
+            #[allow(non_snake_case)]
+            fn into_native(self) -> (Arity, Box<NativeCode>) {
+                let code = move |vm: &mut Vm, args: &[Value]| {
+                    let ($($arg,)*) = <($($arg,)*)>::from_lox_args(args, vm)?;
+                    self($($arg),*).into_lox_result(vm)
+                };
+                (Arity::exactly($arity), Box::new(code))
+            }
+        }
+    };
+}
+
+impl_native!(0;);
+impl_native!(1; A);
+impl_native!(2; A, B);
+impl_native!(3; A, B, C);
+impl_native!(4; A, B, C, D);
+impl_native!(5; A, B, C, D, E);
+impl_native!(6; A, B, C, D, E, G);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_numbers() {
+        let vm = Vm::new();
+        assert_eq!(f64::from_lox(Value::Number(1.5), &vm), Ok(1.5));
+        assert_eq!(u8::from_lox(Value::Number(255.0), &vm), Ok(255));
+        assert_eq!(i8::from_lox(Value::Number(-128.0), &vm), Ok(-128));
+        assert_eq!(
+            u8::from_lox(Value::Number(256.0), &vm),
+            Err(VmError::OutOfRange(256.0, 0, 255))
+        );
+        assert_eq!(
+            u32::from_lox(Value::Number(-1.0), &vm),
+            Err(VmError::OutOfRange(-1.0, 0, u32::MAX as i128))
+        );
+        assert!(i32::from_lox(Value::Number(1.5), &vm).is_err());
+        assert!(i64::from_lox(Value::Number(f64::NAN), &vm).is_err());
+        assert!(i64::from_lox(Value::Number(f64::INFINITY), &vm).is_err());
+        assert!(i64::from_lox(Value::Number(9223372036854775808.0), &vm).is_err());
+        assert_eq!(
+            i64::from_lox(Value::Number(-9223372036854775808.0), &vm),
+            Ok(i64::MIN)
+        );
+        assert!(u64::from_lox(Value::Number(18446744073709551616.0), &vm).is_err());
+        assert_eq!(
+            i32::from_lox(Value::Boolean(true), &vm),
+            Err(VmError::ExpectedType("a number", "a boolean"))
+        );
+    }
+
+    #[test]
+    fn test_strings() {
+        let mut vm = Vm::new();
+        let a = "a".into_lox(&mut vm);
+        let b = String::from("b").into_lox(&mut vm);
+        assert_eq!(String::from_lox(a, &vm), Ok("a".to_string()));
+        assert_eq!(String::from_lox(b, &vm), Ok("b".to_string()));
+        assert_eq!(
+            String::from_lox(Value::Nil, &vm),
+            Err(VmError::ExpectedType("a string", "nil"))
+        );
+    }
+
+    #[test]
+    fn test_returned_strings_are_not_rooted() {
+        let mut vm = Vm::new();
+        vm.collect_garbage();
+        let objects = vm.gc_stats().heap_objects;
+        let returned = "returned".into_lox_return(&mut vm);
+        assert_eq!(String::from_lox(returned, &vm), Ok("returned".to_string()));
+        Some(String::from("returned")).into_lox_return(&mut vm);
+        vm.collect_garbage();
+        assert_eq!(vm.gc_stats().heap_objects, objects);
+        assert_eq!(String::from_lox(returned, &vm), Err(VmError::ReleasedValue));
+        "kept".into_lox(&mut vm);
+        vm.collect_garbage();
+        assert_eq!(vm.gc_stats().heap_objects, objects + 1);
+    }
+
+    #[test]
+    fn test_options_and_args() {
+        let mut vm = Vm::new();
+        assert_eq!(Option::<f64>::from_lox(Value::Nil, &vm), Ok(None));
+        assert_eq!(
+            Option::<f64>::from_lox(Value::Number(1.0), &vm),
+            Ok(Some(1.0))
+        );
+        assert_eq!(None::<f64>.into_lox(&mut vm), Value::Nil);
+        assert_eq!(().into_lox(&mut vm), Value::Nil);
+
+        let args = [Value::Number(1.0), Value::Boolean(true), Value::Nil];
+        assert_eq!(
+            <(u8, bool, Option<String>)>::from_lox_args(&args, &vm),
+            Ok((1, true, None))
+        );
+        assert_eq!(
+            <(u8, bool)>::from_lox_args(&args, &vm),
+            Err(VmError::IncorrectArity(Arity::exactly(2), 3))
+        );
+    }
+}
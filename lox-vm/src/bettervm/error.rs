@@ -0,0 +1,214 @@
+use super::memory::Arity;
+use crate::bettergc::gc::OutOfMemory;
+use crate::bytecode::Span;
+use lox_bytecode::verifier::VerifyError;
+use std::fmt;
+use std::iter;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VmError {
+    StackEmpty,
+    FrameEmpty,
+    StringConstantExpected,
+    GlobalNotDefined(String),
+    InvalidCallee,
+    IncorrectArity(Arity, usize),
+    UnexpectedConstant,
+    ClosureConstantExpected,
+    UnexpectedValue,
+    UndefinedProperty(String),
+    InvalidSuperclass,
+    ExpectedNumberOperand,
+    ExpectedNumberOperands,
+    ExpectedNumberOrStringOperands,
+    ExpectedInstance,
+    InvalidModule(VerifyError),
+    StackOverflow,
+    /// An error reported by a native function of the host.
+    Native(String),
+    ExpectedType(&'static str, &'static str),
+    OutOfRange(f64, i128, i128),
+    /// The heap would have grown past `GcConfig::max_heap`.
+    OutOfMemory,
+    /// The host passed a value of another VM.
+    ForeignValue,
+    /// The host used a value after the VM released it.
+    ReleasedValue,
+}
+
+impl VmError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            VmError::StackEmpty => "E201",
+            VmError::FrameEmpty => "E202",
+            VmError::StringConstantExpected => "E203",
+            VmError::GlobalNotDefined(_) => "E204",
+            VmError::InvalidCallee => "E205",
+            VmError::IncorrectArity(_, _) => "E206",
+            VmError::UnexpectedConstant => "E207",
+            VmError::ClosureConstantExpected => "E208",
+            VmError::UnexpectedValue => "E209",
+            VmError::UndefinedProperty(_) => "E210",
+            VmError::InvalidSuperclass => "E211",
+            VmError::ExpectedNumberOperand => "E212",
+            VmError::ExpectedNumberOperands => "E213",
+            VmError::ExpectedNumberOrStringOperands => "E214",
+            VmError::ExpectedInstance => "E215",
+            VmError::InvalidModule(_) => "E216",
+            VmError::StackOverflow => "E217",
+            VmError::Native(_) => "E218",
+            VmError::ExpectedType(_, _) => "E219",
+            VmError::OutOfRange(_, _, _) => "E220",
+            VmError::OutOfMemory => "E221",
+            VmError::ForeignValue => "E222",
+            VmError::ReleasedValue => "E223",
+        }
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::StackEmpty => write!(f, "Stack is empty."),
+            VmError::FrameEmpty => write!(f, "No call frame."),
+            VmError::StringConstantExpected => write!(f, "Expected a string constant."),
+            VmError::GlobalNotDefined(name) => write!(f, "Undefined variable '{}'.", name),
+            VmError::InvalidCallee => write!(f, "Can only call functions and classes."),
+            VmError::IncorrectArity(expected, got) => {
+                write!(f, "Expected {} arguments but got {}.", expected, got)
+            }
+            VmError::UnexpectedConstant => write!(f, "Unexpected constant."),
+            VmError::ClosureConstantExpected => write!(f, "Expected a closure constant."),
+            VmError::UnexpectedValue => write!(f, "Unexpected value."),
+            VmError::UndefinedProperty(name) => write!(f, "Undefined property '{}'.", name),
+            VmError::InvalidSuperclass => write!(f, "Superclass must be a class."),
+            VmError::ExpectedNumberOperand => write!(f, "Operand must be a number."),
+            VmError::ExpectedNumberOperands => write!(f, "Operands must be numbers."),
+            VmError::ExpectedNumberOrStringOperands => {
+                write!(f, "Operands must be two numbers or two strings.")
+            }
+            VmError::ExpectedInstance => write!(f, "Only instances have properties."),
+            VmError::InvalidModule(error) => write!(f, "Invalid module: {}", error),
+            VmError::StackOverflow => write!(f, "Stack overflow."),
+            VmError::Native(message) => write!(f, "{}", message),
+            VmError::ExpectedType(expected, got) => {
+                write!(f, "Expected {} but got {}.", expected, got)
+            }
+            VmError::OutOfRange(n, min, max) => {
+                write!(
+                    f,
+                    "Expected an integer from {} to {} but got {}.",
+                    min, max, n
+                )
+            }
+            VmError::OutOfMemory => write!(f, "Out of memory."),
+            VmError::ForeignValue => write!(f, "Value belongs to another VM."),
+            VmError::ReleasedValue => write!(f, "Value was released by the VM."),
+        }
+    }
+}
+
+/// A single entry of the Lox call stack at the time of a runtime error.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceFrame {
+    /// Name of the function, `None` for top-level code.
+    pub function: Option<String>,
+    pub span: Span,
+    pub line: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub error: VmError,
+    pub span: Span,
+    pub line: usize,
+    /// The call stack, innermost frame first.
+    pub trace: Vec<TraceFrame>,
+}
+
+// A frame that repeats this many times or more, like in a runaway recursion, is shown once.
+const COLLAPSE_REPEATS: usize = 3;
+// How many lines of a long trace are shown at the start and at the end.
+const TRACE_HEAD: usize = 10;
+const TRACE_TAIL: usize = 10;
+
+impl TraceFrame {
+    fn location(&self) -> String {
+        match &self.function {
+            Some(name) => format!("{}()", name),
+            None => "script".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[line {}] in {}", self.line, self.location())
+    }
+}
+
+impl RuntimeError {
+    /// The trace as it is shown to users, innermost frame first. Repeated frames are collapsed
+    /// and only the start and the end of a long trace are kept.
+    pub fn trace_lines(&self) -> Vec<String> {
+        // Every line, with the number of frames it stands for.
+        let mut lines: Vec<(String, usize)> = vec![];
+        let mut frames = self.trace.iter().peekable();
+        while let Some(frame) = frames.next() {
+            let mut repeats = 0;
+            while frames.next_if_eq(&frame).is_some() {
+                repeats += 1;
+            }
+            if repeats < COLLAPSE_REPEATS {
+                lines.extend(iter::repeat_n((frame.to_string(), 1), repeats + 1));
+            } else {
+                lines.push((frame.to_string(), 1));
+                let more = format!("... {} in {}", more_frames(repeats), frame.location());
+                lines.push((more, repeats));
+            }
+        }
+
+        if lines.len() > TRACE_HEAD + TRACE_TAIL {
+            let hidden = TRACE_HEAD..lines.len() - TRACE_TAIL;
+            let count = lines[hidden.clone()].iter().map(|(_, frames)| frames).sum();
+            lines.splice(
+                hidden,
+                iter::once((format!("... {}", more_frames(count)), count)),
+            );
+        }
+        lines.into_iter().map(|(line, _)| line).collect()
+    }
+}
+
+fn more_frames(count: usize) -> String {
+    match count {
+        1 => "1 more frame".to_string(),
+        _ => format!("{} more frames", count),
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.error)?;
+        for line in self.trace_lines() {
+            write!(f, "\n{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+// Lets natives pass on errors of code they called with `?`, the error is reported again at the
+// call of the native.
+impl From<RuntimeError> for VmError {
+    fn from(error: RuntimeError) -> Self {
+        error.error
+    }
+}
+
+impl From<OutOfMemory> for VmError {
+    fn from(_: OutOfMemory) -> Self {
+        VmError::OutOfMemory
+    }
+}
@@ -0,0 +1,146 @@
+use super::error::VmError;
+use super::memory;
+use crate::bettergc::gc::Context;
+use crate::bettergc::UniqueRoot;
+use std::fmt;
+
+/// A value as the host sees it.
+///
+/// Everything that lives on the heap of a VM is an `Object`, a handle that only the VM can
+/// resolve. The objects the VM hands to the host stay alive until the host calls into the VM
+/// again, or until the native that got them returns. A host that holds on to one for longer
+/// should keep it reachable from a global. Using a handle after that, or passing it to another
+/// VM, is a runtime error.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Value {
+    Number(f64),
+    Boolean(bool),
+    Nil,
+    Object(Object),
+}
+
+/// A handle to a string, function, class or instance on the heap of a VM. Handles are equal
+/// when they are the same handle, not when they point to the same object.
+#[derive(Copy, Clone, PartialEq)]
+pub struct Object {
+    // The id of the heap the object is on.
+    heap: usize,
+    handle: Handle,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Handle {
+    // A slot of `HostValues`, valid as long as the slot has the same stamp.
+    Rooted {
+        index: usize,
+        stamp: u64,
+    },
+    // Not rooted at all, valid until the next collection. Natives return these.
+    Fresh {
+        value: memory::Value,
+        collections: usize,
+    },
+}
+
+// The object might be gone, so it can't be shown.
+impl fmt::Debug for Object {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Object").finish_non_exhaustive()
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Value::Number(value)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Boolean(value)
+    }
+}
+
+/// The objects handed to the host, rooted until they are released. Every one gets a new
+/// stamp, so the handles of released objects don't match the objects that replace them.
+pub(super) struct HostValues {
+    values: UniqueRoot<Vec<memory::Value>>,
+    stamps: Vec<u64>,
+    next_stamp: u64,
+}
+
+impl HostValues {
+    pub(super) fn new(gc: &mut Context) -> Self {
+        HostValues {
+            values: gc.unique(vec![]),
+            stamps: vec![],
+            next_stamp: 0,
+        }
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Release the objects handed out after the first `len`.
+    pub(super) fn truncate(&mut self, len: usize) {
+        self.values.truncate(len);
+        self.stamps.truncate(len);
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.truncate(0);
+    }
+
+    /// Hand `value` to the host, rooting it if it is an object.
+    pub(super) fn handle(&mut self, value: memory::Value, gc: &Context) -> Value {
+        let handle = match value {
+            memory::Value::Number(n) => return Value::Number(n),
+            memory::Value::Boolean(boolean) => return Value::Boolean(boolean),
+            memory::Value::Nil => return Value::Nil,
+            _ => Handle::Rooted {
+                index: self.values.len(),
+                stamp: self.next_stamp,
+            },
+        };
+        self.values.push(value);
+        self.stamps.push(self.next_stamp);
+        self.next_stamp += 1;
+        Value::Object(Object {
+            heap: gc.id(),
+            handle,
+        })
+    }
+
+    /// Hand `value` to the host without rooting it, the handle is released by the next
+    /// collection.
+    pub(super) fn fresh(&self, value: memory::Value, gc: &Context) -> Value {
+        Value::Object(Object {
+            heap: gc.id(),
+            handle: Handle::Fresh {
+                value,
+                collections: gc.collections(),
+            },
+        })
+    }
+
+    /// The value a handle of the host refers to.
+    pub(super) fn resolve(&self, value: Value, gc: &Context) -> Result<memory::Value, VmError> {
+        let object = match value {
+            Value::Number(n) => return Ok(memory::Value::Number(n)),
+            Value::Boolean(boolean) => return Ok(memory::Value::Boolean(boolean)),
+            Value::Nil => return Ok(memory::Value::Nil),
+            Value::Object(object) => object,
+        };
+        if object.heap != gc.id() {
+            return Err(VmError::ForeignValue);
+        }
+        match object.handle {
+            Handle::Rooted { index, stamp } if self.stamps.get(index) == Some(&stamp) => {
+                Ok(self.values[index])
+            }
+            Handle::Fresh { value, collections } if collections == gc.collections() => Ok(value),
+            _ => Err(VmError::ReleasedValue),
+        }
+    }
+}
@@ -1,6 +1,8 @@
-use crate::bettergc::{Gc, Trace};
+use super::error::VmError;
+use super::host;
+use super::vm::Vm;
+use crate::bettergc::{Gc, GcCell, Trace, Tracer};
 use crate::bytecode::ChunkIndex;
-use std::cell::RefCell;
 use std::collections::HashMap;
 
 #[derive(Debug, Copy, Clone)]
@@ -12,29 +14,16 @@ pub enum Upvalue {
 impl Upvalue {
     pub fn is_open_with_index(&self, index: usize) -> bool {
         match self {
-            Self::Open(i) => {
-                if *i == index {
-                    true
-                } else {
-                    false
-                }
-            }
-            Self::Closed(_) => false,
-        }
-    }
-
-    pub fn is_open(&self) -> bool {
-        match self {
-            Self::Open(_) => true,
+            Self::Open(i) => *i == index,
             Self::Closed(_) => false,
         }
     }
 }
 
 impl Trace for Upvalue {
-    fn trace(&self) {
+    fn trace(&self, tracer: &mut Tracer) {
         match self {
-            Upvalue::Closed(value) => value.trace(),
+            Upvalue::Closed(value) => value.trace(tracer),
             Upvalue::Open(_) => (),
         }
     }
@@ -42,42 +31,130 @@ impl Trace for Upvalue {
 
 #[derive(Debug)]
 pub struct Instance {
-    pub class: Gc<RefCell<Class>>,
-    pub fields: HashMap<String, Value>,
+    pub class: Gc<GcCell<Class>>,
+    pub fields: HashMap<Gc<String>, Value>,
 }
 
 impl Trace for Instance {
-    fn trace(&self) {
-        self.class.trace();
-        self.fields.trace();
+    fn trace(&self, tracer: &mut Tracer) {
+        self.class.trace(tracer);
+        self.fields.trace(tracer);
+    }
+
+    fn size_hint(&self) -> usize {
+        self.fields.size_hint()
     }
 }
 
 #[derive(Debug)]
 pub struct Class {
     pub name: String,
+    pub methods: HashMap<Gc<String>, Gc<Closure>>,
 }
 
 impl Trace for Class {
-    fn trace(&self) {}
+    fn trace(&self, tracer: &mut Tracer) {
+        self.methods.trace(tracer);
+    }
+
+    fn size_hint(&self) -> usize {
+        self.name.capacity() + self.methods.size_hint()
+    }
+}
+
+#[derive(Debug)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: Gc<Closure>,
+}
+
+impl Trace for BoundMethod {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.receiver.trace(tracer);
+        self.method.trace(tracer);
+    }
 }
 
 #[derive(Debug)]
 pub struct Closure {
     pub function: Gc<Function>,
-    pub upvalues: Vec<Gc<RefCell<Upvalue>>>,
+    pub upvalues: Vec<Gc<GcCell<Upvalue>>>,
 }
 
 impl Trace for Closure {
-    fn trace(&self) {
-        self.function.trace();
-        self.upvalues.trace();
+    fn trace(&self, tracer: &mut Tracer) {
+        self.function.trace(tracer);
+        self.upvalues.trace(tracer);
+    }
+
+    fn size_hint(&self) -> usize {
+        self.upvalues.size_hint()
+    }
+}
+
+/// The host code behind a native function, called with the VM and the arguments.
+/// An error is reported as a runtime error at the call.
+pub type NativeCode = dyn Fn(&mut Vm, &[host::Value]) -> Result<host::Value, VmError>;
+
+/// How many arguments a function takes, natives can take a range.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Arity {
+    min: usize,
+    max: Option<usize>,
+}
+
+impl Arity {
+    pub fn exactly(count: usize) -> Self {
+        Arity {
+            min: count,
+            max: Some(count),
+        }
+    }
+
+    pub fn at_least(min: usize) -> Self {
+        Arity { min, max: None }
+    }
+
+    pub fn accepts(&self, count: usize) -> bool {
+        count >= self.min && self.max.is_none_or(|max| count <= max)
+    }
+}
+
+impl From<usize> for Arity {
+    fn from(count: usize) -> Self {
+        Arity::exactly(count)
+    }
+}
+
+impl From<std::ops::RangeInclusive<usize>> for Arity {
+    fn from(range: std::ops::RangeInclusive<usize>) -> Self {
+        Arity {
+            min: *range.start(),
+            max: Some(*range.end()),
+        }
+    }
+}
+
+impl From<std::ops::RangeFrom<usize>> for Arity {
+    fn from(range: std::ops::RangeFrom<usize>) -> Self {
+        Arity::at_least(range.start)
+    }
+}
+
+impl std::fmt::Display for Arity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.max {
+            Some(max) if max == self.min => write!(f, "{}", max),
+            Some(max) => write!(f, "{} to {}", self.min, max),
+            None => write!(f, "at least {}", self.min),
+        }
     }
 }
 
 pub struct NativeFunction {
     pub name: String,
-    pub code: fn(&[Value]) -> Value,
+    pub arity: Arity,
+    pub code: Box<NativeCode>,
 }
 
 impl std::fmt::Debug for NativeFunction {
@@ -87,7 +164,12 @@ impl std::fmt::Debug for NativeFunction {
 }
 
 impl Trace for NativeFunction {
-    fn trace(&self) {}
+    fn trace(&self, _tracer: &mut Tracer) {}
+
+    // The state that the host code captured is owned by the function as well.
+    fn size_hint(&self) -> usize {
+        self.name.capacity() + std::mem::size_of_val(&*self.code)
+    }
 }
 
 //TODO Drop this entirely and merge this into Closure
@@ -100,7 +182,11 @@ pub struct Function {
 }
 
 impl Trace for Function {
-    fn trace(&self) {}
+    fn trace(&self, _tracer: &mut Tracer) {}
+
+    fn size_hint(&self) -> usize {
+        self.name.capacity()
+    }
 }
 
 impl From<&crate::bytecode::Function> for Function {
@@ -113,26 +199,30 @@ impl From<&crate::bytecode::Function> for Function {
     }
 }
 
+/// A value as scripts see it. Values that point into the heap are only kept alive by the VM,
+/// the host gets handles to them instead.
 #[derive(Debug, Copy, Clone)]
 pub enum Value {
     Number(f64),
     String(Gc<String>),
     Closure(Gc<Closure>),
+    BoundMethod(Gc<BoundMethod>),
     NativeFunction(Gc<NativeFunction>),
     Boolean(bool),
-    Class(Gc<RefCell<Class>>),
-    Instance(Gc<RefCell<Instance>>),
+    Class(Gc<GcCell<Class>>),
+    Instance(Gc<GcCell<Instance>>),
     Nil,
 }
 
 impl Trace for Value {
-    fn trace(&self) {
+    fn trace(&self, tracer: &mut Tracer) {
         match self {
-            Value::String(string) => string.trace(),
-            Value::NativeFunction(function) => function.trace(),
-            Value::Closure(closure) => closure.trace(),
-            Value::Class(class) => class.trace(),
-            Value::Instance(instance) => instance.trace(),
+            Value::String(string) => string.trace(tracer),
+            Value::NativeFunction(function) => function.trace(tracer),
+            Value::Closure(closure) => closure.trace(tracer),
+            Value::BoundMethod(bound) => bound.trace(tracer),
+            Value::Class(class) => class.trace(tracer),
+            Value::Instance(instance) => instance.trace(tracer),
             Value::Number(_) => (),
             Value::Nil => (),
             Value::Boolean(_) => (),
@@ -141,6 +231,19 @@ impl Trace for Value {
 }
 
 impl Value {
+    /// The type of the value for error messages, like "a number".
+    pub fn type_description(&self) -> &'static str {
+        match self {
+            Value::Number(_) => "a number",
+            Value::String(_) => "a string",
+            Value::Closure(_) | Value::BoundMethod(_) | Value::NativeFunction(_) => "a function",
+            Value::Boolean(_) => "a boolean",
+            Value::Class(_) => "a class",
+            Value::Instance(_) => "an instance",
+            Value::Nil => "nil",
+        }
+    }
+
     pub fn is_falsey(&self) -> bool {
         match self {
             Value::Boolean(boolean) => !boolean,
@@ -148,20 +251,60 @@ impl Value {
             _ => false,
         }
     }
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Number(n) => write!(f, "{}", n),
+            Value::Nil => write!(f, "nil"),
+            Value::Boolean(boolean) => write!(f, "{}", boolean),
+            Value::String(string) => write!(f, "{}", string.as_str()),
+            Value::NativeFunction(function) => write!(f, "<native fun {}>", function.name),
+            Value::Closure(closure) => write!(
+                f,
+                "<fun {}({}) @ {}>",
+                closure.function.name, closure.function.arity, closure.function.chunk_index
+            ),
+            Value::BoundMethod(bound) => write!(
+                f,
+                "<fun {}({}) @ {}>",
+                bound.method.function.name,
+                bound.method.function.arity,
+                bound.method.function.chunk_index
+            ),
+            Value::Class(class) => write!(f, "{}", class.borrow().name),
+            Value::Instance(instance) => {
+                write!(f, "{} instance", instance.borrow().class.borrow().name)
+            }
+        }
+    }
+}
 
-    pub fn is_same_type(a: &Value, b: &Value) -> bool {
-        match (b, a) {
-            (Value::Number(_), Value::Number(_)) => true,
-            (Value::Boolean(_), Value::Boolean(_)) => true,
-            (Value::String(_), Value::String(_)) => true,
-            (Value::NativeFunction(_), Value::NativeFunction(_)) => true,
-            (Value::Closure(_), Value::Closure(_)) => true,
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::Boolean(a), Value::Boolean(b)) => a == b,
+            // Strings are interned, so equal strings are the same object.
+            (Value::String(a), Value::String(b)) => Gc::ptr_eq(a, b),
+            (Value::Closure(a), Value::Closure(b)) => Gc::ptr_eq(a, b),
+            (Value::BoundMethod(a), Value::BoundMethod(b)) => Gc::ptr_eq(a, b),
+            (Value::NativeFunction(a), Value::NativeFunction(b)) => Gc::ptr_eq(a, b),
+            (Value::Class(a), Value::Class(b)) => Gc::ptr_eq(a, b),
+            (Value::Instance(a), Value::Instance(b)) => Gc::ptr_eq(a, b),
             (Value::Nil, Value::Nil) => true,
             _ => false,
         }
     }
 }
 
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Value::Number(value)
+    }
+}
+
 impl From<bool> for Value {
     fn from(value: bool) -> Self {
         if value {
@@ -1,27 +1,33 @@
+mod builder;
+mod convert;
+mod error;
+mod host;
 mod memory;
 mod vm;
 
 use crate::bytecode::Module;
-use vm::Vm;
 
-pub use vm::VmError;
+pub use crate::bettergc::gc::{GcConfig, GcStats};
+pub use builder::VmBuilder;
+pub use convert::{FromLox, FromLoxArgs, IntoLox, IntoLoxResult, IntoNative};
+pub use error::{RuntimeError, TraceFrame, VmError};
+pub use host::{Object, Value};
+pub use memory::Arity;
+pub use vm::{Limits, Vm};
 
-pub fn execute(module: &Module) -> Result<(), VmError> {
-    let mut vm = Vm::new(module);
-
-    //TODO Work on a way of initializing and executing the VM.
-    //     It should be possible to define your own native functions.
-    vm.set_native_fn("clock", |_args| {
-        use std::time::{SystemTime, UNIX_EPOCH};
-
-        let time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_secs_f64();
-        memory::Value::Number(time)
-    });
+/// Run `module` on a new VM with the standard native functions.
+pub fn execute(module: &Module) -> Result<(), RuntimeError> {
+    let mut vm = VmBuilder::new().register_native("clock", 0, clock).build();
+    vm.interpret(module.clone())
+}
 
-    vm.interpret()?;
+/// Seconds since the Unix epoch.
+pub fn clock(_vm: &mut Vm, _args: &[Value]) -> Result<Value, VmError> {
+    use std::time::{SystemTime, UNIX_EPOCH};
 
-    Ok(())
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    Ok(Value::Number(time))
 }
@@ -1,150 +1,419 @@
+use super::convert::IntoNative;
+use super::error::{RuntimeError, TraceFrame, VmError};
+use super::host::{self, HostValues};
 use super::memory::*;
-use crate::bettergc::{gc, Gc, Root, UniqueRoot};
-use crate::bytecode::{Chunk, Module};
-use std::cell::RefCell;
+use crate::bettergc::gc::{self, Context, GcConfig, GcStats};
+use crate::bettergc::{Gc, GcCell, Root, UniqueRoot};
+use crate::bytecode::{ChunkIndex, ConstantIndex, GlobalIndex, Instruction, Module};
+use lox_bytecode::verifier;
 use std::collections::HashMap;
 
 #[derive(PartialEq)]
 enum InterpretResult {
-    Done,
     More,
+    // The running frame returned, its result is on the stack.
+    Returned,
 }
 
-#[derive(Debug)]
-pub enum VmError {
-    StackEmpty,
-    FrameEmpty,
-    StringConstantExpected,
-    GlobalNotDefined,
-    InvalidCallee,
-    IncorrectArity,
-    UnexpectedConstant,
-    ClosureConstantExpected,
-    UnexpectedValue,
-    UndefinedProperty,
+struct CallFrame {
+    program_counter: usize,
+    base_counter: usize,
+    chunk_index: ChunkIndex,
+    closure: Root<Closure>,
 }
 
-struct CallFrame<'a> {
+// The parts of the running frame that every instruction needs, kept out of `frames` while
+// the frame runs. The program counter is written back before anything looks at `frames`.
+struct ActiveFrame {
     program_counter: usize,
     base_counter: usize,
-    chunk: &'a Chunk,
-    closure: Root<Closure>,
+    // The instructions of the running chunk, `module` doesn't change while the VM runs.
+    instructions: *const Instruction,
+    closure: Gc<Closure>,
+}
+
+/// Bounds on the memory a VM uses for calls, going over them is a stack overflow.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Limits {
+    /// How deep calls can be nested, the top-level code counts as a call.
+    pub max_frames: usize,
+    /// How many values fit on the stack, it is allocated up front.
+    pub stack_size: usize,
 }
 
-pub struct Vm<'a> {
-    module: &'a Module,
-    frames: Vec<CallFrame<'a>>,
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_frames: 256,
+            stack_size: 256 * 256,
+        }
+    }
+}
+
+/// A virtual machine that keeps its globals and heap between calls to `interpret`,
+/// so modules can be added to it one after another.
+pub struct Vm {
+    module: Module,
+    limits: Limits,
+    // How many stack slots a frame of each chunk in `module` needs at most.
+    stack_sizes: Vec<usize>,
+    frames: Vec<CallFrame>,
     stack: UniqueRoot<Vec<Value>>,
-    globals: UniqueRoot<HashMap<String, Value>>,
-    upvalues: Vec<Root<RefCell<Upvalue>>>,
+    // Indexed by the global slots of `module`, `None` until the global is defined.
+    globals: UniqueRoot<Vec<Option<Value>>>,
+    upvalues: Vec<Root<GcCell<Upvalue>>>,
+    // The interned copy of every string constant in `module`, by constant index.
+    constant_strings: Vec<Option<Root<String>>>,
+    init_string: Root<String>,
+    // Objects handed to the host, kept alive until the host calls into the VM again. The ones
+    // a native gets are released when it returns.
+    host_values: HostValues,
+    // Reused for the arguments of natives, so calling one doesn't allocate.
+    native_args: Vec<host::Value>,
+    // Owns every object of the VM. Fields are dropped in order, so this has to be last:
+    // the roots above are unrooted on drop.
+    gc: Context,
 }
 
-impl<'a> Vm<'a> {
-    pub fn new(module: &'a Module) -> Self {
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Vm {
+    pub fn new() -> Self {
+        Self::with_limits(Limits::default())
+    }
+
+    pub fn with_limits(limits: Limits) -> Self {
+        Self::with_config(limits, GcConfig::default())
+    }
+
+    pub(super) fn with_config(limits: Limits, config: GcConfig) -> Self {
+        let mut gc = Context::new(config);
         Vm {
-            module,
-            frames: vec![],
-            stack: gc::unique(vec![]),
-            globals: gc::unique(HashMap::new()),
+            module: Module::new(),
+            limits,
+            stack_sizes: vec![],
+            frames: Vec::with_capacity(limits.max_frames),
+            stack: gc.unique(Vec::with_capacity(limits.stack_size)),
+            globals: gc.unique(vec![]),
             upvalues: vec![],
+            constant_strings: vec![],
+            init_string: gc.intern("init"),
+            host_values: HostValues::new(&mut gc),
+            native_args: vec![],
+            gc,
         }
     }
 
-    pub fn interpret(&mut self) -> Result<(), VmError> {
-        let function = gc::manage(Function {
+    /// Add `module` to the VM and run its top-level code.
+    /// Globals defined by earlier modules stay available.
+    pub fn interpret(&mut self, module: Module) -> Result<(), RuntimeError> {
+        // Instructions are run without checking their operands or the stack,
+        // which is only safe for verified modules.
+        let stack_sizes = verifier::verify_stack_sizes(&module)
+            .map_err(|error| self.runtime_error(VmError::InvalidModule(error)))?;
+
+        let chunk_index = self.module.append(module);
+        self.stack_sizes.extend(stack_sizes);
+        self.intern_constants();
+        self.globals.resize(self.module.globals().len(), None);
+
+        // Top-level code runs in a function without a name, no Lox function can have one.
+        let function = self.gc.manage(Function {
             arity: 0,
-            chunk_index: 0,
-            name: "top".into(),
+            chunk_index,
+            name: String::new(),
         });
-        let closure = gc::manage(Closure {
+        let closure = self.gc.manage(Closure {
             upvalues: vec![],
             function: function.as_gc(),
         });
-        self.push(Value::Closure(closure.as_gc()));
+        self.run_call(0, |vm| {
+            vm.push(Value::Closure(closure.as_gc()));
+            Ok(())
+        })?;
+        Ok(())
+    }
 
-        self.frames.push(CallFrame {
-            //TODO Use begin/end_frame because it needs to do cleanup of the stack
-            program_counter: 0,
-            base_counter: 0,
-            chunk: self.module.chunk(0),
-            closure: closure,
+    /// Call the global function `name` with `args` and return its result.
+    /// This can be used from native functions while the VM is running.
+    pub fn call_function(
+        &mut self,
+        name: &str,
+        args: &[host::Value],
+    ) -> Result<host::Value, RuntimeError> {
+        let callee = match self.global_value(name) {
+            Some(callee) => callee,
+            None => return Err(self.runtime_error(VmError::GlobalNotDefined(name.to_string()))),
+        };
+        let result = self.run_call(args.len(), |vm| {
+            vm.push(callee);
+            vm.push_host_values(args)
+        })?;
+        Ok(self.host_values.handle(result, &self.gc))
+    }
+
+    /// Call `callee` with `args` like scripts do and return its result.
+    /// This can be used from native functions while the VM is running.
+    pub fn call_value(
+        &mut self,
+        callee: host::Value,
+        args: &[host::Value],
+    ) -> Result<host::Value, RuntimeError> {
+        let result = self.run_call(args.len(), |vm| {
+            vm.push_host_values(&[callee])?;
+            vm.push_host_values(args)
+        })?;
+        Ok(self.host_values.handle(result, &self.gc))
+    }
+
+    // Push a callee and `arity` arguments with `push`, and run the call to completion. On
+    // errors the stack and frames are unwound to where they were.
+    fn run_call(
+        &mut self,
+        arity: usize,
+        push: impl FnOnce(&mut Vm) -> Result<(), VmError>,
+    ) -> Result<Value, RuntimeError> {
+        let depth = self.frames.len();
+        let stack_len = self.stack.len();
+        let result = if stack_len + arity + 1 > self.limits.stack_size {
+            Err(VmError::StackOverflow)
+        } else {
+            push(self).and_then(|()| {
+                // The host is done with the objects it got from earlier calls, or passed them in.
+                if depth == 0 {
+                    self.host_values.clear();
+                }
+                self.call(arity)
+            })
+        };
+        let result = result.and_then(|()| {
+            if self.frames.len() > depth {
+                self.run(depth)
+            } else {
+                // Natives and classes without an initializer are done right away.
+                Ok(self.pop())
+            }
         });
 
-        while self.interpret_next()? == InterpretResult::More {}
+        result.map_err(|error| {
+            let error = self.runtime_error(error);
+            self.unwind(depth, stack_len);
+            error
+        })
+    }
 
+    fn push_host_values(&mut self, values: &[host::Value]) -> Result<(), VmError> {
+        for &value in values {
+            let value = self.host_values.resolve(value, &self.gc)?;
+            self.push(value);
+        }
         Ok(())
     }
 
-    pub fn set_native_fn(&mut self, identifier: &str, code: fn(&[Value]) -> Value) {
-        let native_function = NativeFunction {
-            name: identifier.to_string(),
-            code: code,
-        };
+    /// Create a Lox string. It stays alive until the host calls into the VM again, or until
+    /// the native that created it returns. Keep it in a global to hold on to it for longer.
+    pub fn string(&mut self, string: &str) -> host::Value {
+        let value = Value::String(self.gc.intern(string).as_gc());
+        self.host_values.handle(value, &self.gc)
+    }
 
-        let root = gc::manage(native_function);
-        self.globals
-            .insert(identifier.to_string(), Value::NativeFunction(root.as_gc()));
+    /// A string that isn't rooted, its handle is released by the next collection.
+    pub(super) fn intern(&mut self, string: &str) -> host::Value {
+        let value = Value::String(self.gc.intern(string).as_gc());
+        self.host_values.fresh(value, &self.gc)
     }
 
-    fn interpret_next(&mut self) -> Result<InterpretResult, VmError> {
-        use crate::bytecode::{Constant, Instruction};
+    /// The type of `value` for error messages, like "a number".
+    pub fn type_description(&self, value: host::Value) -> Result<&'static str, VmError> {
+        Ok(self.resolve(value)?.type_description())
+    }
 
-        self.current_frame_mut()?.program_counter += 1;
+    pub(super) fn resolve(&self, value: host::Value) -> Result<Value, VmError> {
+        self.host_values.resolve(value, &self.gc)
+    }
 
-        let instr = {
-            let frame = self.current_frame()?;
-            frame.chunk.instructions()[frame.program_counter - 1]
-        };
+    fn intern_constants(&mut self) {
+        use crate::bytecode::Constant;
+
+        let gc = &mut self.gc;
+        let new = &self.module.constants()[self.constant_strings.len()..];
+        let strings: Vec<_> = new
+            .iter()
+            .map(|constant| match constant {
+                Constant::String(string) => Some(gc.intern(string)),
+                _ => None,
+            })
+            .collect();
+        self.constant_strings.extend(strings);
+    }
 
-        if false {
-            // DEBUG
-            println!("stack: {:?}", self.stack);
-            println!("");
-            println!("globals: {:?}", self.globals);
-            println!("{:?}", instr);
-            println!("");
+    // Run until the frames above `depth` have returned, and return the result of the last one.
+    fn run(&mut self, depth: usize) -> Result<Value, VmError> {
+        let mut frame = self.active_frame();
+        loop {
+            match self.interpret_next(&mut frame) {
+                Ok(InterpretResult::More) => (),
+                Ok(InterpretResult::Returned) => {
+                    if self.frames.len() == depth {
+                        return Ok(self.pop());
+                    }
+                    frame = self.active_frame();
+                }
+                Err(error) => {
+                    self.store_frame(&frame);
+                    return Err(error);
+                }
+            }
         }
+    }
+
+    fn runtime_error(&self, error: VmError) -> RuntimeError {
+        let trace: Vec<TraceFrame> = self
+            .frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                let chunk = self.module.chunk(frame.chunk_index);
+                let span = chunk.span(frame.program_counter.saturating_sub(1));
+                let name = &frame.closure.function.name;
+                TraceFrame {
+                    function: if name.is_empty() {
+                        None
+                    } else {
+                        Some(name.clone())
+                    },
+                    span,
+                    line: self.module.line(frame.chunk_index, span.start),
+                }
+            })
+            .collect();
+
+        let (span, line) = trace
+            .first()
+            .map(|frame| (frame.span, frame.line))
+            .unwrap_or_default();
+
+        RuntimeError {
+            error,
+            span,
+            line,
+            trace,
+        }
+    }
+
+    // Throw away what a failed call left behind, leaving the frames and stack of its caller.
+    fn unwind(&mut self, depth: usize, stack_len: usize) {
+        self.frames.truncate(depth);
+        self.close_upvalues(stack_len);
+        self.stack.truncate(stack_len);
+    }
+
+    /// Define a global function `name` that converts its arguments and result to and from Lox
+    /// values, like `fn add(a: f64, b: f64) -> f64`. Calling it with the wrong number or types
+    /// of arguments is a runtime error.
+    pub fn register_fn<Args>(&mut self, name: &str, function: impl IntoNative<Args>) {
+        let (arity, code) = function.into_native();
+        self.define_native(NativeFunction {
+            name: name.to_string(),
+            arity,
+            code,
+        });
+    }
+
+    /// Define a global function `name` that runs `code` when scripts call it with a number of
+    /// arguments that `arity` accepts. It replaces whatever the global held before.
+    pub fn register_native<F>(&mut self, name: &str, arity: impl Into<Arity>, code: F)
+    where
+        F: Fn(&mut Vm, &[host::Value]) -> Result<host::Value, VmError> + 'static,
+    {
+        self.define_native(NativeFunction {
+            name: name.to_string(),
+            arity: arity.into(),
+            code: Box::new(code),
+        });
+    }
+
+    pub(super) fn define_native(&mut self, native: NativeFunction) {
+        let slot = self.module.add_global(&native.name);
+        let root = self.gc.manage(native);
+        self.globals.resize(self.module.globals().len(), None);
+        self.globals[slot] = Some(Value::NativeFunction(root.as_gc()));
+    }
+
+    /// The value of the global `name`, `None` if no module defined it. Like the values returned
+    /// by calls, it stays alive until the host calls into the VM again.
+    pub fn global(&mut self, name: &str) -> Option<host::Value> {
+        let value = self.global_value(name)?;
+        Some(self.host_values.handle(value, &self.gc))
+    }
+
+    fn global_value(&self, name: &str) -> Option<Value> {
+        let slot = self.module.global_index(name)?;
+        self.globals.get(slot).copied().flatten()
+    }
+
+    /// What the garbage collector of this VM did so far.
+    pub fn gc_stats(&self) -> GcStats {
+        self.gc.stats()
+    }
+
+    /// Collect all garbage now, instead of waiting for the heap to grow.
+    pub fn collect_garbage(&mut self) {
+        self.gc.force_collect();
+    }
+
+    #[inline(always)]
+    fn interpret_next(&mut self, frame: &mut ActiveFrame) -> Result<InterpretResult, VmError> {
+        use crate::bytecode::Constant;
+
+        // SAFETY: The verifier checked that jumps stay inside the chunk and that it ends in a
+        // `Return`, so the program counter never runs past the last instruction.
+        let instr = unsafe { *frame.instructions.add(frame.program_counter) };
+        frame.program_counter += 1;
 
         match instr {
             Instruction::Constant(index) => match self.module.constant(index) {
                 Constant::Number(n) => self.push(Value::Number(*n)),
-                Constant::String(string) => self.push_string(string),
-                Constant::Class(_) => unimplemented!(),
-                Constant::Closure(_) => unimplemented!(),
+                Constant::String(_) => {
+                    let string = self.string_constant(index)?;
+                    self.push(Value::String(string));
+                }
+                Constant::Class(_) | Constant::Closure(_) => {
+                    return Err(VmError::UnexpectedConstant)
+                }
             },
             Instruction::Closure(index) => {
                 if let Constant::Closure(closure) = self.module.constant(index) {
-                    let upvalues = closure
-                        .upvalues
+                    let function = Function::from(&closure.function);
+                    let upvalues = closure.upvalues.clone();
+                    let upvalues = upvalues
                         .iter()
-                        .map(|u| {
-                            match u {
-                                crate::bytecode::Upvalue::Local(index) => {
-                                    let frame = &self.frames[self.frames.len() - 1]; //TODO Result // Get the enclosing frame
-                                    let base = frame.base_counter;
-                                    let index = base + *index;
-
-                                    if let Some(upvalue) = self.find_open_upvalue_with_index(index)
-                                    {
-                                        upvalue
-                                    } else {
-                                        let root = gc::manage(RefCell::new(Upvalue::Open(index)));
-                                        self.upvalues.push(root.clone());
-                                        root.as_gc()
-                                    }
-                                }
-                                crate::bytecode::Upvalue::Upvalue(u) => {
-                                    self.find_upvalue_by_index(*u)
+                        .map(|u| match u {
+                            crate::bytecode::Upvalue::Local(index) => {
+                                let index = frame.base_counter + *index;
+
+                                if let Some(upvalue) = self.find_open_upvalue_with_index(index) {
+                                    Ok(upvalue)
+                                } else {
+                                    let root =
+                                        self.gc.try_manage(GcCell::new(Upvalue::Open(index)))?;
+                                    self.upvalues.push(root.clone());
+                                    Ok(root.as_gc())
                                 }
                             }
+                            crate::bytecode::Upvalue::Upvalue(u) => Ok(frame.closure.upvalues[*u]),
                         })
-                        .collect();
+                        .collect::<Result<_, VmError>>()?;
 
-                    let function_root = gc::manage(Function::from(&closure.function));
-                    let closure_root = gc::manage(Closure {
+                    let function_root = self.gc.try_manage(function)?;
+                    let closure_root = self.gc.try_manage(Closure {
                         function: function_root.as_gc(),
-                        upvalues: upvalues,
-                    });
+                        upvalues,
+                    })?;
                     self.push(Value::Closure(closure_root.as_gc()));
                 } else {
                     return Err(VmError::ClosureConstantExpected);
@@ -152,221 +421,221 @@ impl<'a> Vm<'a> {
             }
             Instruction::Class(index) => {
                 if let Constant::Class(class) = self.module.constant(index) {
-                    let class = gc::manage(RefCell::new(Class {
+                    let class = self.gc.try_manage(GcCell::new(Class {
                         name: class.name.clone(),
-                    }));
+                        methods: HashMap::new(),
+                    }))?;
                     self.push(Value::Class(class.as_gc()));
                 } else {
                     return Err(VmError::UnexpectedConstant);
                 }
             }
+            Instruction::Method(index) => {
+                let identifier = self.string_constant(index)?;
+                let method = match self.peek() {
+                    Value::Closure(closure) => closure,
+                    _ => return Err(VmError::UnexpectedValue),
+                };
+                if let Value::Class(class) = self.peek_n(1) {
+                    class
+                        .borrow_mut(&mut self.gc)
+                        .methods
+                        .insert(identifier, method);
+                    self.pop();
+                } else {
+                    return Err(VmError::UnexpectedValue);
+                }
+            }
+            Instruction::Inherit => {
+                if let (Value::Class(class), Value::Class(superclass)) =
+                    (self.peek(), self.peek_n(1))
+                {
+                    let methods = superclass.borrow().methods.clone();
+                    class.borrow_mut(&mut self.gc).methods.extend(methods);
+                    self.pop();
+                } else {
+                    return Err(VmError::InvalidSuperclass);
+                }
+            }
+            Instruction::GetSuper(index) => {
+                let identifier = self.string_constant(index)?;
+                if let Value::Class(superclass) = self.peek() {
+                    let receiver = self.peek_n(1);
+                    let value = self.bind_method(superclass, receiver, identifier)?;
+                    self.pop();
+                    self.pop();
+                    self.push(value);
+                } else {
+                    return Err(VmError::UnexpectedValue);
+                }
+            }
             Instruction::SetProperty(index) => {
-                if let Constant::String(property) = self.module.constant(index) {
-                    if let Value::Instance(instance) = self.peek_n(1)? {
-                        instance
-                            .borrow_mut()
-                            .fields
-                            .insert(property.clone(), *self.peek()?);
-
-                        let value = self.pop()?;
-                        self.pop()?;
-                        self.push(value);
-                    } else {
-                        return Err(VmError::UnexpectedValue);
-                    }
+                let property = self.string_constant(index)?;
+                if let Value::Instance(instance) = self.peek_n(1) {
+                    instance
+                        .borrow_mut(&mut self.gc)
+                        .fields
+                        .insert(property, self.peek());
+
+                    let value = self.pop();
+                    self.pop();
+                    self.push(value);
                 } else {
-                    return Err(VmError::UnexpectedConstant);
+                    return Err(VmError::ExpectedInstance);
                 }
             }
             Instruction::GetProperty(index) => {
-                if let Constant::String(property) = self.module.constant(index) {
-                    if let Value::Instance(instance) = self.pop()? {
-                        let instance = gc::root(instance);
-                        if let Some(value) = instance.borrow().fields.get(property) {
-                            self.push(*value);
-                        } else {
-                            return Err(VmError::UndefinedProperty);
-                        };
-                    }
+                let property = self.string_constant(index)?;
+                if let Value::Instance(instance) = self.peek() {
+                    let value = instance.borrow().fields.get(&property).cloned();
+                    let value = match value {
+                        Some(value) => value,
+                        None => {
+                            let class = instance.borrow().class;
+                            self.bind_method(class, Value::Instance(instance), property)?
+                        }
+                    };
+                    self.pop();
+                    self.push(value);
+                } else {
+                    return Err(VmError::ExpectedInstance);
                 }
             }
-            Instruction::Print => match self.pop()? {
-                Value::Number(n) => println!("{}", n),
-                Value::Nil => println!("nil"),
-                Value::Boolean(boolean) => println!("{}", boolean),
-                Value::String(string) => println!("{}", string),
-                Value::NativeFunction(function) => println!("<native fun {}>", function.name),
-                Value::Closure(closure) => println!(
-                    "<fun {}({}) @ {}>",
-                    closure.function.name, closure.function.arity, closure.function.chunk_index
-                ),
-                Value::Class(class) => println!("{}", class.borrow().name),
-                Value::Instance(instance) => {
-                    println!("{} instance", instance.borrow().class.borrow().name)
-                }
-            },
+            Instruction::Print => println!("{}", self.pop()),
             Instruction::Nil => self.push(Value::Nil),
             Instruction::Return => {
-                let result = self.pop()?;
-                let frame = self.frames.pop().ok_or(VmError::FrameEmpty)?;
-
-                for i in frame.base_counter..self.stack.len() {
-                    self.close_upvalues(i);
-                }
-
-                self.stack.split_off(frame.base_counter);
-
-                if self.frames.len() == 0 {
-                    // We are done interpreting, don't push a result as it'll be nil
-                    return Ok(InterpretResult::Done);
-                }
-
+                let result = self.pop();
+                self.frames.pop();
+                self.close_upvalues(frame.base_counter);
+                self.stack.truncate(frame.base_counter);
                 self.push(result);
+                return Ok(InterpretResult::Returned);
             }
-            Instruction::Add => match (self.pop()?, self.pop()?) {
+            Instruction::Add => match (self.pop(), self.pop()) {
                 (Value::Number(b), Value::Number(a)) => self.push(Value::Number(a + b)),
-                (Value::String(b), Value::String(a)) => self.push_string(&format!("{}{}", a, b)),
-                (b, a) => unimplemented!("{:?} + {:?}", a, b),
+                (Value::String(b), Value::String(a)) => self.push_string(&format!("{}{}", a, b))?,
+                _ => return Err(VmError::ExpectedNumberOrStringOperands),
             },
-            Instruction::Subtract => match (self.pop()?, self.pop()?) {
+            Instruction::Subtract => match (self.pop(), self.pop()) {
                 (Value::Number(b), Value::Number(a)) => self.push(Value::Number(a - b)),
-                (b, a) => unimplemented!("{:?} - {:?}", a, b),
+                _ => return Err(VmError::ExpectedNumberOperands),
             },
-            Instruction::Multiply => match (self.pop()?, self.pop()?) {
+            Instruction::Multiply => match (self.pop(), self.pop()) {
                 (Value::Number(b), Value::Number(a)) => self.push(Value::Number(a * b)),
-                (b, a) => unimplemented!("{:?} * {:?}", a, b),
+                _ => return Err(VmError::ExpectedNumberOperands),
             },
-            Instruction::Divide => match (self.pop()?, self.pop()?) {
+            Instruction::Divide => match (self.pop(), self.pop()) {
                 (Value::Number(b), Value::Number(a)) => self.push(Value::Number(a / b)),
-                (b, a) => unimplemented!("{:?} / {:?}", a, b),
+                _ => return Err(VmError::ExpectedNumberOperands),
             },
             Instruction::Pop => {
-                self.pop()?;
+                self.pop();
             }
-            Instruction::DefineGlobal(index) => {
-                if let Constant::String(identifier) = self.module.constant(index) {
-                    let value = self.pop()?;
-                    self.globals.insert(identifier.to_string(), value);
-                } else {
-                    return Err(VmError::StringConstantExpected);
-                }
+            Instruction::DefineGlobal(slot) => {
+                let value = self.pop();
+                self.globals[slot] = Some(value);
             }
-            Instruction::GetGlobal(index) => {
-                if let Constant::String(identifier) = self.module.constant(index) {
-                    let value = self.globals.get(identifier).cloned();
-                    if let Some(value) = value {
-                        self.push(value);
-                    } else {
-                        return Err(VmError::GlobalNotDefined);
-                    }
+            Instruction::GetGlobal(slot) => {
+                if let Some(value) = self.globals[slot] {
+                    self.push(value);
                 } else {
-                    return Err(VmError::StringConstantExpected);
+                    return Err(self.undefined_global(slot));
                 }
             }
-            Instruction::SetGlobal(index) => {
-                if let Constant::String(identifier) = self.module.constant(index) {
-                    let value = *self.peek()?;
-                    if self.globals.contains_key(identifier) {
-                        self.globals.insert(identifier.to_string(), value);
-                    } else {
-                        return Err(VmError::GlobalNotDefined);
-                    }
+            Instruction::SetGlobal(slot) => {
+                let value = self.peek();
+                if let Some(global) = &mut self.globals[slot] {
+                    *global = value;
                 } else {
-                    return Err(VmError::StringConstantExpected);
+                    return Err(self.undefined_global(slot));
                 }
             }
             Instruction::GetLocal(index) => {
-                let index = self.current_frame()?.base_counter + index;
-                self.push(self.stack[index]);
+                let value = self.local(frame.base_counter + index);
+                self.push(value);
             }
             Instruction::SetLocal(index) => {
-                let index = self.current_frame()?.base_counter + index;
-                let value = *self.peek()?;
-                self.stack[index] = value;
+                let value = self.peek();
+                self.set_local(frame.base_counter + index, value);
             }
             Instruction::True => self.push(Value::Boolean(true)),
             Instruction::False => self.push(Value::Boolean(false)),
             Instruction::JumpIfFalse(to) => {
-                if self.peek()?.is_falsey() {
-                    self.current_frame_mut()?.program_counter = to;
+                if self.peek().is_falsey() {
+                    frame.program_counter = to;
+                }
+            }
+            Instruction::JumpIfTrue(to) => {
+                if !self.peek().is_falsey() {
+                    frame.program_counter = to;
                 }
             }
             Instruction::Jump(to) => {
-                self.current_frame_mut()?.program_counter = to;
+                frame.program_counter = to;
             }
-            Instruction::Less => match (self.pop()?, self.pop()?) {
+            Instruction::Less => match (self.pop(), self.pop()) {
                 (Value::Number(b), Value::Number(a)) => self.push((a < b).into()),
-                (b, a) => unimplemented!("{:?} < {:?}", a, b),
+                _ => return Err(VmError::ExpectedNumberOperands),
             },
-            Instruction::Greater => match (self.pop()?, self.pop()?) {
+            Instruction::Greater => match (self.pop(), self.pop()) {
                 (Value::Number(b), Value::Number(a)) => self.push((a > b).into()),
-                (b, a) => unimplemented!("{:?} > {:?}", a, b),
+                _ => return Err(VmError::ExpectedNumberOperands),
             },
             Instruction::Equal => {
-                let b = self.pop()?;
-                let a = self.pop()?;
-
-                if Value::is_same_type(&a, &b) {
-                    match (b, a) {
-                        (Value::Number(b), Value::Number(a)) => self.push((a == b).into()),
-                        (Value::Boolean(b), Value::Boolean(a)) => self.push((a == b).into()),
-                        (Value::String(b), Value::String(a)) => self.push((*a == *b).into()),
-                        (Value::Closure(_), Value::Closure(_)) => unimplemented!(),
-                        (Value::NativeFunction(_), Value::NativeFunction(_)) => unimplemented!(),
-                        (Value::Nil, Value::Nil) => self.push(true.into()),
-                        _ => (),
-                    };
-                } else {
-                    self.push(false.into())
-                }
+                let b = self.pop();
+                let a = self.pop();
+                self.push((a == b).into());
             }
             Instruction::Call(arity) => {
+                self.store_frame(frame);
                 self.call(arity)?;
+                *frame = self.active_frame();
             }
-            Instruction::Negate => match self.pop()? {
+            Instruction::Negate => match self.pop() {
                 Value::Number(n) => self.push(Value::Number(-n)),
-                x => unimplemented!("{:?}", x),
+                _ => return Err(VmError::ExpectedNumberOperand),
             },
             Instruction::Not => {
-                let is_falsey = self.pop()?.is_falsey();
+                let is_falsey = self.pop().is_falsey();
                 self.push(is_falsey.into());
             }
             Instruction::GetUpvalue(index) => {
-                let upvalue = self.current_frame()?.closure.upvalues[index];
-                self.push(self.resolve_upvalue_into_value(&*upvalue.borrow()));
+                let upvalue = frame.closure.upvalues[index];
+                self.push(self.resolve_upvalue_into_value(&upvalue.borrow()));
             }
             Instruction::SetUpvalue(index) => {
-                let value = *self.peek()?;
-                let upvalue = self.current_frame()?.closure.upvalues[index];
-                self.set_upvalue(&mut *upvalue.borrow_mut(), value);
+                let value = self.peek();
+                let upvalue = frame.closure.upvalues[index];
+                let mut upvalue = upvalue.borrow_mut(&mut self.gc);
+                self.set_upvalue(&mut upvalue, value);
             }
             Instruction::CloseUpvalue => {
                 let index = self.stack.len() - 1;
                 self.close_upvalues(index);
-                self.stack.pop().ok_or(VmError::StackEmpty)?;
+                self.pop();
             }
         }
 
         Ok(InterpretResult::More)
     }
 
-    fn close_upvalues(&mut self, index: usize) {
-        let value = self.stack[index]; //TODO Result
-        for root in &self.upvalues {
-            if root.borrow().is_open_with_index(index) {
-                root.replace(Upvalue::Closed(value));
+    // Close every open upvalue that points at `start` or above on the stack.
+    fn close_upvalues(&mut self, start: usize) {
+        let stack = &self.stack;
+        let gc = &mut self.gc;
+        self.upvalues.retain(|root| {
+            let upvalue = *root.borrow();
+            match upvalue {
+                Upvalue::Open(index) if index >= start => {
+                    *root.borrow_mut(gc) = Upvalue::Closed(stack[index]);
+                    false
+                }
+                _ => true,
             }
-        }
-
-        self.upvalues.retain(|u| u.borrow().is_open());
-    }
-
-    fn find_upvalue_by_index(&self, index: usize) -> Gc<RefCell<Upvalue>> {
-        let frame = &self.frames[self.frames.len() - 1]; //TODO Result
-        frame.closure.upvalues[index]
+        });
     }
 
-    fn find_open_upvalue_with_index(&self, index: usize) -> Option<Gc<RefCell<Upvalue>>> {
+    fn find_open_upvalue_with_index(&self, index: usize) -> Option<Gc<GcCell<Upvalue>>> {
         for root in self.upvalues.iter().rev() {
             if root.borrow().is_open_with_index(index) {
                 return Some(root.as_gc());
@@ -391,32 +660,57 @@ impl<'a> Vm<'a> {
     }
 
     fn call(&mut self, arity: usize) -> Result<(), VmError> {
-        let callee = *self.peek_n(arity)?;
+        let callee = self.peek_n(arity);
         match callee {
             Value::Closure(callee) => {
-                if callee.function.arity != arity {
-                    return Err(VmError::IncorrectArity);
-                }
-                self.begin_frame(callee);
+                self.call_closure(callee, arity)?;
+            }
+            Value::BoundMethod(bound) => {
+                let index = self.stack.len() - arity - 1;
+                self.stack[index] = bound.receiver;
+                self.call_closure(bound.method, arity)?;
             }
             Value::NativeFunction(callee) => {
-                let mut args = self.pop_n(arity)?;
-                args.reverse();
-                self.pop()?; // discard callee
-                let result = (callee.code)(&args);
+                if !callee.arity.accepts(arity) {
+                    return Err(VmError::IncorrectArity(callee.arity, arity));
+                }
+                let args = self.stack.len() - arity;
+                // The arguments stay on the stack so they are kept alive while the native
+                // runs, it can run more code on the VM. A native that calls another one
+                // leaves it without the buffer, that call allocates its own.
+                let mut arguments = std::mem::take(&mut self.native_args);
+                arguments.clear();
+                // Objects the native got from the VM only need to live until it returns.
+                let host_values = self.host_values.len();
+                for &value in &self.stack[args..] {
+                    arguments.push(self.host_values.handle(value, &self.gc));
+                }
+                let result = (callee.code)(self, &arguments);
+                self.native_args = arguments;
+                let result = result.and_then(|value| self.resolve(value));
+                self.host_values.truncate(host_values);
+                let result = result?;
+                self.stack.truncate(args - 1); // discard the arguments and the callee
                 self.push(result);
             }
             Value::Class(class) => {
-                if arity > 0 {
-                    unimplemented!("Calling a class with arguments is not yet supported");
-                }
-                self.pop()?; //TODO Temporary, remove when arguments are supported
-
-                let instance = gc::manage(RefCell::new(Instance {
+                let instance = self.gc.try_manage(GcCell::new(Instance {
                     class,
                     fields: HashMap::new(),
-                }));
-                self.push(Value::Instance(instance.as_gc()));
+                }))?;
+                let index = self.stack.len() - arity - 1;
+                self.stack[index] = Value::Instance(instance.as_gc());
+
+                let initializer = class
+                    .borrow()
+                    .methods
+                    .get(&self.init_string.as_gc())
+                    .cloned();
+                if let Some(initializer) = initializer {
+                    self.call_closure(initializer, arity)?;
+                } else if arity != 0 {
+                    return Err(VmError::IncorrectArity(Arity::exactly(0), arity));
+                }
             }
             _ => return Err(VmError::InvalidCallee),
         }
@@ -424,52 +718,297 @@ impl<'a> Vm<'a> {
         Ok(())
     }
 
-    fn current_frame(&self) -> Result<&CallFrame, VmError> {
-        self.frames.last().ok_or(VmError::FrameEmpty)
+    fn call_closure(&mut self, closure: Gc<Closure>, arity: usize) -> Result<(), VmError> {
+        if closure.function.arity != arity {
+            let expected = Arity::exactly(closure.function.arity);
+            return Err(VmError::IncorrectArity(expected, arity));
+        }
+        self.begin_frame(closure)
+    }
+
+    fn bind_method(
+        &mut self,
+        class: Gc<GcCell<Class>>,
+        receiver: Value,
+        name: Gc<String>,
+    ) -> Result<Value, VmError> {
+        let method = class.borrow().methods.get(&name).cloned();
+        if let Some(method) = method {
+            let bound = self.gc.try_manage(BoundMethod { receiver, method })?;
+            Ok(Value::BoundMethod(bound.as_gc()))
+        } else {
+            Err(VmError::UndefinedProperty(name.to_string()))
+        }
     }
 
-    fn current_frame_mut(&mut self) -> Result<&mut CallFrame<'a>, VmError> {
-        self.frames.last_mut().ok_or(VmError::FrameEmpty)
+    fn active_frame(&self) -> ActiveFrame {
+        let frame = self.frames.last().expect("no frame");
+        ActiveFrame {
+            program_counter: frame.program_counter,
+            base_counter: frame.base_counter,
+            instructions: self.module.chunk(frame.chunk_index).instructions().as_ptr(),
+            closure: frame.closure.as_gc(),
+        }
+    }
+
+    fn store_frame(&mut self, frame: &ActiveFrame) {
+        if let Some(current) = self.frames.last_mut() {
+            current.program_counter = frame.program_counter;
+        }
     }
 
     fn push(&mut self, value: Value) {
         self.stack.push(value)
     }
 
-    fn push_string(&mut self, string: &str) {
-        let root = gc::manage(string.to_string());
+    fn push_string(&mut self, string: &str) -> Result<(), VmError> {
+        let root = self.gc.try_intern(string)?;
         self.push(Value::String(root.as_gc()));
+        Ok(())
+    }
+
+    fn undefined_global(&self, slot: GlobalIndex) -> VmError {
+        VmError::GlobalNotDefined(self.module.globals()[slot].clone())
     }
 
-    fn pop(&mut self) -> Result<Value, VmError> {
-        self.stack.pop().ok_or(VmError::StackEmpty)
+    fn string_constant(&self, index: ConstantIndex) -> Result<Gc<String>, VmError> {
+        self.constant_strings
+            .get(index)
+            .and_then(|string| string.as_ref())
+            .map(|string| string.as_gc())
+            .ok_or(VmError::StringConstantExpected)
     }
 
-    fn pop_n(&mut self, n: usize) -> Result<Vec<Value>, VmError> {
-        let mut result = vec![];
-        while result.len() < n {
-            result.push(self.pop()?);
+    // The stack accessors below don't check their bounds. The verifier checked that every
+    // instruction only uses values that its frame pushed, and frames only use the stack
+    // above their own base.
+
+    fn pop(&mut self) -> Value {
+        debug_assert!(!self.stack.is_empty());
+        unsafe {
+            let len = self.stack.len() - 1;
+            self.stack.set_len(len);
+            *self.stack.as_ptr().add(len)
         }
+    }
 
-        Ok(result)
+    fn peek(&self) -> Value {
+        self.peek_n(0)
     }
 
-    fn peek(&self) -> Result<&Value, VmError> {
-        self.stack.last().ok_or(VmError::StackEmpty)
+    fn peek_n(&self, n: usize) -> Value {
+        debug_assert!(n < self.stack.len());
+        unsafe { *self.stack.get_unchecked(self.stack.len() - n - 1) }
     }
 
-    fn peek_n(&self, n: usize) -> Result<&Value, VmError> {
-        self.stack
-            .get(self.stack.len() - n - 1)
-            .ok_or(VmError::StackEmpty)
+    fn local(&self, index: usize) -> Value {
+        debug_assert!(index < self.stack.len());
+        unsafe { *self.stack.get_unchecked(index) }
     }
 
-    fn begin_frame(&mut self, closure: Gc<Closure>) {
+    fn set_local(&mut self, index: usize, value: Value) {
+        debug_assert!(index < self.stack.len());
+        unsafe { *self.stack.get_unchecked_mut(index) = value }
+    }
+
+    // The stack is never grown, so check that the whole frame fits before running it.
+    fn begin_frame(&mut self, closure: Gc<Closure>) -> Result<(), VmError> {
+        let base_counter = self.stack.len() - closure.function.arity - 1;
+        let chunk_index = closure.function.chunk_index;
+        if self.frames.len() == self.limits.max_frames
+            || base_counter + self.stack_sizes[chunk_index] > self.limits.stack_size
+        {
+            return Err(VmError::StackOverflow);
+        }
+
         self.frames.push(CallFrame {
             program_counter: 0,
-            base_counter: self.stack.len() - closure.function.arity - 1,
-            chunk: self.module.chunk(closure.function.chunk_index),
+            base_counter,
+            chunk_index,
             closure: gc::root(closure),
         });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bettervm::Value;
+    use crate::bytecode::{Instruction, Span};
+
+    #[test]
+    fn test_unverified_module() {
+        let mut module = Module::new();
+        let chunk = module.add_chunk();
+        module
+            .chunk_mut(chunk)
+            .add_instruction(Instruction::Pop, Span::default());
+        module
+            .chunk_mut(chunk)
+            .add_instruction(Instruction::Return, Span::default());
+
+        let error = Vm::new().interpret(module).unwrap_err();
+        assert!(matches!(error.error, VmError::InvalidModule(_)));
+        assert!(error.trace.is_empty());
+    }
+
+    // fun f() { f(); } f();
+    fn infinite_recursion() -> Module {
+        use crate::bytecode::{Closure, Constant, Function};
+        use Instruction::*;
+
+        let mut module = Module::new();
+        let top = module.add_chunk();
+        let function = module.add_chunk();
+        let f = module.add_global("f");
+        let closure = module.add_constant(Constant::Closure(Closure {
+            function: Function {
+                name: "f".into(),
+                chunk_index: function,
+                arity: 0,
+            },
+            upvalues: vec![],
+        }));
+        let top_instructions = [
+            Closure(closure),
+            DefineGlobal(f),
+            GetGlobal(f),
+            Call(0),
+            Pop,
+            Nil,
+            Return,
+        ];
+        for &instruction in top_instructions.iter() {
+            module
+                .chunk_mut(top)
+                .add_instruction(instruction, Span::default());
+        }
+        for &instruction in [GetGlobal(f), Call(0), Pop, Nil, Return].iter() {
+            module
+                .chunk_mut(function)
+                .add_instruction(instruction, Span::default());
+        }
+        module
+    }
+
+    #[test]
+    fn test_max_frames() {
+        let mut vm = Vm::with_limits(Limits {
+            max_frames: 16,
+            ..Limits::default()
+        });
+        let error = vm.interpret(infinite_recursion()).unwrap_err();
+        assert_eq!(error.error, VmError::StackOverflow);
+        assert_eq!(error.trace.len(), 16);
+        assert_eq!(error.trace[0].function.as_deref(), Some("f"));
+        assert_eq!(error.trace[15].function, None);
+    }
+
+    #[test]
+    fn test_stack_size() {
+        let mut vm = Vm::with_limits(Limits {
+            max_frames: 1000,
+            stack_size: 50,
+        });
+        let error = vm.interpret(infinite_recursion()).unwrap_err();
+        assert_eq!(error.error, VmError::StackOverflow);
+        // Every frame of `f` starts one slot above the previous one and needs two.
+        assert_eq!(error.trace.len(), 49);
+        assert_eq!(vm.stack.capacity(), 50);
+    }
+
+    // var result = name(1, 2);
+    fn call_native(name: &str, arguments: usize) -> Module {
+        use crate::bytecode::Constant;
+        use Instruction::*;
+
+        let mut module = Module::new();
+        let top = module.add_chunk();
+        let native = module.add_global(name);
+        let result = module.add_global("result");
+        let one = module.add_constant(Constant::Number(1.0));
+        let two = module.add_constant(Constant::Number(2.0));
+        let mut instructions = vec![GetGlobal(native)];
+        instructions.extend([Constant(one), Constant(two)].iter().take(arguments));
+        instructions.extend(&[Call(arguments), DefineGlobal(result), Nil, Return]);
+        for instruction in instructions {
+            module
+                .chunk_mut(top)
+                .add_instruction(instruction, Span::default());
+        }
+        module
+    }
+
+    #[test]
+    fn test_register_native() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        let calls = Rc::new(Cell::new(0));
+        let counter = calls.clone();
+        let mut vm = crate::bettervm::VmBuilder::new()
+            .register_native("add", 2, move |_vm, args| {
+                counter.set(counter.get() + 1);
+                match args {
+                    [Value::Number(a), Value::Number(b)] => Ok(Value::Number(a + b)),
+                    _ => Ok(Value::Nil),
+                }
+            })
+            .build();
+
+        assert_eq!(vm.global("result"), None);
+        vm.interpret(call_native("add", 2)).unwrap();
+        assert_eq!(vm.global("result"), Some(Value::Number(3.0)));
+        vm.interpret(call_native("add", 2)).unwrap();
+        assert_eq!(calls.get(), 2);
+
+        let error = vm.interpret(call_native("add", 1)).unwrap_err();
+        assert_eq!(error.error, VmError::IncorrectArity(Arity::exactly(2), 1));
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[test]
+    fn test_variadic_native() {
+        let mut vm = crate::bettervm::VmBuilder::new()
+            .register_native("count", 1.., |_vm, args| {
+                Ok(Value::Number(args.len() as f64))
+            })
+            .build();
+
+        vm.interpret(call_native("count", 2)).unwrap();
+        assert_eq!(vm.global("result"), Some(Value::Number(2.0)));
+        vm.interpret(call_native("count", 1)).unwrap();
+        assert_eq!(vm.global("result"), Some(Value::Number(1.0)));
+
+        let error = vm.interpret(call_native("count", 0)).unwrap_err();
+        assert_eq!(error.error, VmError::IncorrectArity(Arity::at_least(1), 0));
+        assert_eq!(
+            error.error.to_string(),
+            "Expected at least 1 arguments but got 0."
+        );
+        assert_eq!(
+            VmError::IncorrectArity(Arity::from(1..=3), 5).to_string(),
+            "Expected 1 to 3 arguments but got 5."
+        );
+    }
+
+    #[test]
+    fn test_native_error() {
+        let mut vm = crate::bettervm::VmBuilder::new()
+            .register_native("fail", 0, |_vm, _args| {
+                Err(VmError::Native("Something went wrong.".into()))
+            })
+            .register_native("ok", 0, |_vm, _args| Ok(Value::Nil))
+            .build();
+
+        let error = vm.interpret(call_native("fail", 0)).unwrap_err();
+        assert_eq!(error.error, VmError::Native("Something went wrong.".into()));
+        assert_eq!(error.trace.len(), 1);
+        assert_eq!(error.trace[0].function, None);
+
+        // The VM is reset and can keep running.
+        vm.interpret(call_native("ok", 0)).unwrap();
+        assert_eq!(vm.global("result"), Some(Value::Nil));
     }
 }
@@ -0,0 +1,92 @@
+//! Measures how long the program stops for garbage collection with a large heap.
+//!
+//! A binary tree of a million instances is kept alive while a loop allocates short-lived
+//! instances, the time every iteration takes is recorded by a native function. Iterations
+//! that run a collection stand out as the slow ones.
+//!
+//! `cargo bench -p lox --bench gc` runs only this benchmark.
+
+use lox_vm::bettervm::{self, VmBuilder};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+// The tree has `2^DEPTH - 1` nodes.
+const DEPTH: u32 = 20;
+const ITERATIONS: usize = 2_000_000;
+
+fn main() {
+    let filter = std::env::args()
+        .skip(1)
+        .find(|arg| !arg.starts_with('-'))
+        .unwrap_or_default();
+    if !"gc".contains(&filter) {
+        return;
+    }
+
+    let source = format!(
+        "class Node {{ init(left, right) {{ this.left = left; this.right = right; }} }}
+         fun tree(depth) {{
+           if (depth == 0) return nil;
+           return Node(tree(depth - 1), tree(depth - 1));
+         }}
+         var root = tree({});
+         built();
+         for (var i = 0; i < {}; i = i + 1) {{
+           tick();
+           Node(nil, nil);
+         }}",
+        DEPTH, ITERATIONS
+    );
+    let module = lox_compiler::compile(&source).expect("benchmark compiles");
+
+    let start = Instant::now();
+    let built = Rc::new(RefCell::new(Duration::ZERO));
+    let ticks = Rc::new(RefCell::new(Vec::with_capacity(ITERATIONS)));
+    let last = Rc::new(RefCell::new(Instant::now()));
+    let (built_at, last_tick) = (built.clone(), last.clone());
+    let recorded = ticks.clone();
+    let mut vm = VmBuilder::new()
+        .register_native("clock", 0, bettervm::clock)
+        .register_fn("built", move || {
+            *built_at.borrow_mut() = start.elapsed();
+            *last_tick.borrow_mut() = Instant::now();
+        })
+        .register_fn("tick", move || {
+            let now = Instant::now();
+            let previous = last.replace(now);
+            recorded.borrow_mut().push(now - previous);
+        })
+        .build();
+    vm.interpret(module).expect("benchmark runs");
+    let total = start.elapsed();
+
+    let mut ticks = ticks.take();
+    ticks.sort();
+    let percentile = |p: f64| ticks[((ticks.len() - 1) as f64 * p) as usize];
+    let mean = ticks.iter().sum::<Duration>() / ticks.len() as u32;
+    let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+
+    println!();
+    println!(
+        "{} live objects, {} iterations",
+        2usize.pow(DEPTH) - 1,
+        ITERATIONS
+    );
+    println!("{:<20} {:>9.1}ms", "build heap", ms(*built.borrow()));
+    println!("{:<20} {:>9.1}ms", "total", ms(total));
+    println!("{:<20} {:>9.4}ms", "mean iteration", ms(mean));
+    println!(
+        "{:<20} {:>9.4}ms",
+        "99.9th percentile",
+        ms(percentile(0.999))
+    );
+    println!("{:<20} {:>9.1}ms", "worst iteration", ms(percentile(1.0)));
+
+    let stats = vm.gc_stats();
+    println!(
+        "{:<20} {:>9} minor, {} major",
+        "collections", stats.minor_collections, stats.major_collections
+    );
+    println!("{:<20} {:>9.1}ms", "pause time", ms(stats.pause_time));
+}
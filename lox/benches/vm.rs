@@ -0,0 +1,57 @@
+//! Runs the Lox programs in `benches/lox` and reports the fastest of a few runs. Besides
+//! `clock`, the programs can call the native `add(a, b)` to measure calls of natives.
+//!
+//! `cargo bench -p lox` runs all of them, `cargo bench -p lox -- fib` only those whose
+//! name contains `fib`.
+
+use lox_vm::bettervm::{self, VmBuilder};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+const RUNS: usize = 10;
+
+fn main() {
+    let filter = std::env::args()
+        .skip(1)
+        .find(|arg| !arg.starts_with('-'))
+        .unwrap_or_default();
+
+    let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("benches/lox");
+    let mut paths: Vec<_> = std::fs::read_dir(&directory)
+        .expect("benchmark directory")
+        .map(|entry| entry.expect("benchmark file").path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "lox"))
+        .collect();
+    paths.sort();
+
+    let mut results = vec![];
+    for path in paths {
+        let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+        if !name.contains(&filter) {
+            continue;
+        }
+
+        let source = std::fs::read_to_string(&path).expect("benchmark source");
+        let module = lox_compiler::compile(&source).expect("benchmark compiles");
+        let best = (0..RUNS)
+            .map(|_| {
+                let mut vm = VmBuilder::new()
+                    .register_native("clock", 0, bettervm::clock)
+                    .register_fn("add", |a: f64, b: f64| a + b)
+                    .build();
+                let module = module.clone();
+                let start = Instant::now();
+                vm.interpret(module).expect("benchmark runs");
+                start.elapsed()
+            })
+            .min()
+            .unwrap_or(Duration::ZERO);
+        results.push((name, best));
+    }
+
+    println!();
+    println!("{:<12} {:>10}", "benchmark", format!("best of {}", RUNS));
+    for (name, best) in results {
+        println!("{:<12} {:>8.1}ms", name, best.as_secs_f64() * 1000.0);
+    }
+}
@@ -1,76 +1,203 @@
-fn main() {
-    // let data = "print 3;";
-    // let data = "print 1 + 2;1+2;print 3;";
-    // let data = "print 1+2*5+12;print 3;print 2+3;";
-    // let data = "var x=1+3;x=x+2;print x;";
-    // let data = "var x = \"Hi!\";print \"Hello, World!\";x=3;";
-    // let data = "var a = \"He\"; var b = \"llo\";print a+b;";
-    // let data = "print \"He\"+\"llo\";";
-    // let data = "var x=93;{var x=123; {var y=3; var x=4; {print x;x=6;}print x+3;} print x;}print x;";
-    // let data ="print 12+8-3;print 12.3;";
-    // let data = "var x = 3;";
-    // let data = "var x=2; {var x=3; { var x=4; print x; } print x;} print x;";
-    // let data = "{var x=2;{ x=3;} print x;}";
-    // let data ="{var x=2; x=3;print x;}";
-    // let data = "{var x;} {var x; var y;} {var w; {var x; var y;} var z;}";
-    // let data ="{var x=1;{}var y=2;{var z=3;}var a=4;}";
-    // let data = "if(true) print 3; else print 4; print 5;";
-    // let data = "if(false) print 3; else print 4; print 5;";
-    // let data = "var i =0; while(i < 1000) { print i; i = i + 1; }";
-    // let data = "for(var i =0; i < 1000; i = i + 1) print i;";
-    // let data = "fun first() { print 3; } print first;";
-    // let data = "fun first() { print 3; } first();";
-    // let data = "fun first(a) { print a; } first(3);";
-    // let data = "fun first(a) { print a; if(a < 1000) first(a+1); } first(3);";
-    // let data = "fun first() { return 3;} print first();";
-    // let data = "fun first(a) { return a + 3; } print first(5); print first(1);";
-    // let data = "print -1;";
-    // let data = "fun first(a) { print a + 3; } first(-1);";
-    // let data = "print !false";
-    // let data = "fun first(a) { if(!a) print \"is falsey\"; else print a; } first(3); first(false); first(nil); first(true);";
-    // let data = "fun first() {} first(1);";
-    // let data = "test(1); test(2,3);";
-    // let data = "var a = clock(); for(var i =0; i < 10000; i = i + 1) {} print clock()-a;";
-
-    // closures
-    // let data = "{ var a = 3; fun first() { print a; } first(); }";
-    // let data = "fun outer() {var x = 3; fun inner() { print x; } return inner; } var closure = outer(); closure();";
-    // let data = "var global; fun main() { { var a = 3; fun one() { print a; } global = one; } } main(); global();";
-    // let data = "{ var a = 3; fun first() { print a; } fun second() { print a; } }";
-    // let data = "
-    // var globalSet;
-    // var globalGet;
-
-    // fun main() {
-    //   var a = \"initial\";
-
-    //   fun set() { a = \"updated\"; }
-    //   fun get() { print a; }
-
-    //   globalSet = set;
-    //   globalGet = get;
-    // }
-
-    // main();
-    // globalSet();
-    // globalGet();
-    // ";
-
-    let data = std::fs::read_to_string("test.lox").unwrap();
-
-    let module = lox_compiler::compile(&data).unwrap();
-
-    // Temporary to test serde out
-    let data = serde_json::to_string_pretty(&module).unwrap();
-    println!("{}", data);
-    let module: lox_bytecode::bytecode::Module = serde_json::from_str(&data).unwrap();
-
-    println!("constants: {:?}", module.constants());
-    for chunk in module.chunks() {
-        println!("chunk: {:?}", chunk.instructions());
+mod repl;
+mod report;
+
+use lox_bytecode::binary::ReadError;
+use lox_bytecode::bytecode::Module;
+use lox_bytecode::disassembler;
+use lox_compiler::OptimizationLevel;
+use lox_vm::bettervm::VmError;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+// Exit codes, following the reference implementation (sysexits.h).
+const EX_USAGE: u8 = 64;
+const EX_DATAERR: u8 = 65;
+const EX_SOFTWARE: u8 = 70;
+const EX_IOERR: u8 = 74;
+
+const USAGE: &str = "Usage:
+    lox run <file.lox>                   Compile and run a script
+    lox compile <file.lox> [-o <file>]   Compile a script to a module file
+    lox exec <file.loxc>                 Run a compiled module
+    lox disasm <file>                    Show the bytecode of a script or module
+    lox repl                             Start an interactive session
+
+Options:
+    -O0                                  Compile without optimizations
+    -O1                                  Fold constants, remove dead code and simplify
+                                         the bytecode (default)";
+
+enum Command {
+    Run(PathBuf, OptimizationLevel),
+    Compile(PathBuf, Option<PathBuf>, OptimizationLevel),
+    Exec(PathBuf),
+    Disasm(PathBuf, OptimizationLevel),
+    Repl,
+    Help,
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let command = match parse_args(&args) {
+        Ok(command) => command,
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, USAGE);
+            return ExitCode::from(EX_USAGE);
+        }
+    };
+
+    let result = match command {
+        Command::Run(path, level) => run(&path, level),
+        Command::Compile(input, output, level) => compile(&input, output, level),
+        Command::Exec(path) => exec(&path),
+        Command::Disasm(path, level) => disasm(&path, level),
+        Command::Repl => repl(),
+        Command::Help => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(code) => ExitCode::from(code),
     }
+}
+
+fn parse_args(args: &[String]) -> Result<Command, String> {
+    let (command, rest) = match args.split_first() {
+        Some((command, rest)) => (command.as_str(), rest),
+        None => return Err("Missing command.".to_string()),
+    };
+
+    let mut level = OptimizationLevel::default();
+    let rest: Vec<String> = rest
+        .iter()
+        .filter(|arg| match arg.as_str() {
+            "-O0" => {
+                level = OptimizationLevel::None;
+                false
+            }
+            "-O1" => {
+                level = OptimizationLevel::Basic;
+                false
+            }
+            _ => true,
+        })
+        .cloned()
+        .collect();
+    let rest = &rest[..];
+
+    let file = |rest: &[String]| match rest {
+        [file] => Ok(PathBuf::from(file)),
+        [] => Err(format!("'{}' expects a file.", command)),
+        _ => Err(format!("Too many arguments for '{}'.", command)),
+    };
 
-    println!();
+    match command {
+        "run" => Ok(Command::Run(file(rest)?, level)),
+        "exec" => Ok(Command::Exec(file(rest)?)),
+        "disasm" => Ok(Command::Disasm(file(rest)?, level)),
+        "compile" => match rest {
+            [input] => Ok(Command::Compile(input.into(), None, level)),
+            [input, flag, output] | [flag, output, input] if flag == "-o" => {
+                Ok(Command::Compile(input.into(), Some(output.into()), level))
+            }
+            _ => Err("'compile' expects a file and an optional '-o <file>'.".to_string()),
+        },
+        "repl" if rest.is_empty() => Ok(Command::Repl),
+        "repl" => Err("Too many arguments for 'repl'.".to_string()),
+        "help" | "--help" | "-h" => Ok(Command::Help),
+        command => Err(format!("Unknown command '{}'.", command)),
+    }
+}
+
+fn run(path: &Path, level: OptimizationLevel) -> Result<(), u8> {
+    let source = read_source(path)?;
+    let module = compile_source(&path.display().to_string(), &source, level)?;
+    execute(&module, Some((&path.display().to_string(), &source)))
+}
+
+fn compile(input: &Path, output: Option<PathBuf>, level: OptimizationLevel) -> Result<(), u8> {
+    let source = read_source(input)?;
+    let module = compile_source(&input.display().to_string(), &source, level)?;
+    let output = output.unwrap_or_else(|| input.with_extension("loxc"));
+
+    std::fs::File::create(&output)
+        .map(io::BufWriter::new)
+        .and_then(|mut file| {
+            module.write_to(&mut file)?;
+            file.flush()
+        })
+        .map_err(|error| {
+            eprintln!("Could not write '{}': {}", output.display(), error);
+            EX_IOERR
+        })
+}
+
+fn exec(path: &Path) -> Result<(), u8> {
+    // The VM verifies the module before it runs any of it.
+    let module = read_module(path)?;
+    execute(&module, None)
+}
+
+fn disasm(path: &Path, level: OptimizationLevel) -> Result<(), u8> {
+    let module = if path
+        .extension()
+        .is_some_and(|extension| extension == "loxc")
+    {
+        read_module(path)?
+    } else {
+        let source = read_source(path)?;
+        compile_source(&path.display().to_string(), &source, level)?
+    };
+
+    print!("{}", disassembler::disassemble(&module));
+    Ok(())
+}
+
+fn repl() -> Result<(), u8> {
+    repl::run().map_err(|error| {
+        eprintln!("Could not read input: {}", error);
+        EX_IOERR
+    })
+}
+
+fn read_source(path: &Path) -> Result<String, u8> {
+    std::fs::read_to_string(path).map_err(|error| {
+        eprintln!("Could not read '{}': {}", path.display(), error);
+        EX_IOERR
+    })
+}
+
+fn read_module(path: &Path) -> Result<Module, u8> {
+    let file = std::fs::File::open(path).map_err(|error| {
+        eprintln!("Could not read '{}': {}", path.display(), error);
+        EX_IOERR
+    })?;
+    Module::read_from(io::BufReader::new(file)).map_err(|error| {
+        eprintln!("Could not load '{}': {}", path.display(), error);
+        match error {
+            ReadError::Io(_) => EX_IOERR,
+            _ => EX_DATAERR,
+        }
+    })
+}
+
+fn compile_source(name: &str, source: &str, level: OptimizationLevel) -> Result<Module, u8> {
+    lox_compiler::compile_with(source, level).map_err(|error| {
+        report::compile_error(name, source, &error);
+        EX_DATAERR
+    })
+}
 
-    lox_vm::bettervm::execute(&module).unwrap();
+fn execute(module: &Module, source: Option<(&str, &str)>) -> Result<(), u8> {
+    lox_vm::bettervm::execute(module).map_err(|error| {
+        report::runtime_error(source, &error);
+        match error.error {
+            VmError::InvalidModule(_) => EX_DATAERR,
+            _ => EX_SOFTWARE,
+        }
+    })
 }
@@ -0,0 +1,52 @@
+use crate::report;
+use lox_compiler::Error;
+use lox_vm::bettervm::{self, VmBuilder};
+use std::io::{self, BufRead, Write};
+
+const NAME: &str = "repl";
+
+/// Read, compile and run input line by line against a single VM, so globals are kept.
+/// Input that ends early, like an open brace, continues on the next line.
+pub fn run() -> io::Result<()> {
+    let mut vm = VmBuilder::new()
+        .register_native("clock", 0, bettervm::clock)
+        .build();
+    // Everything that was run so far, spans of runtime errors point into this.
+    let mut session = String::new();
+    let mut input = String::new();
+
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("{}", if input.is_empty() { "> " } else { "... " });
+        io::stdout().flush()?;
+
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => {
+                if let Err(error) = lox_compiler::compile_interactive(&input) {
+                    report::compile_error(NAME, &input, &error);
+                }
+                println!();
+                return Ok(());
+            }
+        };
+        input.push_str(&line);
+        input.push('\n');
+
+        match lox_compiler::compile_interactive(&input) {
+            Err(Error::ParseError(ref errors)) if errors.iter().all(|e| e.is_incomplete()) => {
+                continue;
+            }
+            Err(error) => report::compile_error(NAME, &input, &error),
+            Ok(mut module) => {
+                module.shift_spans(session.len() as u32, session.matches('\n').count());
+                session.push_str(&input);
+                if let Err(error) = vm.interpret(module) {
+                    report::runtime_error(Some((NAME, &session)), &error);
+                }
+            }
+        }
+        input.clear();
+    }
+}
@@ -0,0 +1,41 @@
+use lox_compiler::Error;
+use lox_syntax::diagnostics::{Diagnostic, Style};
+use lox_syntax::position::{BytePos, Span};
+use lox_vm::bettervm::RuntimeError;
+use std::io::IsTerminal;
+
+pub fn compile_error(name: &str, source: &str, error: &Error) {
+    for diagnostic in error.diagnostics() {
+        eprint!("{}", diagnostic.render(name, source, style()));
+    }
+}
+
+/// Report a runtime error, `source` is the name and code of the script when it is available.
+pub fn runtime_error(source: Option<(&str, &str)>, error: &RuntimeError) {
+    let diagnostic = Diagnostic::new(error.error.code(), error.error.to_string());
+    let diagnostic = error
+        .trace_lines()
+        .into_iter()
+        .fold(diagnostic, |diagnostic, line| diagnostic.with_note(line));
+
+    match source {
+        Some((name, source)) => {
+            let span = Span {
+                start: BytePos(error.span.start),
+                end: BytePos(error.span.end),
+            };
+            let diagnostic = diagnostic.with_span(span);
+            eprint!("{}", diagnostic.render(name, source, style()));
+        }
+        None => eprint!("{}", diagnostic.render("", "", style())),
+    }
+}
+
+// Only color output for terminals, and respect https://no-color.org.
+fn style() -> Style {
+    if std::io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none() {
+        Style::Colored
+    } else {
+        Style::Plain
+    }
+}
@@ -0,0 +1,418 @@
+use lox_vm::bettervm::{FromLox, GcConfig, Value, Vm, VmBuilder, VmError};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+fn load(vm: &mut Vm, source: &str) {
+    let module = lox_compiler::compile(source).expect("valid lox");
+    vm.interpret(module).expect("runs without errors");
+}
+
+#[test]
+fn test_call_function() {
+    let mut vm = Vm::new();
+    load(
+        &mut vm,
+        "var events = 0; fun on_event(a, b) { events = events + 1; return a * b; }",
+    );
+
+    for i in 1..=3 {
+        let args = [Value::Number(i as f64), Value::Number(10.0)];
+        let result = vm.call_function("on_event", &args).unwrap();
+        assert_eq!(result, Value::Number(i as f64 * 10.0));
+    }
+    assert_eq!(vm.global("events"), Some(Value::Number(3.0)));
+}
+
+#[test]
+fn test_call_closure() {
+    let mut vm = Vm::new();
+    load(
+        &mut vm,
+        "fun counter() { var n = 0; fun next() { n = n + 1; return n; } return next; }
+         var next = counter();",
+    );
+
+    assert_eq!(vm.call_function("next", &[]), Ok(Value::Number(1.0)));
+    assert_eq!(vm.call_function("next", &[]), Ok(Value::Number(2.0)));
+    let next = vm.global("next").unwrap();
+    assert_eq!(vm.call_value(next, &[]), Ok(Value::Number(3.0)));
+}
+
+#[test]
+fn test_call_errors() {
+    let mut vm = Vm::new();
+    load(
+        &mut vm,
+        "fun fail(a) { return a + 1; } fun ok() { return true; }",
+    );
+
+    let error = vm.call_function("missing", &[]).unwrap_err();
+    assert_eq!(error.error, VmError::GlobalNotDefined("missing".into()));
+
+    let error = vm.call_function("fail", &[]).unwrap_err();
+    assert!(matches!(error.error, VmError::IncorrectArity(_, 0)));
+
+    let error = vm.call_function("fail", &[Value::Nil]).unwrap_err();
+    assert_eq!(error.error, VmError::ExpectedNumberOrStringOperands);
+    assert_eq!(error.trace.len(), 1);
+    assert_eq!(error.trace[0].function.as_deref(), Some("fail"));
+
+    // The VM is still usable after an error.
+    assert_eq!(vm.call_function("ok", &[]), Ok(Value::Boolean(true)));
+}
+
+#[test]
+fn test_reentrant_call() {
+    let mut vm = VmBuilder::new()
+        .register_native("apply", 1.., |vm, args| {
+            Ok(vm.call_value(args[0], &args[1..])?)
+        })
+        .build();
+    load(
+        &mut vm,
+        "fun double(n) { return apply(add, n, n); }
+         fun add(a, b) { return a + b; }
+         var result = apply(double, 21);",
+    );
+    assert_eq!(vm.global("result"), Some(Value::Number(42.0)));
+    assert_eq!(
+        vm.call_function("double", &[Value::Number(4.0)]),
+        Ok(Value::Number(8.0))
+    );
+}
+
+#[test]
+fn test_global_stays_alive() {
+    let heap_objects = Rc::new(RefCell::new(vec![]));
+    let counts = heap_objects.clone();
+    let mut vm = VmBuilder::new()
+        .register_native("check", 0, move |vm, _| {
+            vm.collect_garbage();
+            counts.borrow_mut().push(vm.gc_stats().heap_objects);
+            let value = vm.global("s").unwrap();
+            // The global no longer points to the string, only the host does.
+            vm.call_function("reset", &[])?;
+            vm.collect_garbage();
+            counts.borrow_mut().push(vm.gc_stats().heap_objects);
+            Ok(value)
+        })
+        .build();
+    load(
+        &mut vm,
+        r#"fun twice(s) { return s + s; }
+           var s = twice("ab");
+           fun reset() { s = nil; }
+           var result = check();"#,
+    );
+
+    let counts = heap_objects.borrow();
+    assert_eq!(counts[0], counts[1]);
+    let result = vm.global("result").unwrap();
+    assert_eq!(String::from_lox(result, &vm), Ok("abab".to_string()));
+}
+
+#[test]
+fn test_reentrant_errors() {
+    let errors = Rc::new(RefCell::new(vec![]));
+    let seen = errors.clone();
+    let mut vm = VmBuilder::new()
+        .register_native("apply", 1, |vm, args| Ok(vm.call_value(args[0], &[])?))
+        .register_native("try", 1, move |vm, args| {
+            match vm.call_value(args[0], &[]) {
+                Ok(value) => Ok(value),
+                Err(error) => {
+                    seen.borrow_mut().push(error.to_string());
+                    Ok(Value::Nil)
+                }
+            }
+        })
+        .build();
+    load(
+        &mut vm,
+        "fun fail() { return -nil; }
+         fun caught() { var local = 1; return try(fail); }
+         var result = caught();",
+    );
+
+    // An error that the native handled doesn't stop the script.
+    assert_eq!(vm.global("result"), Some(Value::Nil));
+    assert_eq!(
+        *errors.borrow(),
+        vec!["Operand must be a number.\n[line 1] in fail()\n[line 2] in caught()\n[line 3] in script"]
+    );
+
+    // An error that the native passed on is reported at the call of the native.
+    let module = lox_compiler::compile("fun outer() { apply(fail); }\nouter();").unwrap();
+    let error = vm.interpret(module).unwrap_err();
+    assert_eq!(error.error, VmError::ExpectedNumberOperand);
+    assert_eq!(error.trace.len(), 2);
+    assert_eq!(error.trace[0].function.as_deref(), Some("outer"));
+    assert_eq!(error.trace[0].line, 1);
+    assert_eq!(error.trace[1].function, None);
+}
+
+#[test]
+fn test_error_lines_in_second_module() {
+    let mut vm = Vm::new();
+    load(&mut vm, "var a = 1;\nvar b = 2;\nvar c = 3;");
+
+    let module = lox_compiler::compile("fun fail() {\n  return -nil;\n}\n\n\nfail();").unwrap();
+    let error = vm.interpret(module).unwrap_err();
+    assert_eq!(error.line, 2);
+    let lines: Vec<_> = error.trace.iter().map(|frame| frame.line).collect();
+    assert_eq!(lines, vec![2, 6]);
+}
+
+#[test]
+fn test_stack_overflow_trace() {
+    let mut vm = Vm::new();
+    let module = lox_compiler::compile("fun f() {\n  f();\n}\nf();").unwrap();
+    let error = vm.interpret(module).unwrap_err();
+    assert_eq!(error.error, VmError::StackOverflow);
+    assert_eq!(error.trace.len(), 256);
+    assert_eq!(
+        error.to_string(),
+        "Stack overflow.\n[line 2] in f()\n... 254 more frames in f()\n[line 4] in script"
+    );
+
+    // Frames that alternate can't be collapsed, only the start and end are shown.
+    let module = lox_compiler::compile("fun g() { h(); }\nfun h() { g(); }\ng();").unwrap();
+    let lines = vm.interpret(module).unwrap_err().trace_lines();
+    assert_eq!(lines.len(), 21);
+    assert_eq!(lines[..2], ["[line 1] in g()", "[line 2] in h()"]);
+    assert_eq!(lines[10], "... 236 more frames");
+    assert_eq!(lines[20], "[line 3] in script");
+}
+
+fn add(a: f64, b: f64) -> f64 {
+    a + b
+}
+
+#[test]
+fn test_register_fn() {
+    let mut vm = VmBuilder::new()
+        .register_fn("add", add)
+        .register_fn("repeat", |text: String, times: u8| {
+            text.repeat(times as usize)
+        })
+        .register_fn("first", |text: String| {
+            text.chars().next().map(String::from)
+        })
+        .register_fn("checked", |n: f64| {
+            if n < 0.0 {
+                Err(VmError::Native("Negative number.".into()))
+            } else {
+                Ok(n.sqrt())
+            }
+        })
+        .build();
+    load(
+        &mut vm,
+        r#"var sum = add(1, 2);
+           var repeated = repeat("ab", 3);
+           var none = first("");
+           var root = checked(16);"#,
+    );
+    assert_eq!(vm.global("sum"), Some(Value::Number(3.0)));
+    let repeated = vm.global("repeated").unwrap();
+    assert_eq!(String::from_lox(repeated, &vm), Ok("ababab".to_string()));
+    assert_eq!(vm.global("none"), Some(Value::Nil));
+    assert_eq!(vm.global("root"), Some(Value::Number(4.0)));
+
+    let run = |vm: &mut Vm, source: &str| {
+        let module = lox_compiler::compile(source).unwrap();
+        vm.interpret(module).unwrap_err().to_string()
+    };
+    assert_eq!(
+        run(&mut vm, "add(1);"),
+        "Expected 2 arguments but got 1.\n[line 1] in script"
+    );
+    assert_eq!(
+        run(&mut vm, "add(1, \"2\");"),
+        "Expected a number but got a string.\n[line 1] in script"
+    );
+    assert_eq!(
+        run(&mut vm, "repeat(\"a\", 256);"),
+        "Expected an integer from 0 to 255 but got 256.\n[line 1] in script"
+    );
+    assert_eq!(
+        run(&mut vm, "fun f() { checked(-1); }\nf();"),
+        "Negative number.\n[line 1] in f()\n[line 2] in script"
+    );
+}
+
+#[test]
+fn test_host_strings() {
+    use lox_vm::bettervm::IntoLox;
+
+    let mut vm = Vm::new();
+    load(&mut vm, "fun join(a, b) { return a + \" \" + b; }");
+
+    let args = [
+        "hello".into_lox(&mut vm),
+        "world".to_string().into_lox(&mut vm),
+    ];
+    let result = vm.call_function("join", &args).unwrap();
+    assert_eq!(String::from_lox(result, &vm), Ok("hello world".to_string()));
+}
+
+#[test]
+fn test_heap_limit() {
+    let mut vm = VmBuilder::new()
+        .gc(GcConfig {
+            max_heap: Some(4 * 1024 * 1024),
+            ..GcConfig::default()
+        })
+        .build();
+    load(
+        &mut vm,
+        r#"fun grow(text) { while (true) text = text + text; }
+           class Garbage {}
+           fun churn() {
+             for (var i = 0; i < 100000; i = i + 1) Garbage();
+             return true;
+           }"#,
+    );
+
+    let text = vm.string("text");
+    let error = vm.call_function("grow", &[text]).unwrap_err();
+    assert_eq!(error.error, VmError::OutOfMemory);
+    assert_eq!(error.trace[0].function.as_deref(), Some("grow"));
+
+    // Garbage doesn't count against the limit, and the strings of `grow` are garbage now.
+    assert_eq!(vm.call_function("churn", &[]), Ok(Value::Boolean(true)));
+}
+
+#[test]
+fn test_gc_stats() {
+    let mut vm = Vm::new();
+    load(
+        &mut vm,
+        "class Node {}
+         var list = nil;
+         for (var i = 0; i < 1000; i = i + 1) { var node = Node(); node.next = list; list = node; }
+         fun clear() { list = nil; }",
+    );
+    let before = vm.gc_stats();
+    assert!(before.heap_objects >= 1000);
+
+    // Clearing the list doesn't allocate, the list is garbage until the next collection.
+    vm.call_function("clear", &[]).unwrap();
+    vm.collect_garbage();
+    let after = vm.gc_stats();
+    assert!(after.major_collections > before.major_collections);
+    assert!(after.heap_objects <= before.heap_objects - 1000);
+    assert!(after.heap_bytes < before.heap_bytes);
+    assert!(after.bytes_freed > before.bytes_freed);
+    assert!(after.pause_time > before.pause_time);
+}
+
+#[test]
+fn test_native_values_are_released() {
+    let mut vm = VmBuilder::new()
+        .register_native("label", 1, |vm, args| {
+            let label = vm.string("item");
+            Ok(vm.call_value(args[0], &[label])?)
+        })
+        .register_fn("name", |n: f64| format!("item {}", n))
+        .build();
+    load(
+        &mut vm,
+        "fun twice(text) { return text + text; }
+         for (var i = 0; i < 100000; i = i + 1) { label(twice); name(i); }",
+    );
+    vm.collect_garbage();
+    assert!(vm.gc_stats().heap_objects < 1000);
+}
+
+#[test]
+fn test_separate_heaps() {
+    let source = "class Node {}
+                  var list = nil;
+                  for (var i = 0; i < 10000; i = i + 1) { var node = Node(); node.next = list; list = node; }
+                  fun length() { var n = 0; for (var node = list; node != nil; node = node.next) n = n + 1; return n; }";
+    // The stack takes 1 MiB of this, the list doesn't fit in the rest.
+    let mut small = VmBuilder::new()
+        .gc(GcConfig {
+            max_heap: Some(1536 * 1024),
+            ..GcConfig::default()
+        })
+        .build();
+    let mut large = Vm::new();
+    load(&mut large, source);
+
+    // The limit of one VM doesn't count the objects of the other.
+    let module = lox_compiler::compile(source).unwrap();
+    let error = small.interpret(module).unwrap_err();
+    assert_eq!(error.error, VmError::OutOfMemory);
+    assert!(small.gc_stats().heap_objects > 1000);
+    assert!(small.gc_stats().heap_objects < 10000);
+    assert!(large.gc_stats().heap_objects >= 10000);
+
+    drop(small);
+    large.collect_garbage();
+    assert_eq!(
+        large.call_function("length", &[]),
+        Ok(Value::Number(10000.0))
+    );
+}
+
+#[test]
+fn test_foreign_values() {
+    let mut first = Vm::new();
+    load(&mut first, "fun greet(name) { return \"hi \" + name; }");
+    let greet = first.global("greet").unwrap();
+    let name = first.string("lox");
+
+    let stolen = Rc::new(RefCell::new(Value::Nil));
+    let value = stolen.clone();
+    let mut second = VmBuilder::new()
+        .register_native("steal", 0, move |_vm, _args| Ok(*value.borrow()))
+        .build();
+    load(&mut second, "fun id(value) { return value; }");
+
+    let error = second.call_value(greet, &[]).unwrap_err();
+    assert_eq!(error.error, VmError::ForeignValue);
+    assert_eq!(error.error.code(), "E222");
+    let error = second.call_function("id", &[name]).unwrap_err();
+    assert_eq!(error.error, VmError::ForeignValue);
+
+    *stolen.borrow_mut() = name;
+    let module = lox_compiler::compile("var s = steal();").unwrap();
+    let error = second.interpret(module).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Value belongs to another VM.\n[line 1] in script"
+    );
+
+    // Both VMs are still usable.
+    let name = first.string("lox");
+    let greeting = first.call_value(greet, &[name]).unwrap();
+    assert_eq!(String::from_lox(greeting, &first), Ok("hi lox".to_string()));
+    assert_eq!(
+        second.call_function("id", &[Value::Number(1.0)]),
+        Ok(Value::Number(1.0))
+    );
+}
+
+#[test]
+fn test_released_values() {
+    let mut vm = Vm::new();
+    load(&mut vm, "fun id(value) { return value; }");
+    let text = vm.string("text");
+    assert_eq!(String::from_lox(text, &vm), Ok("text".to_string()));
+
+    // The call releases the string, it is collected and the handle no longer resolves.
+    vm.call_function("id", &[Value::Nil]).unwrap();
+    vm.collect_garbage();
+    assert_eq!(String::from_lox(text, &vm), Err(VmError::ReleasedValue));
+    let error = vm.call_function("id", &[text]).unwrap_err();
+    assert_eq!(error.error.code(), "E223");
+
+    // A handle can outlive its VM, but it can't be used with another one.
+    let text = {
+        let mut vm = Vm::new();
+        vm.string("text")
+    };
+    assert_eq!(String::from_lox(text, &vm), Err(VmError::ForeignValue));
+}
@@ -0,0 +1,106 @@
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+// Run `source` with the given optimization flag and return what the program did.
+fn run(name: &str, source: &str, flag: &str) -> Output {
+    let path: PathBuf = std::env::temp_dir().join(format!(
+        "lox-optimizer-{}-{}{}.lox",
+        std::process::id(),
+        name,
+        flag
+    ));
+    std::fs::write(&path, source).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_lox"))
+        .arg("run")
+        .arg(flag)
+        .arg(&path)
+        .output()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    output
+}
+
+fn assert_same(name: &str, source: &str) {
+    let unoptimized = run(name, source, "-O0");
+    let optimized = run(name, source, "-O1");
+    assert_eq!(
+        String::from_utf8_lossy(&unoptimized.stdout),
+        String::from_utf8_lossy(&optimized.stdout),
+        "{}",
+        name
+    );
+    assert_eq!(
+        unoptimized.status.code(),
+        optimized.status.code(),
+        "{}",
+        name
+    );
+}
+
+#[test]
+fn test_arithmetic() {
+    assert_same(
+        "arithmetic",
+        "print 1 + 2 * 3; print (1 + 2) * 3; print -(4 - 6) / 4; print \"a\" + \"b\";",
+    );
+}
+
+#[test]
+fn test_division_by_zero() {
+    assert_same(
+        "division",
+        "print 1 / 0; print -1 / 0; print 0 / 0; print 1 / 0 == 1 / 0; print 1 / 0 > 1e308;",
+    );
+}
+
+#[test]
+fn test_nan() {
+    assert_same(
+        "nan",
+        "print 0 / 0 == 0 / 0; print 0 / 0 != 0 / 0; print 0 / 0 < 1; print 0 / 0 > 1;
+         print 0 / 0 <= 1; print 0 / 0 >= 1; print 1 <= 0 / 0; print 1 >= 0 / 0;",
+    );
+}
+
+#[test]
+fn test_equality_and_not() {
+    assert_same(
+        "equality",
+        "print 1 == 1; print 1 == \"1\"; print nil == false; print \"a\" != \"b\";
+         print !true; print !nil; print !0; print !\"\";",
+    );
+}
+
+#[test]
+fn test_logical() {
+    assert_same(
+        "logical",
+        "var a = \"a\"; print false and a; print nil or a; print 1 or a; print true and a;
+         print nil and undefined; print 1 or undefined;
+         var b = nil; if (!b) print 1; print !b and 2; print b or !b;
+         while (!b or false) b = 0; print b;",
+    );
+}
+
+#[test]
+fn test_dead_code() {
+    assert_same(
+        "dead",
+        "if (false) print 1; else print 2; if (1 < 2) print 3; while (nil) print 4;
+         fun f() { if (!true) return 5; return 6; } print f();
+         var i = 0; while (i < 3) { if (false) print i; i = i + 1; } print i;",
+    );
+}
+
+#[test]
+fn test_runtime_errors() {
+    assert_same("add", "print 1; print 1 + \"a\";");
+    assert_same("negate", "print -\"a\";");
+    assert_same("compare", "print nil < 1;");
+}
+
+#[test]
+fn test_compile_errors_in_dead_code() {
+    let output = run("this", "if (false) print this;", "-O1");
+    assert_eq!(output.status.code(), Some(65));
+}
@@ -0,0 +1,14 @@
+use lox_vm::bettervm::{execute, RuntimeError};
+
+fn run(source: &str) -> Result<(), RuntimeError> {
+    let module = lox_compiler::compile(source).expect("valid lox");
+    execute(&module)
+}
+
+#[test]
+fn test_if_without_else_pops_condition() {
+    // A condition left on the stack would move the locals declared after the `if` up a slot,
+    // `b` would read `false` and the addition would fail.
+    let source = "fun f(c) { if (c) 1; var b = 2; return b + 1; } f(false); f(true);";
+    assert_eq!(run(source), Ok(()));
+}